@@ -1,5 +1,6 @@
 use crate::token;
 use std::fmt;
+use std::rc::Rc;
 
 #[allow(dead_code)]
 pub enum Node {
@@ -11,10 +12,29 @@ pub enum Node {
 #[allow(dead_code)]
 #[derive(Clone)]
 pub enum Stmt {
-    Let(Let),
-    Return(Return),
-    ExprStmt(ExprStmt),
-    Block(Block),
+    Let(Rc<Let>),
+    Return(Rc<Return>),
+    ExprStmt(Rc<ExprStmt>),
+    Block(Rc<Block>),
+    While(Rc<While>),
+    For(Rc<For>),
+    Break(Rc<Break>),
+    Continue(Rc<Continue>),
+}
+
+impl Stmt {
+    pub fn span(&self) -> token::Span {
+        return match self {
+            Stmt::Let(l) => l.span,
+            Stmt::Return(r) => r.span,
+            Stmt::ExprStmt(es) => es.span,
+            Stmt::Block(b) => b.span,
+            Stmt::While(w) => w.span,
+            Stmt::For(f) => f.span,
+            Stmt::Break(b) => b.span,
+            Stmt::Continue(c) => c.span,
+        };
+    }
 }
 
 impl fmt::Display for Stmt {
@@ -24,6 +44,10 @@ impl fmt::Display for Stmt {
             Stmt::Return(r) => format!("{}", r),
             Stmt::ExprStmt(es) => format!("{}", es),
             Stmt::Block(b) => format!("{}", b),
+            Stmt::While(w) => format!("{}", w),
+            Stmt::For(f) => format!("{}", f),
+            Stmt::Break(b) => format!("{}", b),
+            Stmt::Continue(c) => format!("{}", c),
         };
         write!(f, "{}", s)
     }
@@ -31,14 +55,41 @@ impl fmt::Display for Stmt {
 
 #[derive(Clone)]
 pub enum Expr {
-    Ident(Ident),
-    Int(Int),
-    Prefix(Prefix),
-    Infix(Infix),
-    Bool(Bool),
-    If(If),
-    Func(Func),
-    Call(Call),
+    Ident(Rc<Ident>),
+    Int(Rc<Int>),
+    Str(Rc<Str>),
+    Prefix(Rc<Prefix>),
+    Infix(Rc<Infix>),
+    Bool(Rc<Bool>),
+    If(Rc<If>),
+    Func(Rc<Func>),
+    Call(Rc<Call>),
+    Array(Rc<Array>),
+    Index(Rc<Index>),
+    Hash(Rc<Hash>),
+    Assign(Rc<Assign>),
+    Range(Rc<Range>),
+}
+
+impl Expr {
+    pub fn span(&self) -> token::Span {
+        return match self {
+            Expr::Ident(i) => i.span,
+            Expr::Int(n) => n.span,
+            Expr::Str(s) => s.span,
+            Expr::Prefix(p) => p.span,
+            Expr::Infix(i) => i.span,
+            Expr::Bool(b) => b.span,
+            Expr::If(i) => i.span,
+            Expr::Func(f) => f.span,
+            Expr::Call(c) => c.span,
+            Expr::Array(a) => a.span,
+            Expr::Index(i) => i.span,
+            Expr::Hash(h) => h.span,
+            Expr::Assign(a) => a.span,
+            Expr::Range(r) => r.span,
+        };
+    }
 }
 
 impl fmt::Display for Expr {
@@ -46,12 +97,18 @@ impl fmt::Display for Expr {
         let s = match self {
             Expr::Ident(i) => format!("{}", i),
             Expr::Int(n) => format!("{}", n),
+            Expr::Str(s) => format!("{}", s),
             Expr::Prefix(p) => format!("{}", p),
             Expr::Infix(i) => format!("{}", i),
             Expr::Bool(b) => format!("{}", b),
             Expr::If(i) => format!("{}", i),
             Expr::Func(f) => format!("{}", f),
             Expr::Call(c) => format!("{}", c),
+            Expr::Array(a) => format!("{}", a),
+            Expr::Index(i) => format!("{}", i),
+            Expr::Hash(h) => format!("{}", h),
+            Expr::Assign(a) => format!("{}", a),
+            Expr::Range(r) => format!("{}", r),
         };
         write!(f, "{}", s)
     }
@@ -74,19 +131,24 @@ impl fmt::Display for Program {
 #[derive(Clone)]
 pub struct Let {
     pub token: token::Token,
+    pub span: token::Span,
     pub name: Ident,
     pub val: Expr,
+    // bound with `const`, so that it cannot be assigned to.
+    pub constant: bool,
 }
 
 impl fmt::Display for Let {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "let {} = {};", self.name, self.val)
+        let keyword = if self.constant { "const" } else { "let" };
+        write!(f, "{} {} = {};", keyword, self.name, self.val)
     }
 }
 
 #[derive(Clone)]
 pub struct Return {
     pub token: token::Token,
+    pub span: token::Span,
     pub val: Expr,
 }
 
@@ -96,9 +158,64 @@ impl fmt::Display for Return {
     }
 }
 
+#[derive(Clone)]
+pub struct While {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub cond: Expr,
+    pub body: Block,
+}
+
+impl fmt::Display for While {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "while {} {}", self.cond, self.body)
+    }
+}
+
+// `for (var in iter) body`, which binds var to each item of iter in turn.
+#[derive(Clone)]
+pub struct For {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub var: Ident,
+    pub iter: Expr,
+    pub body: Block,
+}
+
+impl fmt::Display for For {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "for ({} in {}) {}", self.var, self.iter, self.body)
+    }
+}
+
+#[derive(Clone)]
+pub struct Break {
+    pub token: token::Token,
+    pub span: token::Span,
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "break;")
+    }
+}
+
+#[derive(Clone)]
+pub struct Continue {
+    pub token: token::Token,
+    pub span: token::Span,
+}
+
+impl fmt::Display for Continue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "continue;")
+    }
+}
+
 #[derive(Clone)]
 pub struct ExprStmt {
     pub token: token::Token,
+    pub span: token::Span,
     pub expr: Expr,
 }
 
@@ -111,12 +228,13 @@ impl fmt::Display for ExprStmt {
 #[derive(Clone)]
 pub struct Block {
     pub token: token::Token,
-    pub stmts: Vec<Stmt>,
+    pub span: token::Span,
+    pub stmts: Rc<Vec<Stmt>>,
 }
 
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for stmt in &self.stmts {
+        for stmt in self.stmts.iter() {
             write!(f, "{}", stmt)?;
         }
         Ok(())
@@ -126,6 +244,7 @@ impl fmt::Display for Block {
 #[derive(Clone)]
 pub struct Ident {
     pub token: token::Token,
+    pub span: token::Span,
     pub val: String,
 }
 
@@ -138,6 +257,7 @@ impl fmt::Display for Ident {
 #[derive(Clone)]
 pub struct Int {
     pub token: token::Token,
+    pub span: token::Span,
     pub val: isize,
 }
 
@@ -147,11 +267,25 @@ impl fmt::Display for Int {
     }
 }
 
+#[derive(Clone)]
+pub struct Str {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub val: String,
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.val.escape_debug())
+    }
+}
+
 #[derive(Clone)]
 pub struct Prefix {
     pub token: token::Token,
+    pub span: token::Span,
     pub op: String,
-    pub rhs: Box<Expr>,
+    pub rhs: Rc<Expr>,
 }
 
 impl fmt::Display for Prefix {
@@ -160,23 +294,110 @@ impl fmt::Display for Prefix {
     }
 }
 
+// a chain of left-associative operators such as `1 + 2 + 3` parses to a
+// tree as deep as the chain is long, so code that walks one follows the
+// chain in a loop rather than recursing down lhs.
 #[derive(Clone)]
 pub struct Infix {
     pub token: token::Token,
-    pub lhs: Box<Expr>,
+    pub span: token::Span,
+    pub lhs: Rc<Expr>,
     pub op: String,
-    pub rhs: Box<Expr>,
+    pub rhs: Rc<Expr>,
+}
+
+impl Infix {
+    // the first operand of the chain self ends, and the infix expressions
+    // that apply the others to it in turn, self last.
+    pub fn chain(&self) -> (&Expr, Vec<&Infix>) {
+        let mut chain = vec![self];
+        let mut first = &self.lhs;
+        while let Expr::Infix(i) = &**first {
+            chain.push(i);
+            first = &i.lhs;
+        }
+        chain.reverse();
+        (first, chain)
+    }
+}
+
+impl Drop for Infix {
+    fn drop(&mut self) {
+        let mut next = take_infix(&mut self.lhs);
+        while let Some(mut i) = next {
+            next = match Rc::get_mut(&mut i) {
+                Some(i) => take_infix(&mut i.lhs),
+                None => None,
+            };
+        }
+    }
+}
+
+// move the infix expression out of lhs, if it is one and nothing else
+// holds it, leaving a leaf that drops without recursing in its place.
+fn take_infix(lhs: &mut Rc<Expr>) -> Option<Rc<Infix>> {
+    let lhs = match Rc::get_mut(lhs) {
+        Some(lhs) if matches!(lhs, Expr::Infix(_)) => lhs,
+        _ => return None,
+    };
+    let t = token::Token {
+        t: token::Type::False,
+        literal: String::new(),
+        span: token::Span::default(),
+    };
+    let leaf = Expr::Bool(Rc::new(Bool { token: t, span: token::Span::default(), val: false }));
+    return match std::mem::replace(lhs, leaf) {
+        Expr::Infix(i) => Some(i),
+        _ => None,
+    };
 }
 
 impl fmt::Display for Infix {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "({} {} {})", self.lhs, self.op, self.rhs)
+        let (first, chain) = self.chain();
+        write!(f, "{}{}", "(".repeat(chain.len()), first)?;
+        for i in chain.iter() {
+            write!(f, " {} {})", i.op, i.rhs)?;
+        }
+        Ok(())
+    }
+}
+
+// `name = val`, or a compound assignment such as `name += val`.
+#[derive(Clone)]
+pub struct Assign {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub name: Ident,
+    pub op: String,
+    pub val: Rc<Expr>,
+}
+
+impl fmt::Display for Assign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({} {} {})", self.name, self.op, self.val)
+    }
+}
+
+// `start..end`, the integers from start up to but not including end.
+#[derive(Clone)]
+pub struct Range {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub start: Rc<Expr>,
+    pub end: Rc<Expr>,
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}..{})", self.start, self.end)
     }
 }
 
 #[derive(Clone)]
 pub struct Bool {
     pub token: token::Token,
+    pub span: token::Span,
     pub val: bool,
 }
 
@@ -189,14 +410,45 @@ impl fmt::Display for Bool {
 #[derive(Clone)]
 pub struct If {
     pub token: token::Token,
-    pub cond: Box<Expr>,
+    pub span: token::Span,
+    pub cond: Rc<Expr>,
     pub cons: Block,
+    // tried in order when cond is falsy. They are kept in a list rather
+    // than nested in alt, so a long chain of them is not deep.
+    pub else_ifs: Vec<ElseIf>,
     pub alt: Option<Block>,
 }
 
+#[derive(Clone)]
+pub struct ElseIf {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub cond: Rc<Expr>,
+    pub cons: Block,
+}
+
+impl If {
+    // each condition with the block it picks, in the order they are tried.
+    pub fn branches(&self) -> impl Iterator<Item = (&Expr, &Block)> {
+        let first = std::iter::once((&*self.cond, &self.cons));
+        return first.chain(self.else_ifs.iter().map(|e| (&*e.cond, &e.cons)));
+    }
+
+    // the nth of branches, if there are that many.
+    pub fn branch(&self, n: usize) -> Option<(&Expr, &Block)> {
+        return match n {
+            0 => Some((&*self.cond, &self.cons)),
+            _ => self.else_ifs.get(n - 1).map(|e| (&*e.cond, &e.cons)),
+        };
+    }
+}
+
 impl fmt::Display for If {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "if {} {}", self.cond, self.cons)?;
+        for e in self.else_ifs.iter() {
+            write!(f, " else if {} {}", e.cond, e.cons)?;
+        }
         if let Some(alt) = &self.alt {
             write!(f, "{}", alt)?;
         }
@@ -207,26 +459,63 @@ impl fmt::Display for If {
 #[derive(Clone)]
 pub struct Func {
     pub token: token::Token,
-    pub params: Vec<Ident>,
+    pub span: token::Span,
+    // the name the function is bound to by `let`, for error messages.
+    pub name: Option<String>,
+    pub params: Vec<Param>,
+    // collects the arguments after the last parameter into an array.
+    pub rest: Option<Ident>,
     pub body: Block,
 }
 
+impl Func {
+    pub fn params_string(&self) -> String {
+        return params_string(&self.params, &self.rest);
+    }
+
+    // the fewest arguments a call must pass.
+    pub fn required(&self) -> usize {
+        return self.params.iter().filter(|p| p.default.is_none()).count();
+    }
+}
+
 impl fmt::Display for Func {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "fn")?;
-        write!(f, "(")?;
-        for param in &self.params {
-            write!(f, "{}", param)?;
-        }
-        write!(f, ")")?;
-        write!(f, "{}", self.body)
+        write!(f, "fn({}){}", self.params_string(), self.body)
+    }
+}
+
+// the parameter list as written, e.g. `a, b = 2, ...rest`.
+pub fn params_string(params: &[Param], rest: &Option<Ident>) -> String {
+    let mut params: Vec<String> = params.iter().map(|p| format!("{}", p)).collect();
+    if let Some(rest) = rest {
+        params.push(format!("...{}", rest));
+    }
+    return params.join(", ");
+}
+
+// a function parameter. A parameter with a default may be left out of a
+// call, and the default is evaluated in its place.
+#[derive(Clone)]
+pub struct Param {
+    pub name: Ident,
+    pub default: Option<Expr>,
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match &self.default {
+            Some(default) => write!(f, "{} = {}", self.name, default),
+            None => write!(f, "{}", self.name),
+        };
     }
 }
 
 #[derive(Clone)]
 pub struct Call {
     pub token: token::Token,
-    pub func: Box<Expr>,
+    pub span: token::Span,
+    pub func: Rc<Expr>,
     pub args: Vec<Expr>,
 }
 
@@ -245,3 +534,187 @@ impl fmt::Display for Call {
         write!(f, ")")
     }
 }
+
+#[derive(Clone)]
+pub struct Array {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub elems: Vec<Expr>,
+}
+
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+
+        let len = self.elems.len();
+        for (i, elem) in self.elems.iter().enumerate() {
+            write!(f, "{}", elem)?;
+            if i != len - 1 {
+                write!(f, ", ")?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+#[derive(Clone)]
+pub struct Index {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub lhs: Rc<Expr>,
+    pub index: Rc<Expr>,
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}[{}])", self.lhs, self.index)
+    }
+}
+
+#[derive(Clone)]
+pub struct Hash {
+    pub token: token::Token,
+    pub span: token::Span,
+    pub pairs: Vec<(Expr, Expr)>,
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{")?;
+
+        let len = self.pairs.len();
+        for (i, (key, val)) in self.pairs.iter().enumerate() {
+            write!(f, "{}: {}", key, val)?;
+            if i != len - 1 {
+                write!(f, ", ")?;
+            }
+        }
+        write!(f, "}}")
+    }
+}
+
+// an indented tree of the program, one node per line with where it starts,
+// for inspecting how source was parsed.
+pub fn dump(p: &Program) -> String {
+    let mut out = String::from("Program\n");
+    for stmt in p.stmts.iter() {
+        dump_stmt(&mut out, stmt, 1);
+    }
+    out
+}
+
+fn dump_line(out: &mut String, depth: usize, label: String, span: token::Span) {
+    out.push_str(&format!("{}{} @{}\n", "  ".repeat(depth), label, span));
+}
+
+fn dump_block(out: &mut String, label: &str, b: &Block, depth: usize) {
+    dump_line(out, depth, String::from(label), b.span);
+    for stmt in b.stmts.iter() {
+        dump_stmt(out, stmt, depth + 1);
+    }
+}
+
+fn dump_stmt(out: &mut String, stmt: &Stmt, depth: usize) {
+    match stmt {
+        Stmt::Let(l) => {
+            let label = if l.constant { "Const" } else { "Let" };
+            dump_line(out, depth, format!("{} {}", label, l.name), l.span);
+            dump_expr(out, &l.val, depth + 1);
+        },
+        Stmt::Return(r) => {
+            dump_line(out, depth, String::from("Return"), r.span);
+            dump_expr(out, &r.val, depth + 1);
+        },
+        Stmt::ExprStmt(es) => dump_expr(out, &es.expr, depth),
+        Stmt::Block(b) => dump_block(out, "Block", b, depth),
+        Stmt::While(w) => {
+            dump_line(out, depth, String::from("While"), w.span);
+            dump_expr(out, &w.cond, depth + 1);
+            dump_block(out, "Do", &w.body, depth + 1);
+        },
+        Stmt::For(f) => {
+            dump_line(out, depth, format!("For {}", f.var), f.span);
+            dump_expr(out, &f.iter, depth + 1);
+            dump_block(out, "Do", &f.body, depth + 1);
+        },
+        Stmt::Break(b) => dump_line(out, depth, String::from("Break"), b.span),
+        Stmt::Continue(c) => dump_line(out, depth, String::from("Continue"), c.span),
+    }
+}
+
+fn dump_expr(out: &mut String, expr: &Expr, depth: usize) {
+    match expr {
+        Expr::Ident(i) => dump_line(out, depth, format!("Ident {}", i), i.span),
+        Expr::Int(n) => dump_line(out, depth, format!("Int {}", n), n.span),
+        Expr::Str(s) => dump_line(out, depth, format!("Str {}", s), s.span),
+        Expr::Bool(b) => dump_line(out, depth, format!("Bool {}", b), b.span),
+        Expr::Prefix(p) => {
+            dump_line(out, depth, format!("Prefix {}", p.op), p.span);
+            dump_expr(out, &p.rhs, depth + 1);
+        },
+        Expr::Infix(i) => {
+            let (first, chain) = i.chain();
+            let n = chain.len();
+            for (k, i) in chain.iter().rev().enumerate() {
+                dump_line(out, depth + k, format!("Infix {}", i.op), i.span);
+            }
+            dump_expr(out, first, depth + n);
+            for (k, i) in chain.iter().enumerate() {
+                dump_expr(out, &i.rhs, depth + n - k);
+            }
+        },
+        Expr::Range(r) => {
+            dump_line(out, depth, String::from("Range"), r.span);
+            dump_expr(out, &r.start, depth + 1);
+            dump_expr(out, &r.end, depth + 1);
+        },
+        Expr::Assign(a) => {
+            dump_line(out, depth, format!("Assign {} {}", a.name, a.op), a.span);
+            dump_expr(out, &a.val, depth + 1);
+        },
+        Expr::If(i) => {
+            dump_line(out, depth, String::from("If"), i.span);
+            dump_expr(out, &i.cond, depth + 1);
+            dump_block(out, "Then", &i.cons, depth + 1);
+            for e in i.else_ifs.iter() {
+                dump_line(out, depth + 1, String::from("ElseIf"), e.span);
+                dump_expr(out, &e.cond, depth + 2);
+                dump_block(out, "Then", &e.cons, depth + 2);
+            }
+            if let Some(alt) = &i.alt {
+                dump_block(out, "Else", alt, depth + 1);
+            }
+        },
+        Expr::Func(f) => {
+            dump_line(out, depth, format!("Func({})", f.params_string()), f.span);
+            for stmt in f.body.stmts.iter() {
+                dump_stmt(out, stmt, depth + 1);
+            }
+        },
+        Expr::Call(c) => {
+            dump_line(out, depth, String::from("Call"), c.span);
+            dump_expr(out, &c.func, depth + 1);
+            for arg in c.args.iter() {
+                dump_expr(out, arg, depth + 1);
+            }
+        },
+        Expr::Array(a) => {
+            dump_line(out, depth, String::from("Array"), a.span);
+            for elem in a.elems.iter() {
+                dump_expr(out, elem, depth + 1);
+            }
+        },
+        Expr::Index(i) => {
+            dump_line(out, depth, String::from("Index"), i.span);
+            dump_expr(out, &i.lhs, depth + 1);
+            dump_expr(out, &i.index, depth + 1);
+        },
+        Expr::Hash(h) => {
+            dump_line(out, depth, String::from("Hash"), h.span);
+            for (key, val) in h.pairs.iter() {
+                dump_expr(out, key, depth + 1);
+                dump_expr(out, val, depth + 2);
+            }
+        },
+    }
+}
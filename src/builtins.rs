@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::object::{
+    Object,
+    Int,
+    Null,
+    Array,
+    Builtin,
+    ErrorKind,
+    new_error,
+};
+
+// the builtin functions visible to a program, in registration order.
+#[derive(Clone)]
+pub struct Registry {
+    builtins: Vec<Builtin>,
+    index: HashMap<String, usize>,
+}
+
+pub fn new() -> Registry {
+    return Registry {
+        builtins: vec![],
+        index: HashMap::new(),
+    };
+}
+
+// a registry holding the builtins every program can use.
+pub fn defaults() -> Registry {
+    let mut r = new();
+    r.register("len", Some(1), len);
+    r.register("first", Some(1), first);
+    r.register("last", Some(1), last);
+    r.register("rest", Some(1), rest);
+    r.register("push", Some(2), push);
+    r.register("puts", None, puts);
+    r
+}
+
+impl Registry {
+    // add a builtin, replacing any previous one with the same name.
+    pub fn register<F>(&mut self, name: &str, arity: Option<usize>, func: F)
+    where
+        F: Fn(Vec<Object>) -> Object + 'static,
+    {
+        let b = Builtin {
+            name: String::from(name),
+            arity: arity,
+            func: Rc::new(func),
+        };
+        match self.index.get(name) {
+            Some(&i) => self.builtins[i] = b,
+            None => {
+                self.index.insert(String::from(name), self.builtins.len());
+                self.builtins.push(b);
+            },
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Object> {
+        return self.index.get(name)
+            .map(|&i| Object::Builtin(self.builtins[i].clone()));
+    }
+}
+
+// call a builtin after checking the number of arguments it was given.
+pub fn call(b: &Builtin, args: Vec<Object>) -> Object {
+    if let Some(n) = b.arity {
+        if args.len() != n {
+            return new_error(
+                ErrorKind::WrongArity,
+                format!("`{}` expected {}, got {}", b.name, n, args.len()),
+                None,
+            );
+        }
+    }
+    return (b.func)(args);
+}
+
+fn len(args: Vec<Object>) -> Object {
+    return match &args[0] {
+        Object::Str(s) => Object::Int(Int { val: s.val.chars().count() as isize }),
+        Object::Array(a) => Object::Int(Int { val: a.elems.len() as isize }),
+        Object::Hash(h) => Object::Int(Int { val: h.pairs.len() as isize }),
+        obj => unsupported("len", obj),
+    };
+}
+
+fn first(args: Vec<Object>) -> Object {
+    return match &args[0] {
+        Object::Array(a) => match a.elems.first() {
+            Some(obj) => obj.clone(),
+            None => Object::Null(Null {}),
+        },
+        obj => unsupported("first", obj),
+    };
+}
+
+fn last(args: Vec<Object>) -> Object {
+    return match &args[0] {
+        Object::Array(a) => match a.elems.last() {
+            Some(obj) => obj.clone(),
+            None => Object::Null(Null {}),
+        },
+        obj => unsupported("last", obj),
+    };
+}
+
+// all elements but the first, as a new array.
+fn rest(args: Vec<Object>) -> Object {
+    return match &args[0] {
+        Object::Array(a) => {
+            if a.elems.is_empty() {
+                return Object::Null(Null {});
+            }
+            Object::Array(Array { elems: Rc::new(a.elems[1..].to_vec()) })
+        },
+        obj => unsupported("rest", obj),
+    };
+}
+
+// a new array with the element appended. The argument is left untouched.
+fn push(args: Vec<Object>) -> Object {
+    return match &args[0] {
+        Object::Array(a) => {
+            let mut elems = Vec::with_capacity(a.elems.len() + 1);
+            elems.extend(a.elems.iter().cloned());
+            elems.push(args[1].clone());
+            Object::Array(Array { elems: Rc::new(elems) })
+        },
+        obj => unsupported("push", obj),
+    };
+}
+
+// print each argument on its own line.
+fn puts(args: Vec<Object>) -> Object {
+    for arg in args.iter() {
+        println!("{}", arg);
+    }
+    return Object::Null(Null {});
+}
+
+fn unsupported(name: &str, obj: &Object) -> Object {
+    return new_error(
+        ErrorKind::TypeMismatch,
+        format!("argument to `{}` not supported, got {}", name, obj.type_name()),
+        None,
+    );
+}
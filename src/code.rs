@@ -0,0 +1,332 @@
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+// Op is a single bytecode instruction. Operands follow the opcode byte,
+// big-endian, with the widths given by `operand_widths`.
+#[repr(u8)]
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Op {
+    Constant,      // [constant index]
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Equ,
+    Neq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Minus,
+    Bang,
+    BitNot,
+    True,
+    False,
+    Null,
+    Jump,          // [target]
+    JumpNotTruthy, // [target]
+    GetGlobal,     // [global index]
+    SetGlobal,     // [global index]
+    GetLocal,      // [scope depth, slot]
+    SetLocal,      // [scope depth, slot]
+    Array,         // [number of elements]
+    Hash,          // [number of pairs]
+    Index,
+    Call,          // [number of arguments]
+    ReturnValue,
+    Return,
+    Closure,       // [function index]
+    ConstGlobal,   // [global index]
+    ConstLocal,    // [scope depth, slot]
+    AssignGlobal,  // [global index]
+    AssignLocal,   // [scope depth, slot]
+    Range,
+    Iter,
+    Next,          // [target when done]
+    JumpFalsy,     // [target]
+    JumpTruthy,    // [target]
+    JumpPassed,    // [target, parameter slot]
+    TailCall,      // [number of arguments]
+    Unwind,        // [stack height]
+    HashKey,
+}
+
+const OPS: [Op; 51] = [
+    Op::Constant,
+    Op::Pop,
+    Op::Add,
+    Op::Sub,
+    Op::Mul,
+    Op::Div,
+    Op::Mod,
+    Op::Pow,
+    Op::BitAnd,
+    Op::BitOr,
+    Op::BitXor,
+    Op::Shl,
+    Op::Shr,
+    Op::Equ,
+    Op::Neq,
+    Op::Lt,
+    Op::Gt,
+    Op::Le,
+    Op::Ge,
+    Op::Minus,
+    Op::Bang,
+    Op::BitNot,
+    Op::True,
+    Op::False,
+    Op::Null,
+    Op::Jump,
+    Op::JumpNotTruthy,
+    Op::GetGlobal,
+    Op::SetGlobal,
+    Op::GetLocal,
+    Op::SetLocal,
+    Op::Array,
+    Op::Hash,
+    Op::Index,
+    Op::Call,
+    Op::ReturnValue,
+    Op::Return,
+    Op::Closure,
+    Op::ConstGlobal,
+    Op::ConstLocal,
+    Op::AssignGlobal,
+    Op::AssignLocal,
+    Op::Range,
+    Op::Iter,
+    Op::Next,
+    Op::JumpFalsy,
+    Op::JumpTruthy,
+    Op::JumpPassed,
+    Op::TailCall,
+    Op::Unwind,
+    Op::HashKey,
+];
+
+impl Op {
+    pub fn from_byte(b: u8) -> Option<Op> {
+        return OPS.get(b as usize).copied();
+    }
+
+    // the width in bytes of each operand.
+    pub fn operand_widths(&self) -> &'static [usize] {
+        return match self {
+            Op::Constant | Op::Jump | Op::JumpNotTruthy | Op::Next |
+            Op::JumpFalsy | Op::JumpTruthy |
+            Op::GetGlobal | Op::SetGlobal | Op::ConstGlobal | Op::AssignGlobal |
+            Op::Array | Op::Hash | Op::Call | Op::TailCall | Op::Closure |
+            Op::Unwind => &[2],
+            Op::GetLocal | Op::SetLocal | Op::ConstLocal | Op::AssignLocal => &[1, 2],
+            Op::JumpPassed => &[2, 2],
+            _ => &[],
+        };
+    }
+
+    // how many more values the stack holds after the instruction than
+    // before it, when execution carries on to the next one. Unwind sets the
+    // height rather than changing it, and is left to the caller.
+    pub fn stack_effect(&self, operands: &[usize]) -> isize {
+        return match self {
+            Op::Constant | Op::True | Op::False | Op::Null |
+            Op::GetGlobal | Op::GetLocal | Op::Closure | Op::Iter | Op::Next => 1,
+            Op::Pop | Op::JumpNotTruthy | Op::JumpFalsy | Op::JumpTruthy |
+            Op::ReturnValue | Op::Index | Op::Range => -1,
+            Op::Array => 1 - operands[0] as isize,
+            Op::Hash => 1 - 2 * operands[0] as isize,
+            Op::Call | Op::TailCall => -(operands[0] as isize),
+            _ if self.infix_operator().is_some() => -1,
+            _ => 0,
+        };
+    }
+
+    // the infix operator an arithmetic or comparison op stands for.
+    pub fn infix_operator(&self) -> Option<&'static str> {
+        return match self {
+            Op::Add => Some("+"),
+            Op::Sub => Some("-"),
+            Op::Mul => Some("*"),
+            Op::Div => Some("/"),
+            Op::Mod => Some("%"),
+            Op::Pow => Some("**"),
+            Op::BitAnd => Some("&"),
+            Op::BitOr => Some("|"),
+            Op::BitXor => Some("^"),
+            Op::Shl => Some("<<"),
+            Op::Shr => Some(">>"),
+            Op::Equ => Some("=="),
+            Op::Neq => Some("!="),
+            Op::Lt => Some("<"),
+            Op::Gt => Some(">"),
+            Op::Le => Some("<="),
+            Op::Ge => Some(">="),
+            _ => None,
+        };
+    }
+
+    // the prefix operator a unary op stands for.
+    pub fn prefix_operator(&self) -> Option<&'static str> {
+        return match self {
+            Op::Minus => Some("-"),
+            Op::Bang => Some("!"),
+            Op::BitNot => Some("~"),
+            _ => None,
+        };
+    }
+
+    pub fn from_prefix_operator(op: &str) -> Option<Op> {
+        return OPS.iter().copied().find(|o| o.prefix_operator() == Some(op));
+    }
+
+    pub fn from_infix_operator(op: &str) -> Option<Op> {
+        return OPS.iter().copied().find(|o| o.infix_operator() == Some(op));
+    }
+}
+
+// the largest operand that fits in width bytes.
+pub fn max_operand(width: usize) -> usize {
+    return (1 << (8 * width)) - 1;
+}
+
+// encode an instruction. Panics if an operand is wider than max_operand
+// allows, rather than silently cutting it down.
+pub fn make(op: Op, operands: &[usize]) -> Vec<u8> {
+    let widths = op.operand_widths();
+    let mut ins = vec![op as u8];
+    for (operand, width) in operands.iter().zip(widths) {
+        match width {
+            1 => ins.push(u8::try_from(*operand).expect("operand wider than 1 byte")),
+            2 => {
+                let operand = u16::try_from(*operand).expect("operand wider than 2 bytes");
+                ins.extend_from_slice(&operand.to_be_bytes());
+            },
+            _ => unreachable!(),
+        }
+    }
+    ins
+}
+
+// decode the operands of op, which start at ins[0]. Returns the operands
+// and the number of bytes read.
+pub fn read_operands(op: Op, ins: &[u8]) -> (Vec<usize>, usize) {
+    let mut operands = vec![];
+    let mut offset = 0;
+    for width in op.operand_widths() {
+        match width {
+            1 => operands.push(ins[offset] as usize),
+            2 => operands.push(read_u16(ins, offset)),
+            _ => unreachable!(),
+        }
+        offset += width;
+    }
+    (operands, offset)
+}
+
+pub fn read_u16(ins: &[u8], at: usize) -> usize {
+    return u16::from_be_bytes([ins[at], ins[at + 1]]) as usize;
+}
+
+// a human readable listing of instructions, one per line.
+pub fn disassemble(ins: &[u8]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < ins.len() {
+        let op = match Op::from_byte(ins[i]) {
+            Some(op) => op,
+            None => {
+                let _ = writeln!(out, "{:04} ERROR: unknown opcode {}", i, ins[i]);
+                i += 1;
+                continue;
+            },
+        };
+        let (operands, n) = read_operands(op, &ins[i + 1..]);
+        let _ = write!(out, "{:04} {:?}", i, op);
+        for operand in operands.iter() {
+            let _ = write!(out, " {}", operand);
+        }
+        out.push('\n');
+        i += 1 + n;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn make_instructions() {
+        struct Test {
+            op: Op,
+            operands: Vec<usize>,
+            expected: Vec<u8>,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test { op: Op::Constant, operands: vec![65534], expected: vec![Op::Constant as u8, 255, 254] },
+            Test { op: Op::Add, operands: vec![], expected: vec![Op::Add as u8] },
+            Test { op: Op::GetLocal, operands: vec![1, 258], expected: vec![Op::GetLocal as u8, 1, 1, 2] },
+            Test { op: Op::Call, operands: vec![255], expected: vec![Op::Call as u8, 0, 255] },
+        ];
+
+        for test in tests.iter() {
+            let ins = make(test.op, &test.operands);
+            assert_eq!(ins, test.expected);
+
+            let (operands, n) = read_operands(test.op, &ins[1..]);
+            assert_eq!(n, ins.len() - 1);
+            assert_eq!(operands, test.operands);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "operand wider than 2 bytes")]
+    fn make_rejects_wide_operands() {
+        assert_eq!(max_operand(1), 255);
+        assert_eq!(max_operand(2), 65535);
+        make(Op::Constant, &[65536]);
+    }
+
+    #[test]
+    fn op_from_byte() {
+        for (i, op) in OPS.iter().enumerate() {
+            assert_eq!(*op as usize, i);
+            assert_eq!(Op::from_byte(i as u8), Some(*op));
+        }
+        assert_eq!(Op::from_byte(OPS.len() as u8), None);
+        assert_eq!(Op::from_infix_operator("<"), Some(Op::Lt));
+        assert_eq!(Op::from_infix_operator("<="), Some(Op::Le));
+        assert_eq!(Op::from_infix_operator("&&"), None);
+        assert_eq!(Op::from_prefix_operator("~"), Some(Op::BitNot));
+    }
+
+    #[test]
+    fn disassemble_instructions() {
+        let ins: Vec<u8> = vec![
+            make(Op::Add, &[]),
+            make(Op::GetLocal, &[0, 1]),
+            make(Op::Constant, &[2]),
+            make(Op::Constant, &[65535]),
+            make(Op::Call, &[2]),
+            make(Op::Pop, &[]),
+        ].concat();
+
+        let expected = "0000 Add
+0001 GetLocal 0 1
+0005 Constant 2
+0008 Constant 65535
+0011 Call 2
+0014 Pop
+";
+        assert_eq!(disassemble(&ins), expected);
+    }
+}
@@ -0,0 +1,1059 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use crate::ast;
+use crate::code;
+use crate::code::Op;
+use crate::object::{
+    Object,
+    Int,
+    Str,
+    CompiledFunc,
+    HashKey,
+};
+use crate::token::Span;
+
+// Compiler lowers a program to bytecode for the vm module. Constants,
+// functions and globals accumulate across calls to compile, so a program
+// can use what an earlier one defined, as in a REPL session. Equal
+// constants and functions are stored once, so compiling the same code
+// again adds nothing, and a program that fails to compile adds nothing
+// either.
+pub struct Compiler {
+    constants: Vec<Object>,
+    // the index of each constant, by its value.
+    constant_index: HashMap<HashKey, usize>,
+    funcs: Vec<Rc<CompiledFunc>>,
+    // the indexes of the functions with each body.
+    func_index: HashMap<String, Vec<usize>>,
+    globals: HashMap<String, usize>,
+    global_names: Vec<String>,
+    // the functions being compiled, innermost last. The first one is the
+    // program itself, whose bindings are globals rather than locals.
+    scopes: Vec<FuncScope>,
+    // the first operand of the program being compiled that did not fit.
+    error: Option<CompileError>,
+    // the innermost node being compiled. Instructions without a span of
+    // their own get its, so the vm can say where it ran out of budget.
+    span: Span,
+}
+
+// an operand too big for the instruction it belongs to, as when a program
+// has more constants or globals, or more bytecode to jump over, than two
+// bytes can address.
+#[derive(Debug)]
+pub struct CompileError {
+    pub op: Op,
+    pub operand: usize,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "operand {} of {:?} does not fit in its instruction", self.operand, self.op)
+    }
+}
+
+// the compiled form of a program, together with the pools it refers to.
+pub struct Bytecode<'a> {
+    pub main: Rc<CompiledFunc>,
+    pub constants: &'a [Object],
+    pub funcs: &'a [Rc<CompiledFunc>],
+    // the name of each global slot.
+    pub globals: &'a [String],
+}
+
+struct FuncScope {
+    instructions: Vec<u8>,
+    spans: Vec<(usize, Span)>,
+    locals: HashMap<String, usize>,
+    names: Vec<String>,
+    last_op: Option<Op>,
+    // how many values the instructions so far leave on the stack above the
+    // frame's base, or None if execution cannot reach the next one.
+    depth: Option<usize>,
+    // the loops being compiled, innermost last.
+    loops: Vec<Loop>,
+}
+
+// the jumps out of a loop body, patched once the loop is compiled.
+struct Loop {
+    span: Span,
+    // the stack height at the top of the body, which `break` and `continue`
+    // cut back to, or None if the loop cannot be reached.
+    height: Option<usize>,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// where a name is stored at run time. A local is addressed by how many
+// function scopes out it lives and its slot there.
+#[derive(Clone, Copy)]
+enum Symbol {
+    Global(usize),
+    Local(usize, usize),
+}
+
+pub fn new() -> Compiler {
+    return Compiler {
+        constants: vec![],
+        constant_index: HashMap::new(),
+        funcs: vec![],
+        func_index: HashMap::new(),
+        globals: HashMap::new(),
+        global_names: vec![],
+        scopes: vec![],
+        error: None,
+        span: Span::default(),
+    };
+}
+
+fn new_scope() -> FuncScope {
+    return FuncScope {
+        instructions: vec![],
+        spans: vec![],
+        locals: HashMap::new(),
+        names: vec![],
+        last_op: None,
+        depth: Some(0),
+        loops: vec![],
+    };
+}
+
+impl Compiler {
+    // the value of a program is that of its last statement, which the vm
+    // keeps as the last value popped.
+    pub fn compile(&mut self, p: &ast::Program) -> Result<Bytecode<'_>, CompileError> {
+        self.scopes = vec![new_scope()];
+        self.error = None;
+        let (constants, funcs, globals) = (self.constants.len(), self.funcs.len(), self.global_names.len());
+        for stmt in p.stmts.iter() {
+            self.compile_stmt(stmt);
+        }
+        let main = self.leave_scope(String::new());
+        if let Some(e) = self.error.take() {
+            self.constants.truncate(constants);
+            self.constant_index.retain(|_, i| *i < constants);
+            self.funcs.truncate(funcs);
+            for indexes in self.func_index.values_mut() {
+                indexes.retain(|i| *i < funcs);
+            }
+            self.func_index.retain(|_, indexes| !indexes.is_empty());
+            self.global_names.truncate(globals);
+            self.globals.retain(|_, i| *i < globals);
+            return Err(e);
+        }
+
+        return Ok(Bytecode {
+            main: Rc::new(main),
+            constants: &self.constants,
+            funcs: &self.funcs,
+            globals: &self.global_names,
+        });
+    }
+
+    fn compile_stmt(&mut self, stmt: &ast::Stmt) {
+        let outer = mem::replace(&mut self.span, stmt.span());
+        match stmt {
+            ast::Stmt::ExprStmt(es) => {
+                self.compile_expr(&es.expr);
+                self.emit(Op::Pop, &[]);
+            },
+            // a `let` evaluates to the bound value, so the set leaves it on
+            // the stack.
+            ast::Stmt::Let(l) => {
+                let symbol = self.define(&l.name.val);
+                self.compile_expr(&l.val);
+                let span = l.name.span;
+                match (symbol, l.constant) {
+                    (Symbol::Global(i), false) => self.emit_at(Op::SetGlobal, &[i], span),
+                    (Symbol::Global(i), true) => self.emit_at(Op::ConstGlobal, &[i], span),
+                    (Symbol::Local(depth, i), false) => self.emit_at(Op::SetLocal, &[depth, i], span),
+                    (Symbol::Local(depth, i), true) => self.emit_at(Op::ConstLocal, &[depth, i], span),
+                };
+                self.emit(Op::Pop, &[]);
+            },
+            ast::Stmt::Return(r) => {
+                self.compile_expr(&r.val);
+                self.emit(Op::ReturnValue, &[]);
+            },
+            ast::Stmt::Block(b) => {
+                for stmt in b.stmts.iter() {
+                    self.compile_stmt(stmt);
+                }
+            },
+            // like `let`, a loop leaves a value to pop: null.
+            ast::Stmt::While(w) => {
+                self.compile_while(w);
+                self.emit(Op::Null, &[]);
+                self.emit(Op::Pop, &[]);
+            },
+            ast::Stmt::For(f) => {
+                self.compile_for(f);
+                self.emit(Op::Null, &[]);
+                self.emit(Op::Pop, &[]);
+            },
+            ast::Stmt::Break(_) => {
+                self.unwind();
+                let jump = self.emit(Op::Jump, &[0]);
+                self.scope().loops.last_mut().unwrap().breaks.push(jump);
+            },
+            // continuing jumps backwards, so it too is where the vm checks
+            // for interrupts.
+            ast::Stmt::Continue(_) => {
+                self.unwind();
+                let span = self.scope().loops.last().unwrap().span;
+                let jump = self.emit_at(Op::Jump, &[0], span);
+                self.scope().loops.last_mut().unwrap().continues.push(jump);
+            },
+        }
+        self.span = outer;
+    }
+
+    // the jump back to the condition carries the loop's span, as the vm
+    // checks for interrupts there.
+    fn compile_while(&mut self, w: &ast::While) {
+        let start = self.scope().instructions.len();
+        let height = self.scope().depth;
+        self.compile_expr(&w.cond);
+        let exit = self.emit(Op::JumpNotTruthy, &[0]);
+
+        self.scope().loops.push(Loop {
+            span: w.span,
+            height: height,
+            breaks: vec![],
+            continues: vec![],
+        });
+        for stmt in w.body.stmts.iter() {
+            self.compile_stmt(stmt);
+        }
+        let l = self.scope().loops.pop().unwrap();
+        self.emit_at(Op::Jump, &[start], w.span);
+        self.scope().depth = height;
+
+        let end = self.scope().instructions.len();
+        self.change_operand(exit, end);
+        for pos in l.breaks.into_iter() {
+            self.change_operand(pos, end);
+        }
+        for pos in l.continues.into_iter() {
+            self.change_operand(pos, start);
+        }
+    }
+
+    // a block used as a value leaves its last statement's value on the
+    // stack, or null if it is empty.
+    fn compile_block(&mut self, b: &ast::Block, tail: bool) {
+        if tail {
+            self.compile_tail_stmts(&b.stmts);
+        } else {
+            for stmt in b.stmts.iter() {
+                self.compile_stmt(stmt);
+            }
+        }
+        if !self.remove_last_pop() && b.stmts.is_empty() {
+            self.emit(Op::Null, &[]);
+        }
+    }
+
+    // the iterable and a cursor into it sit on the stack while the loop
+    // runs. Next pushes the item at the cursor and advances it, or jumps
+    // to the end once there are no more items.
+    fn compile_for(&mut self, f: &ast::For) {
+        let symbol = self.define(&f.var.val);
+        self.compile_expr(&f.iter);
+        self.emit_at(Op::Iter, &[], f.iter.span());
+
+        let start = self.emit(Op::Next, &[0]);
+        match symbol {
+            Symbol::Global(i) => self.emit_at(Op::SetGlobal, &[i], f.var.span),
+            Symbol::Local(depth, i) => self.emit_at(Op::SetLocal, &[depth, i], f.var.span),
+        };
+        self.emit(Op::Pop, &[]);
+        let height = self.scope().depth;
+
+        self.scope().loops.push(Loop {
+            span: f.span,
+            height: height,
+            breaks: vec![],
+            continues: vec![],
+        });
+        for stmt in f.body.stmts.iter() {
+            self.compile_stmt(stmt);
+        }
+        let l = self.scope().loops.pop().unwrap();
+        self.emit_at(Op::Jump, &[start], f.span);
+        self.scope().depth = height;
+
+        // both ways out of the loop leave the iterable and cursor behind.
+        let end = self.scope().instructions.len();
+        self.change_operand(start, end);
+        for pos in l.breaks.into_iter() {
+            self.change_operand(pos, end);
+        }
+        for pos in l.continues.into_iter() {
+            self.change_operand(pos, start);
+        }
+        self.emit(Op::Pop, &[]);
+        self.emit(Op::Pop, &[]);
+    }
+
+    fn compile_expr(&mut self, expr: &ast::Expr) {
+        let outer = mem::replace(&mut self.span, expr.span());
+        match expr {
+            ast::Expr::Int(n) => {
+                let i = self.add_constant(Object::Int(Int { val: n.val }));
+                self.emit(Op::Constant, &[i]);
+            },
+            ast::Expr::Str(s) => {
+                let i = self.add_constant(Object::Str(Str { val: s.val.clone() }));
+                self.emit_at(Op::Constant, &[i], s.span);
+            },
+            ast::Expr::Bool(b) => {
+                self.emit(if b.val { Op::True } else { Op::False }, &[]);
+            },
+            ast::Expr::Ident(i) => {
+                let symbol = self.resolve(&i.val);
+                self.load(symbol, i.span);
+            },
+            // like a `let`, an assignment leaves the assigned value on the
+            // stack.
+            ast::Expr::Assign(a) => {
+                let symbol = self.resolve(&a.name.val);
+                if a.op != "=" {
+                    self.load(symbol, a.name.span);
+                }
+                self.compile_expr(&a.val);
+                if a.op != "=" {
+                    match Op::from_infix_operator(a.op.trim_end_matches('=')) {
+                        Some(op) => self.emit_at(op, &[], a.span),
+                        None => panic!("unknown assignment operator {}", a.op),
+                    };
+                }
+                match symbol {
+                    Symbol::Global(g) => self.emit_at(Op::AssignGlobal, &[g], a.name.span),
+                    Symbol::Local(depth, l) => {
+                        self.emit_at(Op::AssignLocal, &[depth, l], a.name.span)
+                    },
+                };
+            },
+            ast::Expr::Prefix(p) => {
+                self.compile_expr(&p.rhs);
+                match Op::from_prefix_operator(&p.op) {
+                    Some(op) => self.emit_at(op, &[], p.span),
+                    None => panic!("unknown prefix operator {}", p.op),
+                };
+            },
+            ast::Expr::Infix(i) => self.compile_infix(i),
+            ast::Expr::Range(r) => {
+                self.compile_expr(&r.start);
+                self.compile_expr(&r.end);
+                self.emit_at(Op::Range, &[], r.span);
+            },
+            ast::Expr::If(i) => self.compile_if(i, false),
+            ast::Expr::Func(f) => self.compile_func(f),
+            ast::Expr::Call(c) => self.compile_call(c, Op::Call),
+            ast::Expr::Array(a) => {
+                for elem in a.elems.iter() {
+                    self.compile_expr(elem);
+                }
+                self.emit_at(Op::Array, &[a.elems.len()], a.span);
+            },
+            ast::Expr::Hash(h) => {
+                for (key, val) in h.pairs.iter() {
+                    self.compile_expr(key);
+                    self.emit_at(Op::HashKey, &[], key.span());
+                    self.compile_expr(val);
+                }
+                self.emit_at(Op::Hash, &[h.pairs.len()], h.span);
+            },
+            ast::Expr::Index(i) => {
+                self.compile_expr(&i.lhs);
+                self.compile_expr(&i.index);
+                self.emit_at(Op::Index, &[], i.span);
+            },
+        }
+        self.span = outer;
+    }
+
+    // a chain of operators is compiled from the left in a loop, however long
+    // it is. The deciding operand of `&&` or `||` stays on the stack as the
+    // result; otherwise it is dropped and rhs is the result.
+    fn compile_infix(&mut self, i: &ast::Infix) {
+        let (first, chain) = i.chain();
+        self.compile_expr(first);
+        for i in chain.into_iter() {
+            if i.op == "&&" || i.op == "||" {
+                let kept = self.scope().depth;
+                let op = if i.op == "&&" { Op::JumpFalsy } else { Op::JumpTruthy };
+                let jump = self.emit(op, &[0]);
+                self.compile_expr(&i.rhs);
+                let end = self.scope().instructions.len();
+                self.change_operand(jump, end);
+                let scope = self.scope();
+                scope.depth = scope.depth.or(kept);
+                continue;
+            }
+            self.compile_expr(&i.rhs);
+            match Op::from_infix_operator(&i.op) {
+                Some(op) => self.emit_at(op, &[], i.span),
+                None => panic!("unknown infix operator {}", i.op),
+            };
+        }
+    }
+
+    fn load(&mut self, symbol: Symbol, span: Span) {
+        match symbol {
+            Symbol::Global(g) => self.emit_at(Op::GetGlobal, &[g], span),
+            Symbol::Local(depth, l) => self.emit_at(Op::GetLocal, &[depth, l], span),
+        };
+    }
+
+    // the statements whose value a function returns. As in the evaluator,
+    // the last one and any `return` are in tail position, and a call there
+    // replaces the running one rather than adding to the call stack.
+    fn compile_tail_stmts(&mut self, stmts: &[ast::Stmt]) {
+        for (i, stmt) in stmts.iter().enumerate() {
+            let last = i + 1 == stmts.len();
+            let outer = mem::replace(&mut self.span, stmt.span());
+            match stmt {
+                ast::Stmt::Return(r) => {
+                    self.compile_tail_expr(&r.val);
+                    self.emit(Op::ReturnValue, &[]);
+                },
+                ast::Stmt::ExprStmt(es) if last => {
+                    self.compile_tail_expr(&es.expr);
+                    self.emit(Op::Pop, &[]);
+                },
+                ast::Stmt::Block(b) if last => self.compile_tail_stmts(&b.stmts),
+                _ => self.compile_stmt(stmt),
+            }
+            self.span = outer;
+        }
+    }
+
+    fn compile_tail_expr(&mut self, expr: &ast::Expr) {
+        let outer = mem::replace(&mut self.span, expr.span());
+        match expr {
+            ast::Expr::Call(c) => self.compile_call(c, Op::TailCall),
+            ast::Expr::If(i) => self.compile_if(i, true),
+            _ => self.compile_expr(expr),
+        }
+        self.span = outer;
+    }
+
+    fn compile_call(&mut self, c: &ast::Call, op: Op) {
+        self.compile_expr(&c.func);
+        for arg in c.args.iter() {
+            self.compile_expr(arg);
+        }
+        self.emit_at(op, &[c.args.len()], c.span);
+    }
+
+    // each branch jumps to the next one when its condition is falsy, and to
+    // the end once its block is done.
+    fn compile_if(&mut self, i: &ast::If, tail: bool) {
+        let mut jumps = vec![];
+        let mut cons_depth = None;
+        for (cond, cons) in i.branches() {
+            self.compile_expr(cond);
+            let jump_not_truthy = self.emit(Op::JumpNotTruthy, &[0]);
+            let depth = self.scope().depth;
+            self.compile_block(cons, tail);
+            cons_depth = cons_depth.or(self.scope().depth);
+            jumps.push(self.emit(Op::Jump, &[0]));
+            self.scope().depth = depth;
+
+            let next = self.scope().instructions.len();
+            self.change_operand(jump_not_truthy, next);
+        }
+        match &i.alt {
+            Some(alt) => self.compile_block(alt, tail),
+            None => {
+                self.emit(Op::Null, &[]);
+            },
+        }
+        let end = self.scope().instructions.len();
+        for jump in jumps.into_iter() {
+            self.change_operand(jump, end);
+        }
+        let scope = self.scope();
+        scope.depth = cons_depth.or(scope.depth);
+    }
+
+    // every name a function binds gets a slot up front, so that functions
+    // bound later in the body can still be called from earlier ones.
+    fn compile_func(&mut self, f: &ast::Func) {
+        self.scopes.push(new_scope());
+        for param in f.params.iter() {
+            self.define(&param.name.val);
+        }
+        if let Some(rest) = &f.rest {
+            self.define(&rest.val);
+        }
+        let mut lets = vec![];
+        for default in f.params.iter().filter_map(|p| p.default.as_ref()) {
+            expr_lets(default, &mut lets);
+        }
+        hoisted_lets(&f.body.stmts, &mut lets);
+        for name in lets.iter() {
+            self.define(name);
+        }
+
+        // the vm leaves the slot of a parameter that was not passed empty,
+        // and the default fills it.
+        for (i, param) in f.params.iter().enumerate() {
+            if let Some(default) = &param.default {
+                let jump = self.emit(Op::JumpPassed, &[0, i]);
+                self.compile_expr(default);
+                self.emit(Op::SetLocal, &[0, i]);
+                self.emit(Op::Pop, &[]);
+                let after = self.scope().instructions.len();
+                self.change_operand(jump, after);
+                // this pop is not the value of a statement in the body.
+                self.scope().last_op = None;
+            }
+        }
+
+        self.compile_tail_stmts(&f.body.stmts);
+        if self.remove_last_pop() {
+            self.emit(Op::ReturnValue, &[]);
+        } else if self.scope().last_op != Some(Op::ReturnValue) {
+            self.emit(Op::Return, &[]);
+        }
+
+        let mut func = self.leave_scope(format!("{}", f.body));
+        func.name = f.name.clone();
+        func.num_params = f.params.len();
+        func.num_required = f.required();
+        func.rest = f.rest.is_some();
+        func.params = f.params_string();
+        let i = self.add_func(func);
+        self.emit(Op::Closure, &[i]);
+    }
+
+    fn add_func(&mut self, func: CompiledFunc) -> usize {
+        let funcs = &self.funcs;
+        let indexes = self.func_index.entry(func.body.clone()).or_default();
+        if let Some(&i) = indexes.iter().find(|&&i| *funcs[i] == func) {
+            return i;
+        }
+        indexes.push(funcs.len());
+        self.funcs.push(Rc::new(func));
+        return self.funcs.len() - 1;
+    }
+
+    fn leave_scope(&mut self, body: String) -> CompiledFunc {
+        let scope = self.scopes.pop().unwrap();
+        return CompiledFunc {
+            instructions: scope.instructions,
+            spans: scope.spans,
+            name: None,
+            num_params: 0,
+            num_required: 0,
+            rest: false,
+            params: String::new(),
+            locals: scope.names,
+            body: body,
+        };
+    }
+
+    fn scope(&mut self) -> &mut FuncScope {
+        return self.scopes.last_mut().unwrap();
+    }
+
+    // constants are literals, so their hash keys tell them apart.
+    fn add_constant(&mut self, obj: Object) -> usize {
+        let key = obj.hash_key().expect("constant is not a literal");
+        if let Some(&i) = self.constant_index.get(&key) {
+            return i;
+        }
+        self.constants.push(obj);
+        self.constant_index.insert(key, self.constants.len() - 1);
+        return self.constants.len() - 1;
+    }
+
+    // append an instruction and return its offset.
+    fn emit(&mut self, op: Op, operands: &[usize]) -> usize {
+        let span = self.span;
+        return self.emit_at(op, operands, span);
+    }
+
+    // append an instruction located at span. A span is only recorded where
+    // it changes, and an instruction is at the last one recorded before it.
+    fn emit_at(&mut self, op: Op, operands: &[usize], span: Span) -> usize {
+        let ins = self.make(op, operands);
+        let scope = self.scope();
+        let pos = scope.instructions.len();
+        if scope.spans.last().map(|&(_, last)| last) != Some(span) {
+            scope.spans.push((pos, span));
+        }
+        scope.instructions.extend(ins);
+        scope.last_op = Some(op);
+        scope.depth = match op {
+            Op::Jump | Op::Return | Op::ReturnValue => None,
+            Op::Unwind => Some(operands[0]),
+            _ => scope.depth.map(|d| (d as isize + op.stack_effect(operands)) as usize),
+        };
+        return pos;
+    }
+
+    fn remove_last_pop(&mut self) -> bool {
+        let scope = self.scope();
+        if scope.last_op != Some(Op::Pop) {
+            return false;
+        }
+        scope.instructions.pop();
+        if scope.spans.last().map(|&(at, _)| at) == Some(scope.instructions.len()) {
+            scope.spans.pop();
+        }
+        scope.last_op = None;
+        scope.depth = scope.depth.map(|d| d + 1);
+        return true;
+    }
+
+    // before `break` or `continue` jumps, drop what enclosing expressions
+    // have pushed since the innermost loop's body began, as when the jump is
+    // in an `if` used as an operand.
+    fn unwind(&mut self) {
+        let scope = self.scope();
+        let height = scope.loops.last().unwrap().height;
+        if let (Some(depth), Some(height)) = (scope.depth, height) {
+            if depth > height {
+                self.emit(Op::Unwind, &[height]);
+            }
+        }
+    }
+
+    // replace the first operand of the instruction at pos, which for jumps
+    // is the target.
+    fn change_operand(&mut self, pos: usize, operand: usize) {
+        let op = Op::from_byte(self.scope().instructions[pos]).unwrap();
+        let new = self.make(op, &[operand]);
+        self.scope().instructions[pos..pos + new.len()].copy_from_slice(&new);
+    }
+
+    // encode an instruction, noting the first operand too wide for it. The
+    // rest of the program is still compiled, with such operands as 0, but
+    // compile returns the error instead of the bytecode.
+    fn make(&mut self, op: Op, operands: &[usize]) -> Vec<u8> {
+        for (&operand, &width) in operands.iter().zip(op.operand_widths()) {
+            if operand > code::max_operand(width) {
+                if self.error.is_none() {
+                    self.error = Some(CompileError { op: op, operand: operand });
+                }
+                return code::make(op, &vec![0; operands.len()]);
+            }
+        }
+        return code::make(op, operands);
+    }
+
+    fn define(&mut self, name: &str) -> Symbol {
+        if self.scopes.len() == 1 {
+            return Symbol::Global(self.global(name));
+        }
+        let scope = self.scope();
+        if let Some(&i) = scope.locals.get(name) {
+            return Symbol::Local(0, i);
+        }
+        scope.locals.insert(String::from(name), scope.names.len());
+        scope.names.push(String::from(name));
+        return Symbol::Local(0, scope.names.len() - 1);
+    }
+
+    // look a name up from the innermost function outwards. Anything not
+    // bound by an enclosing function is a global, which may be defined later
+    // or be a builtin; the vm decides when it runs.
+    fn resolve(&mut self, name: &str) -> Symbol {
+        for (depth, scope) in self.scopes[1..].iter().rev().enumerate() {
+            if let Some(&i) = scope.locals.get(name) {
+                return Symbol::Local(depth, i);
+            }
+        }
+        return Symbol::Global(self.global(name));
+    }
+
+    // the name of each global slot allocated so far.
+    pub fn global_names(&self) -> &[String] {
+        return &self.global_names;
+    }
+
+    // the slot of the global called name, allocating one if needed.
+    pub fn global(&mut self, name: &str) -> usize {
+        if let Some(&i) = self.globals.get(name) {
+            return i;
+        }
+        self.globals.insert(String::from(name), self.global_names.len());
+        self.global_names.push(String::from(name));
+        return self.global_names.len() - 1;
+    }
+}
+
+// collect the names bound by `let` in stmts, including inside `if` blocks
+// but not inside nested functions, which get scopes of their own.
+fn hoisted_lets(stmts: &[ast::Stmt], names: &mut Vec<String>) {
+    for stmt in stmts.iter() {
+        match stmt {
+            ast::Stmt::Let(l) => {
+                names.push(l.name.val.clone());
+                expr_lets(&l.val, names);
+            },
+            ast::Stmt::Return(r) => expr_lets(&r.val, names),
+            ast::Stmt::ExprStmt(es) => expr_lets(&es.expr, names),
+            ast::Stmt::Block(b) => hoisted_lets(&b.stmts, names),
+            ast::Stmt::While(w) => {
+                expr_lets(&w.cond, names);
+                hoisted_lets(&w.body.stmts, names);
+            },
+            ast::Stmt::For(f) => {
+                names.push(f.var.val.clone());
+                expr_lets(&f.iter, names);
+                hoisted_lets(&f.body.stmts, names);
+            },
+            ast::Stmt::Break(_) | ast::Stmt::Continue(_) => (),
+        }
+    }
+}
+
+fn expr_lets(expr: &ast::Expr, names: &mut Vec<String>) {
+    match expr {
+        ast::Expr::If(i) => {
+            for (cond, cons) in i.branches() {
+                expr_lets(cond, names);
+                hoisted_lets(&cons.stmts, names);
+            }
+            if let Some(alt) = &i.alt {
+                hoisted_lets(&alt.stmts, names);
+            }
+        },
+        ast::Expr::Prefix(p) => expr_lets(&p.rhs, names),
+        ast::Expr::Assign(a) => expr_lets(&a.val, names),
+        ast::Expr::Range(r) => {
+            expr_lets(&r.start, names);
+            expr_lets(&r.end, names);
+        },
+        ast::Expr::Infix(i) => {
+            let (first, chain) = i.chain();
+            expr_lets(first, names);
+            for i in chain.into_iter() {
+                expr_lets(&i.rhs, names);
+            }
+        },
+        ast::Expr::Call(c) => {
+            expr_lets(&c.func, names);
+            for arg in c.args.iter() {
+                expr_lets(arg, names);
+            }
+        },
+        ast::Expr::Array(a) => {
+            for elem in a.elems.iter() {
+                expr_lets(elem, names);
+            }
+        },
+        ast::Expr::Index(i) => {
+            expr_lets(&i.lhs, names);
+            expr_lets(&i.index, names);
+        },
+        ast::Expr::Hash(h) => {
+            for (key, val) in h.pairs.iter() {
+                expr_lets(key, names);
+                expr_lets(val, names);
+            }
+        },
+        ast::Expr::Ident(_) | ast::Expr::Int(_) | ast::Expr::Str(_) |
+        ast::Expr::Bool(_) | ast::Expr::Func(_) => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lexer;
+    use crate::parser;
+
+    #[test]
+    fn compile_exprs() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                input: "1 + 2; let x = 3;",
+                expected: "0000 Constant 0
+0003 Constant 1
+0006 Add
+0007 Pop
+0008 Constant 2
+0011 SetGlobal 0
+0014 Pop
+",
+            },
+            Test {
+                input: "-x; !y[0]",
+                expected: "0000 GetGlobal 0
+0003 Minus
+0004 Pop
+0005 GetGlobal 1
+0008 Constant 0
+0011 Index
+0012 Bang
+0013 Pop
+",
+            },
+            Test {
+                input: "if (true) { 10 }; 3333;",
+                expected: "0000 True
+0001 JumpNotTruthy 10
+0004 Constant 0
+0007 Jump 11
+0010 Null
+0011 Pop
+0012 Constant 1
+0015 Pop
+",
+            },
+            Test {
+                input: "const c = 1; c *= 2",
+                expected: "0000 Constant 0
+0003 ConstGlobal 0
+0006 Pop
+0007 GetGlobal 0
+0010 Constant 1
+0013 Mul
+0014 AssignGlobal 0
+0017 Pop
+",
+            },
+            Test {
+                input: "while (x) { x = 1; continue; }",
+                expected: "0000 GetGlobal 0
+0003 JumpNotTruthy 19
+0006 Constant 0
+0009 AssignGlobal 0
+0012 Pop
+0013 Jump 0
+0016 Jump 0
+0019 Null
+0020 Pop
+",
+            },
+            Test {
+                input: "while (x) { 1 + if (x) { break; } else { 2 }; }",
+                expected: "0000 GetGlobal 0
+0003 JumpNotTruthy 32
+0006 Constant 0
+0009 GetGlobal 0
+0012 JumpNotTruthy 24
+0015 Unwind 0
+0018 Jump 32
+0021 Jump 27
+0024 Constant 1
+0027 Add
+0028 Pop
+0029 Jump 0
+0032 Null
+0033 Pop
+",
+            },
+            Test {
+                input: "for (x in y) { break; }",
+                expected: "0000 GetGlobal 1
+0003 Iter
+0004 Next 17
+0007 SetGlobal 0
+0010 Pop
+0011 Jump 17
+0014 Jump 4
+0017 Pop
+0018 Pop
+0019 Null
+0020 Pop
+",
+            },
+            Test {
+                input: "if (true) { } else { let a = 1; }",
+                expected: "0000 True
+0001 JumpNotTruthy 8
+0004 Null
+0005 Jump 14
+0008 Constant 0
+0011 SetGlobal 0
+0014 Pop
+",
+            },
+            Test {
+                input: "[1, \"a\"]; {1: 2}; f(3, 4)",
+                expected: "0000 Constant 0
+0003 Constant 1
+0006 Array 2
+0009 Pop
+0010 Constant 0
+0013 HashKey
+0014 Constant 2
+0017 Hash 1
+0020 Pop
+0021 GetGlobal 0
+0024 Constant 3
+0027 Constant 4
+0030 Call 2
+0033 Pop
+",
+            },
+        ];
+
+        for test in tests.iter() {
+            let mut c = new();
+            let bc = c.compile(&parse(test.input)).unwrap();
+            assert_eq!(code::disassemble(&bc.main.instructions), test.expected);
+        }
+    }
+
+    #[test]
+    fn compile_funcs() {
+        let mut c = new();
+        let bc = c.compile(&parse("fn(a) { fn(b) { a + b } }")).unwrap();
+        assert_eq!(code::disassemble(&bc.main.instructions), "0000 Closure 1
+0003 Pop
+");
+        assert_eq!(code::disassemble(&bc.funcs[0].instructions), "0000 GetLocal 1 0
+0004 GetLocal 0 0
+0008 Add
+0009 ReturnValue
+");
+        assert_eq!(code::disassemble(&bc.funcs[1].instructions), "0000 Closure 0
+0003 ReturnValue
+");
+
+        let mut c = new();
+        let bc = c.compile(&parse("fn() { g(); if (true) { let g = fn() { }; } }")).unwrap();
+        let f = &bc.funcs[1];
+        assert_eq!(f.locals, vec!["g"]);
+        assert_eq!(code::disassemble(&f.instructions), "0000 GetLocal 0 0
+0004 Call 0
+0007 Pop
+0008 True
+0009 JumpNotTruthy 22
+0012 Closure 0
+0015 SetLocal 0 0
+0019 Jump 23
+0022 Null
+0023 ReturnValue
+");
+        assert_eq!(code::disassemble(&bc.funcs[0].instructions), "0000 Return
+");
+
+        let mut c = new();
+        let bc = c.compile(&parse("fn(n) { if (n) { return f(n); } g(); if (n) { h() } }")).unwrap();
+        assert_eq!(code::disassemble(&bc.funcs[0].instructions), "0000 GetLocal 0 0
+0004 JumpNotTruthy 21
+0007 GetGlobal 0
+0010 GetLocal 0 0
+0014 Call 1
+0017 ReturnValue
+0018 Jump 22
+0021 Null
+0022 Pop
+0023 GetGlobal 1
+0026 Call 0
+0029 Pop
+0030 GetLocal 0 0
+0034 JumpNotTruthy 46
+0037 GetGlobal 2
+0040 TailCall 0
+0043 Jump 47
+0046 Null
+0047 ReturnValue
+");
+
+        let mut c = new();
+        let bc = c.compile(&parse("let f = fn(a, b = a, ...c) { }")).unwrap();
+        let f = &bc.funcs[0];
+        assert_eq!(f.name.as_deref(), Some("f"));
+        assert_eq!((f.num_params, f.num_required, f.rest), (2, 1, true));
+        assert_eq!(f.locals, vec!["a", "b", "c"]);
+        assert_eq!(f.params, "a, b = a, ...c");
+        assert_eq!(code::disassemble(&f.instructions), "0000 JumpPassed 14 1
+0005 GetLocal 0 0
+0009 SetLocal 0 1
+0013 Pop
+0014 Return
+");
+    }
+
+    #[test]
+    fn compile_keeps_globals() {
+        let mut c = new();
+        c.compile(&parse("let a = 1; b;")).unwrap();
+        let bc = c.compile(&parse("let b = a;")).unwrap();
+        assert_eq!(code::disassemble(&bc.main.instructions), "0000 GetGlobal 0
+0003 SetGlobal 1
+0006 Pop
+");
+        assert_eq!(bc.globals, &["a", "b"]);
+        assert_eq!(bc.constants.len(), 1);
+    }
+
+    #[test]
+    fn compile_spans() {
+        let mut c = new();
+        let bc = c.compile(&parse("let x = 1;\nx + len(x)")).unwrap();
+        let spans: Vec<String> = bc.main.spans.iter()
+            .map(|(at, span)| format!("{} {}", at, span))
+            .collect();
+        assert_eq!(spans, vec!["0 1:9", "3 1:5", "6 1:1", "7 2:1", "10 2:5", "13 2:9", "16 2:5", "19 2:1"]);
+    }
+
+    #[test]
+    fn compile_wide_operands() {
+        // constant 65536 needs a third byte.
+        let input: String = (0..70000).map(|i| format!("{};", i)).collect();
+        let mut c = new();
+        let err = c.compile(&parse(&input)).err().unwrap();
+        assert_eq!((err.op, err.operand), (Op::Constant, 65536));
+
+        // each `1;` is 4 bytes, so the jump past the block needs a target
+        // beyond 65535.
+        let input = format!("let y = 1; if (true) {{ {} }}", "1;".repeat(20000));
+        let mut c = new();
+        let err = c.compile(&parse(&input)).err().unwrap();
+        assert_eq!((err.op, err.operand), (Op::JumpNotTruthy, 80013));
+        assert_eq!(
+            format!("{}", err),
+            "operand 80013 of JumpNotTruthy does not fit in its instruction"
+        );
+
+        // the compiler is usable again afterwards, with nothing left of the
+        // program that failed.
+        let bc = c.compile(&parse("let x = 2;")).unwrap();
+        assert_eq!(code::disassemble(&bc.main.instructions), "0000 Constant 0
+0003 SetGlobal 0
+0006 Pop
+");
+        assert_eq!(bc.constants.len(), 1);
+        assert_eq!(bc.globals, &["x"]);
+    }
+
+    #[test]
+    fn compile_again() {
+        let input = "let f = fn(x) { x + 1 }; f(\"a\" + \"b\"); fn() { 2 }";
+        let mut c = new();
+        let (constants, funcs) = {
+            let bc = c.compile(&parse(input)).unwrap();
+            (bc.constants.len(), bc.funcs.len())
+        };
+        assert_eq!((constants, funcs), (4, 2));
+
+        // compiling the same program again reuses its constants and
+        // functions.
+        let bc = c.compile(&parse(input)).unwrap();
+        assert_eq!((bc.constants.len(), bc.funcs.len()), (constants, funcs));
+    }
+
+    fn parse(input: &str) -> ast::Program {
+        let mut l = lexer::new(input);
+        let mut p = parser::new(&mut l);
+        return p.parse_program().ok().unwrap();
+    }
+}
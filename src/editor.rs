@@ -0,0 +1,735 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+// the most lines kept in the history file.
+const MAX_HISTORY: usize = 1000;
+
+// Editor reads lines from a terminal with cursor movement, history, Ctrl-R
+// search and tab completion. The terminal is in raw mode only while a line
+// is being read.
+pub struct Editor {
+    history: Vec<String>,
+    path: Option<PathBuf>,
+    // keys read past the end of the last line, such as the rest of a paste.
+    pending: VecDeque<Key>,
+}
+
+pub enum Input {
+    Line(String),
+    // Ctrl-C was pressed.
+    Interrupted,
+    // Ctrl-D was pressed on an empty line, or input ended.
+    Eof,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+enum Key {
+    Char(char),
+    Ctrl(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Esc,
+    Unknown,
+}
+
+#[derive(PartialEq, Debug)]
+enum Action {
+    Continue,
+    Submit,
+    Cancel,
+    Eof,
+    // show these completions below the line.
+    List(Vec<String>),
+}
+
+// the line being edited.
+struct State {
+    buf: Vec<char>,
+    pos: usize,
+    // the history entry shown; history.len() for the line being typed.
+    hist: usize,
+    // the line being typed, kept while browsing history.
+    draft: Vec<char>,
+    search: Option<Search>,
+}
+
+// an incremental search backwards through history.
+struct Search {
+    query: String,
+    found: Option<usize>,
+}
+
+// history is loaded from path, if any, and saved back to it as lines are
+// added.
+pub fn new(path: Option<PathBuf>) -> Editor {
+    let history = match &path {
+        Some(path) => fs::read_to_string(path)
+            .map(|s| s.lines().filter(|l| !l.is_empty()).map(String::from).collect())
+            .unwrap_or_default(),
+        None => vec![],
+    };
+    return Editor {
+        history: history,
+        path: path,
+        pending: VecDeque::new(),
+    };
+}
+
+fn new_state(hist: usize) -> State {
+    return State {
+        buf: vec![],
+        pos: 0,
+        hist: hist,
+        draft: vec![],
+        search: None,
+    };
+}
+
+impl Editor {
+    // read a line after printing prompt. Tab completes the word before the
+    // cursor from words. When standard input is not a terminal the line is
+    // read as is.
+    pub fn read_line(&mut self, prompt: &str, words: &[String]) -> io::Result<Input> {
+        let _raw = match term::enable_raw_mode() {
+            Some(raw) => raw,
+            None => return read_plain_line(prompt),
+        };
+
+        let mut state = new_state(self.history.len());
+        render(prompt, &state)?;
+        loop {
+            if self.pending.is_empty() {
+                let bytes = term::read_bytes()?;
+                if bytes.is_empty() {
+                    print!("\r\n");
+                    return Ok(Input::Eof);
+                }
+                self.pending.extend(parse_keys(&bytes));
+            }
+
+            while let Some(key) = self.pending.pop_front() {
+                match state.handle(key, &self.history, words) {
+                    Action::Continue => (),
+                    Action::Submit => {
+                        print!("\r\n");
+                        io::stdout().flush()?;
+                        let line: String = state.buf.iter().collect();
+                        self.add_history(&line);
+                        return Ok(Input::Line(line));
+                    },
+                    Action::Cancel => {
+                        print!("^C\r\n");
+                        io::stdout().flush()?;
+                        return Ok(Input::Interrupted);
+                    },
+                    Action::Eof => {
+                        print!("\r\n");
+                        io::stdout().flush()?;
+                        return Ok(Input::Eof);
+                    },
+                    Action::List(words) => print!("\r\n{}\r\n", words.join("  ")),
+                }
+            }
+            render(prompt, &state)?;
+        }
+    }
+
+    // remember a line, skipping blanks and repeats of the previous one.
+    fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(|l| l.as_str()) == Some(line) {
+            return;
+        }
+        self.history.push(String::from(line));
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.path {
+            // history is a convenience, so failing to save it is not an error.
+            let _ = fs::write(path, self.history.join("\n") + "\n");
+        }
+    }
+}
+
+fn read_plain_line(prompt: &str) -> io::Result<Input> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Ok(Input::Eof);
+    }
+    return Ok(Input::Line(String::from(line.trim_end_matches(&['\r', '\n'][..]))));
+}
+
+fn render(prompt: &str, state: &State) -> io::Result<()> {
+    let (line, cursor) = match &state.search {
+        Some(search) => {
+            let line = format!(
+                "(reverse-i-search)`{}': {}",
+                search.query,
+                state.buf.iter().collect::<String>(),
+            );
+            let cursor = line.chars().count();
+            (line, cursor)
+        },
+        None => (
+            format!("{}{}", prompt, state.buf.iter().collect::<String>()),
+            prompt.chars().count() + state.pos,
+        ),
+    };
+
+    // redraw the whole line, clear what is left of the old one, then put
+    // the cursor back.
+    let mut out = io::stdout();
+    write!(out, "\r{}\x1b[K\r", line)?;
+    if cursor > 0 {
+        write!(out, "\x1b[{}C", cursor)?;
+    }
+    out.flush()
+}
+
+// split raw terminal input into keys. A chunk may hold several keys, as
+// when text is pasted.
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        i += 1;
+        let key = match b {
+            b'\r' | b'\n' => Key::Enter,
+            b'\t' => Key::Tab,
+            8 | 127 => Key::Backspace,
+            1..=26 => Key::Ctrl((b'a' + b - 1) as char),
+            27 => {
+                let (key, n) = parse_escape(&bytes[i..]);
+                i += n;
+                key
+            },
+            0..=31 => Key::Unknown,
+            _ => {
+                // a UTF-8 sequence is as long as its first byte says.
+                let len = match b {
+                    0xf0..=0xff => 4,
+                    0xe0..=0xef => 3,
+                    0xc0..=0xdf => 2,
+                    _ => 1,
+                };
+                let end = (i - 1 + len).min(bytes.len());
+                let key = match std::str::from_utf8(&bytes[i - 1..end]) {
+                    Ok(s) => Key::Char(s.chars().next().unwrap()),
+                    Err(_) => Key::Unknown,
+                };
+                i = end;
+                key
+            },
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+// decode the bytes after an escape. Returns the key and the number of bytes
+// used.
+fn parse_escape(bytes: &[u8]) -> (Key, usize) {
+    if bytes.len() < 2 || (bytes[0] != b'[' && bytes[0] != b'O') {
+        return (Key::Esc, 0);
+    }
+    let key = match bytes[1] {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        b'0'..=b'9' if bytes.len() > 2 && bytes[2] == b'~' => {
+            let key = match bytes[1] {
+                b'1' | b'7' => Key::Home,
+                b'4' | b'8' => Key::End,
+                b'3' => Key::Delete,
+                _ => Key::Unknown,
+            };
+            return (key, 3);
+        },
+        _ => {
+            // skip an unknown sequence up to its final byte.
+            let n = bytes[1..].iter()
+                .position(|b| (0x40..=0x7e).contains(b))
+                .map_or(bytes.len(), |p| p + 2);
+            return (Key::Unknown, n);
+        },
+    };
+    (key, 2)
+}
+
+impl State {
+    fn handle(&mut self, key: Key, history: &[String], words: &[String]) -> Action {
+        if self.search.is_some() {
+            match self.handle_search(&key, history) {
+                Some(action) => return action,
+                None => self.search = None,
+            }
+        }
+
+        match key {
+            Key::Char(c) => {
+                self.buf.insert(self.pos, c);
+                self.pos += 1;
+            },
+            Key::Enter => return Action::Submit,
+            Key::Ctrl('c') => return Action::Cancel,
+            Key::Ctrl('d') if self.buf.is_empty() => return Action::Eof,
+            Key::Ctrl('d') | Key::Delete if self.pos < self.buf.len() => {
+                self.buf.remove(self.pos);
+            },
+            Key::Backspace if self.pos > 0 => {
+                self.pos -= 1;
+                self.buf.remove(self.pos);
+            },
+            Key::Left | Key::Ctrl('b') => self.pos = self.pos.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.pos = (self.pos + 1).min(self.buf.len()),
+            Key::Home | Key::Ctrl('a') => self.pos = 0,
+            Key::End | Key::Ctrl('e') => self.pos = self.buf.len(),
+            Key::Ctrl('k') => self.buf.truncate(self.pos),
+            Key::Ctrl('u') => {
+                self.buf.drain(..self.pos);
+                self.pos = 0;
+            },
+            Key::Ctrl('w') => {
+                let start = self.word_start(|c| !c.is_whitespace());
+                let start = if start == self.pos {
+                    // delete the spaces before the cursor and the word before
+                    // them.
+                    let mut i = self.pos;
+                    while i > 0 && self.buf[i - 1].is_whitespace() {
+                        i -= 1;
+                    }
+                    while i > 0 && !self.buf[i - 1].is_whitespace() {
+                        i -= 1;
+                    }
+                    i
+                } else {
+                    start
+                };
+                self.buf.drain(start..self.pos);
+                self.pos = start;
+            },
+            Key::Up | Key::Ctrl('p') if self.hist > 0 => {
+                if self.hist == history.len() {
+                    self.draft = self.buf.clone();
+                }
+                self.hist -= 1;
+                self.set_line(history[self.hist].chars().collect());
+            },
+            Key::Down | Key::Ctrl('n') if self.hist < history.len() => {
+                self.hist += 1;
+                let line = if self.hist == history.len() {
+                    self.draft.clone()
+                } else {
+                    history[self.hist].chars().collect()
+                };
+                self.set_line(line);
+            },
+            Key::Ctrl('r') => {
+                self.search = Some(Search { query: String::new(), found: None });
+            },
+            Key::Tab => return self.complete(words),
+            _ => (),
+        }
+        Action::Continue
+    }
+
+    // handle a key during a search. Returns None when the key ends the
+    // search and should be handled as usual, keeping the match.
+    fn handle_search(&mut self, key: &Key, history: &[String]) -> Option<Action> {
+        let search = self.search.as_mut().unwrap();
+        // search from the current match, inclusive, so that a longer query
+        // can keep matching it.
+        let from = search.found.map_or(history.len(), |i| i + 1);
+        match key {
+            Key::Char(c) => {
+                search.query.push(*c);
+                search.found = find(history, &search.query, from);
+            },
+            Key::Backspace => {
+                search.query.pop();
+                search.found = find(history, &search.query, history.len());
+            },
+            Key::Ctrl('r') => {
+                let older = search.found.unwrap_or(history.len());
+                if let Some(i) = find(history, &search.query, older) {
+                    search.found = Some(i);
+                }
+            },
+            Key::Ctrl('g') | Key::Ctrl('c') => {
+                self.search = None;
+                return Some(Action::Continue);
+            },
+            Key::Enter => {
+                self.search = None;
+                return Some(Action::Submit);
+            },
+            _ => return None,
+        }
+
+        if let Some(i) = self.search.as_ref().unwrap().found {
+            self.hist = i;
+            self.set_line(history[i].chars().collect());
+        }
+        Some(Action::Continue)
+    }
+
+    // complete the word before the cursor as far as all candidates agree,
+    // or list them if that adds nothing.
+    fn complete(&mut self, words: &[String]) -> Action {
+        let start = self.word_start(|c| c.is_alphanumeric() || c == '_');
+        if start == self.pos {
+            return Action::Continue;
+        }
+        let prefix: String = self.buf[start..self.pos].iter().collect();
+
+        let mut matches: Vec<String> = words.iter()
+            .filter(|w| w.starts_with(&prefix))
+            .cloned()
+            .collect();
+        matches.sort();
+        matches.dedup();
+        if matches.is_empty() {
+            return Action::Continue;
+        }
+
+        let common = common_prefix(&matches);
+        if common.len() > prefix.len() {
+            for c in common[prefix.len()..].chars() {
+                self.buf.insert(self.pos, c);
+                self.pos += 1;
+            }
+            return Action::Continue;
+        }
+        if matches.len() == 1 {
+            return Action::Continue;
+        }
+        Action::List(matches)
+    }
+
+    // where the run of chars matching f that ends at the cursor starts.
+    fn word_start<F: Fn(char) -> bool>(&self, f: F) -> usize {
+        let mut i = self.pos;
+        while i > 0 && f(self.buf[i - 1]) {
+            i -= 1;
+        }
+        i
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.buf = line;
+        self.pos = self.buf.len();
+    }
+}
+
+// the newest history entry before index from that contains query.
+fn find(history: &[String], query: &str, from: usize) -> Option<usize> {
+    return (0..from.min(history.len())).rev().find(|&i| history[i].contains(query));
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = words[0].clone();
+    for w in words[1..].iter() {
+        while !w.starts_with(&prefix) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+// the termios layout and constants below are those of the Linux
+// architectures listed; powerpc, mips, sparc and alpha differ.
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+    ),
+))]
+mod term {
+    use std::io;
+    use std::os::raw::{
+        c_int,
+        c_uchar,
+        c_uint,
+        c_void,
+    };
+
+    // struct termios as laid out by glibc and musl on these architectures.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Termios {
+        c_iflag: c_uint,
+        c_oflag: c_uint,
+        c_cflag: c_uint,
+        c_lflag: c_uint,
+        c_line: c_uchar,
+        c_cc: [c_uchar; 32],
+        c_ispeed: c_uint,
+        c_ospeed: c_uint,
+    }
+
+    extern "C" {
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, action: c_int, termios: *const Termios) -> c_int;
+        fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+    }
+
+    const STDIN: c_int = 0;
+    const TCSANOW: c_int = 0;
+    const VTIME: usize = 5;
+    const VMIN: usize = 6;
+    const BRKINT: c_uint = 0o2;
+    const INPCK: c_uint = 0o20;
+    const ISTRIP: c_uint = 0o40;
+    const ICRNL: c_uint = 0o400;
+    const IXON: c_uint = 0o2000;
+    const OPOST: c_uint = 0o1;
+    const CS8: c_uint = 0o60;
+    const ISIG: c_uint = 0o1;
+    const ICANON: c_uint = 0o2;
+    const ECHO: c_uint = 0o10;
+    const IEXTEN: c_uint = 0o100000;
+
+    // the terminal stays in raw mode until this is dropped.
+    pub struct RawMode {
+        orig: Termios,
+    }
+
+    // None if standard input is not a terminal.
+    pub fn enable_raw_mode() -> Option<RawMode> {
+        let mut t = Termios {
+            c_iflag: 0,
+            c_oflag: 0,
+            c_cflag: 0,
+            c_lflag: 0,
+            c_line: 0,
+            c_cc: [0; 32],
+            c_ispeed: 0,
+            c_ospeed: 0,
+        };
+        if unsafe { tcgetattr(STDIN, &mut t) } != 0 {
+            return None;
+        }
+        let orig = t;
+
+        t.c_iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
+        t.c_oflag &= !OPOST;
+        t.c_cflag |= CS8;
+        t.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
+        t.c_cc[VMIN] = 1;
+        t.c_cc[VTIME] = 0;
+        if unsafe { tcsetattr(STDIN, TCSANOW, &t) } != 0 {
+            return None;
+        }
+        return Some(RawMode { orig: orig });
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe {
+                tcsetattr(STDIN, TCSANOW, &self.orig);
+            }
+        }
+    }
+
+    // the bytes of the next key presses. Empty at the end of input.
+    pub fn read_bytes() -> io::Result<Vec<u8>> {
+        let mut buf = [0u8; 256];
+        loop {
+            let n = unsafe { read(STDIN, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+            if n >= 0 {
+                return Ok(buf[..n as usize].to_vec());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+    ),
+)))]
+mod term {
+    use std::io;
+
+    pub struct RawMode {}
+
+    // raw mode is only supported on the Linux architectures above; elsewhere
+    // lines are read as is.
+    pub fn enable_raw_mode() -> Option<RawMode> {
+        return None;
+    }
+
+    pub fn read_bytes() -> io::Result<Vec<u8>> {
+        return Ok(vec![]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_key_bytes() {
+        let keys = parse_keys(b"a\x1b[D\x1b[3~\x01\x7f\r\t\x1bx\xc3\xa9\x1b[1;5C\x1b[A");
+        assert_eq!(keys, vec![
+            Key::Char('a'),
+            Key::Left,
+            Key::Delete,
+            Key::Ctrl('a'),
+            Key::Backspace,
+            Key::Enter,
+            Key::Tab,
+            Key::Esc,
+            Key::Char('x'),
+            Key::Char('é'),
+            Key::Unknown,
+            Key::Up,
+        ]);
+        assert_eq!(parse_keys(b"\x1b"), vec![Key::Esc]);
+    }
+
+    #[test]
+    fn edit_line() {
+        struct Test<'a> {
+            input: &'a [u8],
+            expected: &'a str,
+            pos: usize,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test { input: b"let x", expected: "let x", pos: 5 },
+            Test { input: b"lt\x1b[De", expected: "let", pos: 2 },
+            Test { input: b"abc\x01\x1b[3~x\x05y", expected: "xbcy", pos: 4 },
+            Test { input: b"abc\x7f\x7f", expected: "a", pos: 1 },
+            Test { input: b"abcd\x02\x02\x0b", expected: "ab", pos: 2 },
+            Test { input: b"abcd\x02\x15", expected: "d", pos: 0 },
+            Test { input: b"let foo  \x17", expected: "let ", pos: 4 },
+            Test { input: b"let foo\x17", expected: "let ", pos: 4 },
+        ];
+
+        for test in tests.iter() {
+            let mut state = new_state(0);
+            for key in parse_keys(test.input) {
+                assert_eq!(state.handle(key, &[], &[]), Action::Continue);
+            }
+            assert_eq!(state.buf.iter().collect::<String>(), test.expected);
+            assert_eq!(state.pos, test.pos);
+        }
+    }
+
+    #[test]
+    fn control_keys() {
+        let mut state = new_state(0);
+        assert_eq!(state.handle(Key::Enter, &[], &[]), Action::Submit);
+        assert_eq!(state.handle(Key::Ctrl('c'), &[], &[]), Action::Cancel);
+        assert_eq!(state.handle(Key::Ctrl('d'), &[], &[]), Action::Eof);
+        state.handle(Key::Char('x'), &[], &[]);
+        state.handle(Key::Home, &[], &[]);
+        assert_eq!(state.handle(Key::Ctrl('d'), &[], &[]), Action::Continue);
+        assert!(state.buf.is_empty());
+    }
+
+    #[test]
+    fn browse_history() {
+        let history: Vec<String> = vec![String::from("one"), String::from("two")];
+        let mut state = new_state(history.len());
+        state.handle(Key::Char('x'), &history, &[]);
+
+        state.handle(Key::Up, &history, &[]);
+        assert_eq!(state.buf.iter().collect::<String>(), "two");
+        state.handle(Key::Up, &history, &[]);
+        state.handle(Key::Up, &history, &[]);
+        assert_eq!(state.buf.iter().collect::<String>(), "one");
+        state.handle(Key::Down, &history, &[]);
+        state.handle(Key::Down, &history, &[]);
+        assert_eq!(state.buf.iter().collect::<String>(), "x");
+    }
+
+    #[test]
+    fn search_history() {
+        let history: Vec<String> = vec![
+            String::from("let add = fn(a, b) { a + b };"),
+            String::from("add(1, 2)"),
+            String::from("let x = 5;"),
+        ];
+        let mut state = new_state(history.len());
+        for key in parse_keys(b"\x12add") {
+            state.handle(key, &history, &[]);
+        }
+        assert_eq!(state.buf.iter().collect::<String>(), "add(1, 2)");
+
+        state.handle(Key::Ctrl('r'), &history, &[]);
+        assert_eq!(state.buf.iter().collect::<String>(), "let add = fn(a, b) { a + b };");
+
+        // a key that is not part of the search keeps the match for editing.
+        state.handle(Key::Home, &history, &[]);
+        assert!(state.search.is_none());
+        assert_eq!(state.pos, 0);
+
+        let mut state = new_state(history.len());
+        for key in parse_keys(b"\x12zzz") {
+            state.handle(key, &history, &[]);
+        }
+        assert!(state.buf.is_empty());
+        assert_eq!(state.handle(Key::Ctrl('g'), &history, &[]), Action::Continue);
+        assert!(state.search.is_none());
+    }
+
+    #[test]
+    fn complete_words() {
+        let words: Vec<String> = vec!["let", "len", "length", "fn", "false"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let mut state = new_state(0);
+        for key in parse_keys(b"1 + le") {
+            state.handle(key, &[], &words);
+        }
+        assert_eq!(
+            state.handle(Key::Tab, &[], &words),
+            Action::List(vec![String::from("len"), String::from("length"), String::from("let")])
+        );
+
+        state.handle(Key::Char('n'), &[], &words);
+        state.handle(Key::Char('g'), &[], &words);
+        assert_eq!(state.handle(Key::Tab, &[], &words), Action::Continue);
+        assert_eq!(state.buf.iter().collect::<String>(), "1 + length");
+
+        let mut state = new_state(0);
+        for key in parse_keys(b"f(x)\x02\x02\x02") {
+            state.handle(key, &[], &words);
+        }
+        state.handle(Key::Tab, &[], &words);
+        assert_eq!(state.buf.iter().collect::<String>(), "f(x)");
+    }
+}
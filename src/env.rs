@@ -1,23 +1,97 @@
-use std::collections::HashMap;
-use crate::object::Object;
+use std::cell::RefCell;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::rc::Rc;
+use crate::object::{
+    Object,
+    Func,
+    ErrorKind,
+};
 
-#[derive(Clone)]
 pub struct Env {
     pub idents: HashMap<String, Object>,
+    // the names bound with `const`.
+    pub consts: HashSet<String>,
+    pub outer: Option<Rc<RefCell<Env>>>,
 }
 
-pub fn new() -> Env {
-    return Env {
+pub fn new() -> Rc<RefCell<Env>> {
+    return Rc::new(RefCell::new(Env {
         idents: HashMap::new(),
-    };
+        consts: HashSet::new(),
+        outer: None,
+    }));
+}
+
+// create a new scope whose unresolved names are looked up in `outer`.
+pub fn new_enclosed(outer: &Rc<RefCell<Env>>) -> Rc<RefCell<Env>> {
+    return Rc::new(RefCell::new(Env {
+        idents: HashMap::new(),
+        consts: HashSet::new(),
+        outer: Some(Rc::clone(outer)),
+    }));
 }
 
 impl Env {
-    pub fn get(&self, name: String) -> Option<&Object> {
-        return self.idents.get(&name);
+    pub fn get(&self, name: &str) -> Option<Object> {
+        return match self.idents.get(name) {
+            Some(Object::Func(f)) if f.env.is_none() => Some(Object::Func(Func {
+                def: Rc::clone(&f.def),
+                env: Some(f.captured()),
+            })),
+            Some(obj) => Some(obj.clone()),
+            None => match &self.outer {
+                Some(outer) => outer.borrow().get(name),
+                None => None,
+            },
+        };
     }
 
     pub fn set(&mut self, name: String, obj: Object) {
+        self.consts.remove(&name);
+        self.insert(name, obj);
+    }
+
+    // bind name in this scope as `let`, or `const` if constant, does. A
+    // name bound with `const` cannot be bound again in the same scope.
+    pub fn bind(&mut self, name: String, obj: Object, constant: bool) -> Result<(), ErrorKind> {
+        if self.consts.contains(&name) {
+            return Err(ErrorKind::ConstAssign);
+        }
+        if constant {
+            self.consts.insert(name.clone());
+        }
+        self.insert(name, obj);
+        Ok(())
+    }
+
+    // update the nearest binding of name. Unlike set, this never creates a
+    // binding.
+    pub fn assign(&mut self, name: &str, obj: Object) -> Result<(), ErrorKind> {
+        if self.idents.contains_key(name) {
+            if self.consts.contains(name) {
+                return Err(ErrorKind::ConstAssign);
+            }
+            self.insert(String::from(name), obj);
+            return Ok(());
+        }
+        return match &self.outer {
+            Some(outer) => outer.borrow_mut().assign(name, obj),
+            None => Err(ErrorKind::UnknownIdent),
+        };
+    }
+
+    // a function defined in this scope is stored here without holding the
+    // scope, so the two do not keep each other alive.
+    fn insert(&mut self, name: String, obj: Object) {
+        let obj = match obj {
+            Object::Func(f) if f.env.as_ref().is_some_and(|env| std::ptr::eq(env.as_ptr(), self)) => {
+                Object::Func(Func { def: f.def, env: None })
+            },
+            obj => obj,
+        };
         self.idents.insert(name, obj);
     }
 }
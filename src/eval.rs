@@ -1,130 +1,998 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::vec;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
 use crate::object::{
     Object,
     Int,
     Bool,
     Null,
     Return,
+    Break,
+    Continue,
+    Str,
     Func,
+    FuncDef,
+    Array,
+    Hash,
+    HashPair,
+    HashKey,
+    Range,
+    ErrorKind,
+    TraceFrame,
+    new_error,
 };
 use crate::ast;
+use crate::builtins;
+use crate::env;
 use crate::env::Env;
+use crate::token::Span;
+
+// Evaluator walks the AST. It owns the state that outlives a single node,
+// such as the table of builtin functions.
+pub struct Evaluator {
+    builtins: builtins::Registry,
+    typing: Typing,
+    // set from elsewhere, possibly a signal handler, to stop the program.
+    interrupt: Arc<AtomicBool>,
+    // the calls to Monkey functions in progress, outermost first.
+    calls: Vec<TraceFrame>,
+    max_depth: usize,
+    // what is left to do of the nodes being evaluated, innermost last.
+    conts: Vec<Cont>,
+    budget: Budget,
+}
 
-pub fn eval(node: ast::Node, env: &mut Env) -> Object {
-    return match node {
-        ast::Node::Program(p) => eval_program(&p, env),
-        ast::Node::Stmt(s) => eval_stmt(&s, env),
-        ast::Node::Expr(e) => eval_expr(&e, env),
+// how many calls may be in progress at once before a program fails with a
+// StackOverflow error.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+pub fn new() -> Evaluator {
+    return Evaluator {
+        builtins: builtins::defaults(),
+        typing: Typing::Strict,
+        interrupt: Arc::new(AtomicBool::new(false)),
+        calls: vec![],
+        max_depth: DEFAULT_MAX_DEPTH,
+        conts: vec![],
+        budget: budget(Limits::default()),
     };
 }
 
-pub fn eval_program(p: &ast::Program, env: &mut Env) -> Object {
-    let stmts = &p.stmts;
-    let mut result = Object::Null(Null {});
+// caps on what a single run may use, for programs that are not trusted.
+// Going past one stops the program with a ResourceLimit error. None is no
+// cap.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Limits {
+    // expressions evaluated by the evaluator, or instructions run by the
+    // vm.
+    pub steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    // the most elements an array or hash, or bytes a string, may have,
+    // counting those of the arrays, hashes and strings within it too.
+    pub max_len: Option<usize>,
+}
 
-    for stmt in stmts.iter() {
-        result = eval_stmt(stmt, env);
-        match &result {
-            Object::Return(r) => return *(r.clone().val),
-            _ => (),
-        };
-    }
+// what a run has used so far of its Limits.
+pub struct Budget {
+    limits: Limits,
+    steps: u64,
+    deadline: Option<Instant>,
+}
 
-    result
+pub fn budget(limits: Limits) -> Budget {
+    return Budget { limits: limits, steps: 0, deadline: None };
 }
 
-pub fn eval_block(b: &ast::Block, env: &mut Env) -> Object {
-    let stmts = &b.stmts;
-    let mut result = Object::Null(Null {});
+impl Budget {
+    // begin a run with all of the budget left.
+    pub fn start(&mut self) {
+        self.steps = 0;
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+    }
 
-    for stmt in stmts.iter() {
-        result = eval_stmt(stmt, env);
-        match &result {
-            Object::Return(r) => return Object::Return(r.clone()),
-            _ => (),
+    // count a step, failing once the steps or the time have run out. The
+    // clock costs more to read than a step, so it is read every so often.
+    pub fn step(&mut self) -> Result<(), Object> {
+        self.steps += 1;
+        if let Some(max) = self.limits.steps {
+            if self.steps > max {
+                return Err(limit_exceeded(format!("more than {} steps", max)));
+            }
+        }
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.limits.timeout) {
+            if self.steps % 1024 == 0 && Instant::now() >= deadline {
+                return Err(limit_exceeded(format!("ran longer than {:?}", timeout)));
+            }
+        }
+        Ok(())
+    }
+
+    // fail if obj is larger than allowed. Elements are counted with what
+    // they hold, so nesting arrays cannot hide their size.
+    pub fn check_len(&self, obj: &Object) -> Result<(), Object> {
+        let max = match self.limits.max_len {
+            Some(max) => max,
+            None => return Ok(()),
         };
+        if size(obj, max) > max {
+            return Err(limit_exceeded(format!("{} larger than {}", obj.type_name(), max)));
+        }
+        Ok(())
+    }
+}
+
+// the bytes of the strings in obj plus the elements of its collections, at
+// every depth. Counting stops once it passes max.
+fn size(obj: &Object, max: usize) -> usize {
+    let mut size = 0;
+    let mut objs = vec![obj];
+    while let Some(obj) = objs.pop() {
+        match obj {
+            Object::Array(a) => {
+                size += a.elems.len();
+                if size <= max {
+                    objs.extend(a.elems.iter());
+                }
+            },
+            Object::Hash(h) => {
+                size += h.pairs.len();
+                if size <= max {
+                    for pair in h.pairs.values() {
+                        objs.push(&pair.key);
+                        objs.push(&pair.val);
+                    }
+                }
+            },
+            Object::Str(s) => size += s.val.len(),
+            _ => (),
+        }
+        if size > max {
+            break;
+        }
     }
+    return size;
+}
 
-    result
+fn limit_exceeded(msg: String) -> Object {
+    return new_error(ErrorKind::ResourceLimit, msg, None);
 }
 
-pub fn eval_stmt(stmt: &ast::Stmt, env: &mut Env) -> Object {
-    return match stmt {
-        ast::Stmt::ExprStmt(es) => eval_expr(&es.expr, env),
-        ast::Stmt::Block(b) => eval_block(&b, env),
-        ast::Stmt::Return(r) => {
-            let ret = eval_expr(&r.val, env);
-            Object::Return(Return { val: Box::new(ret) })
-        },
-        ast::Stmt::Let(l) => {
-            let val = eval_expr(&l.val, env);
-            env.set(l.name.val.clone(), val.clone());
-            val
-        },
-    };
+// how operators treat booleans.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Typing {
+    // booleans are only booleans: `true + 1` is a type mismatch.
+    Strict,
+    // booleans are also the integers 0 and 1, as in earlier versions:
+    // `true + 1` is 2.
+    Legacy,
 }
 
-pub fn eval_expr(expr: &ast::Expr, env: &mut Env) -> Object {
-    return match expr {
-        ast::Expr::Int(n) => Object::Int(Int { val: n.val }),
-        ast::Expr::Bool(b) => Object::Bool(Bool { val: b.val }),
-        ast::Expr::Prefix(p) => eval_prefix_expr(&p, env),
-        ast::Expr::Infix(i) => eval_infix_expr(&i, env),
-        ast::Expr::If(i) => eval_if_expr(&i, env),
-        ast::Expr::Func(f) => eval_func(f.clone(), env),
-        ast::Expr::Call(c) => eval_call(&c, env),
-        ast::Expr::Ident(i) => eval_ident(&i, env),
+impl Evaluator {
+    pub fn set_typing(&mut self, typing: Typing) {
+        self.typing = typing;
+    }
+
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    // limit each call to eval from now on.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.budget = budget(limits);
+    }
+
+    // share the flag that stops a running program when set. The program
+    // ends with an Interrupted error at its next function call or loop
+    // iteration.
+    pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = flag;
+    }
+
+    // make a Rust function callable from Monkey as `name`. An arity of None
+    // accepts any number of arguments.
+    pub fn register<F>(&mut self, name: &str, arity: Option<usize>, func: F)
+    where
+        F: Fn(Vec<Object>) -> Object + 'static,
+    {
+        self.builtins.register(name, arity, func);
+    }
+
+    pub fn eval(&mut self, node: ast::Node, env: &Rc<RefCell<Env>>) -> Object {
+        self.budget.start();
+        self.conts.clear();
+        let step = match node {
+            ast::Node::Program(p) => self.next_program_stmt(Rc::new(p.stmts), 0, env),
+            ast::Node::Stmt(s) => self.start_stmt(&s, env, false),
+            ast::Node::Expr(e) => Step::Expr(e, Rc::clone(env)),
+        };
+        return self.run(step);
+    }
+
+    // take steps until a value is left with nothing more to do with it.
+    fn run(&mut self, step: Step) -> Object {
+        let mut step = step;
+        loop {
+            step = match step {
+                Step::Expr(expr, env) => self.start_expr(&expr, &env),
+                Step::Value(val) => match self.conts.pop() {
+                    Some(cont) => self.resume(cont, val),
+                    None => return val,
+                },
+            };
+        }
+    }
+
+    // carry on with cont now that val is known.
+    fn resume(&mut self, cont: Cont, val: Object) -> Step {
+        return match cont {
+            Cont::Program { stmts, next, env } => match val {
+                Object::Return(r) => Step::Value(*r.val),
+                Object::Error(_) => Step::Value(val),
+                _ if next == stmts.len() => Step::Value(val),
+                _ => self.next_program_stmt(stmts, next, &env),
+            },
+            Cont::Block { stmts, next, env, tail } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                self.next_stmt(stmts, next, &env, tail)
+            },
+            Cont::Let(l, env) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                Step::Value(match env.borrow_mut().bind(l.name.val.clone(), val.clone(), l.constant) {
+                    Ok(()) => val,
+                    Err(kind) => new_error(kind, l.name.val.clone(), Some(l.name.span)),
+                })
+            },
+            Cont::Return => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                Step::Value(Object::Return(Return { val: Box::new(val) }))
+            },
+            Cont::WhileCond(w, env) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                if !is_truthy(&val) {
+                    return Step::Value(Object::Null(Null {}));
+                }
+                let body = Rc::clone(&w.body.stmts);
+                self.conts.push(Cont::WhileBody(w, Rc::clone(&env)));
+                self.next_stmt(body, 0, &env, false)
+            },
+            Cont::WhileBody(w, env) => match val {
+                Object::Break(_) => Step::Value(Object::Null(Null {})),
+                Object::Return(_) | Object::Error(_) => Step::Value(val),
+                _ => self.start_while(w, env),
+            },
+            Cont::ForIter(f, env) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                match iterable(val) {
+                    Ok(iter) => self.next_item(f, env, iter, 0),
+                    Err(e) => Step::Value(with_span(e, f.iter.span())),
+                }
+            },
+            Cont::ForBody { f, env, iter, cursor } => match val {
+                Object::Break(_) => Step::Value(Object::Null(Null {})),
+                Object::Return(_) | Object::Error(_) => Step::Value(val),
+                _ => self.next_item(f, env, iter, cursor),
+            },
+            Cont::Prefix(p) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                Step::Value(with_span(eval_prefix(&p.op, &val, self.typing), p.span))
+            },
+            Cont::InfixLhs { chain, n, env } => self.next_infix(chain, n, env, val),
+            Cont::InfixRhs { chain, n, env, lhs } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let i = &chain[n];
+                let lhs = self.limit_len(with_span(eval_infix(&i.op, &lhs, &val, self.typing), i.span), i.span);
+                self.next_infix(chain, n + 1, env, lhs)
+            },
+            Cont::Assign { a, env, cur } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let mut val = val;
+                if let Some(cur) = cur {
+                    let op = a.op.trim_end_matches('=');
+                    val = self.limit_len(with_span(eval_infix(op, &cur, &val, self.typing), a.span), a.span);
+                    if val.is_error() {
+                        return Step::Value(val);
+                    }
+                }
+                Step::Value(match env.borrow_mut().assign(&a.name.val, val.clone()) {
+                    Ok(()) => val,
+                    Err(kind) => new_error(kind, a.name.val.clone(), Some(a.name.span)),
+                })
+            },
+            Cont::Array { a, env, elems } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let mut elems = elems;
+                elems.push(val);
+                self.next_elem(a, env, elems)
+            },
+            Cont::HashKey { h, env, pairs, n } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let (key_expr, val_expr) = &h.pairs[n];
+                let hash_key = match val.hash_key() {
+                    Some(k) => k,
+                    None => return Step::Value(new_error(
+                        ErrorKind::Unhashable,
+                        val.type_name().to_string(),
+                        Some(key_expr.span()),
+                    )),
+                };
+                let val_expr = val_expr.clone();
+                self.conts.push(Cont::HashVal { h, env: Rc::clone(&env), pairs, n, key: val, hash_key });
+                Step::Expr(val_expr, env)
+            },
+            Cont::HashVal { h, env, pairs, n, key, hash_key } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let mut pairs = pairs;
+                pairs.insert(hash_key, HashPair { key: key, val: val });
+                self.next_pair(h, env, pairs, n + 1)
+            },
+            Cont::IndexLhs(i, env) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let index = (*i.index).clone();
+                self.conts.push(Cont::IndexIndex(i, val));
+                Step::Expr(index, env)
+            },
+            Cont::IndexIndex(i, lhs) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                Step::Value(with_span(eval_index(&lhs, &val), i.span))
+            },
+            Cont::RangeStart(r, env) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let end = (*r.end).clone();
+                self.conts.push(Cont::RangeEnd(r, val));
+                Step::Expr(end, env)
+            },
+            Cont::RangeEnd(r, start) => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                Step::Value(with_span(eval_range(&start, &val), r.span))
+            },
+            Cont::If { i, env, branch, tail } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                if !is_truthy(&val) {
+                    return self.next_branch(i, env, branch + 1, tail);
+                }
+                let cons = match i.branch(branch) {
+                    Some((_, cons)) => Rc::clone(&cons.stmts),
+                    None => unreachable!(),
+                };
+                self.next_stmt(cons, 0, &env, tail)
+            },
+            Cont::Callee { c, env, tail } => match val {
+                Object::Func(_) | Object::Builtin(_) => self.next_arg(c, env, tail, val, vec![]),
+                obj if obj.is_abrupt() => Step::Value(obj),
+                obj => Step::Value(new_error(
+                    ErrorKind::NotCallable,
+                    obj.type_name().to_string(),
+                    Some(c.func.span()),
+                )),
+            },
+            Cont::Args { c, env, tail, func, args } => {
+                if val.is_abrupt() {
+                    return Step::Value(val);
+                }
+                let mut args = args;
+                args.push(val);
+                self.next_arg(c, env, tail, func, args)
+            },
+            Cont::Param { f, env, args, n } => match val {
+                Object::Return(r) => Step::Value(*r.val),
+                obj if obj.is_abrupt() => Step::Value(obj),
+                obj => {
+                    env.borrow_mut().set(f.def.params[n].name.val.clone(), obj);
+                    self.next_param(f, env, args, n + 1)
+                },
+            },
+            Cont::Call(span) => {
+                let res = match val {
+                    Object::Return(r) => *r.val,
+                    obj => with_span(obj, span),
+                };
+                // the innermost call an error passes through knows the whole
+                // stack.
+                let res = match res {
+                    Object::Error(mut e) => {
+                        if e.trace.is_empty() {
+                            e.trace = self.calls.iter().rev().cloned().collect();
+                        }
+                        Object::Error(e)
+                    },
+                    obj => obj,
+                };
+                self.calls.pop();
+                Step::Value(res)
+            },
+        };
+    }
+
+    // a `return` at the top level ends the program, and so does an error.
+    fn next_program_stmt(
+        &mut self, stmts: Rc<Vec<ast::Stmt>>, n: usize, env: &Rc<RefCell<Env>>
+    ) -> Step {
+        if n == stmts.len() {
+            return Step::Value(Object::Null(Null {}));
+        }
+        self.conts.push(Cont::Program { stmts: Rc::clone(&stmts), next: n + 1, env: Rc::clone(env) });
+        return self.start_stmt(&stmts[n], env, false);
+    }
+
+    // run the statements of a block from the nth on. In tail position, the
+    // last statement and any `return` are too, so a call there can take the
+    // place of the running one.
+    fn next_stmt(
+        &mut self, stmts: Rc<Vec<ast::Stmt>>, n: usize, env: &Rc<RefCell<Env>>, tail: bool
+    ) -> Step {
+        if n == stmts.len() {
+            return Step::Value(Object::Null(Null {}));
+        }
+        let last = n + 1 == stmts.len();
+        if !last {
+            self.conts.push(Cont::Block { stmts: Rc::clone(&stmts), next: n + 1, env: Rc::clone(env), tail: tail });
+        }
+        let tail = tail && (last || matches!(stmts[n], ast::Stmt::Return(_)));
+        return self.start_stmt(&stmts[n], env, tail);
+    }
+
+    fn start_stmt(
+        &mut self, stmt: &ast::Stmt, env: &Rc<RefCell<Env>>, tail: bool
+    ) -> Step {
+        return match stmt {
+            ast::Stmt::ExprStmt(es) if tail => self.start_tail_expr(&es.expr, env),
+            ast::Stmt::ExprStmt(es) => Step::Expr(es.expr.clone(), Rc::clone(env)),
+            ast::Stmt::Block(b) => self.next_stmt(Rc::clone(&b.stmts), 0, env, tail),
+            ast::Stmt::While(w) => self.start_while(Rc::clone(w), Rc::clone(env)),
+            ast::Stmt::For(f) => {
+                self.conts.push(Cont::ForIter(Rc::clone(f), Rc::clone(env)));
+                Step::Expr(f.iter.clone(), Rc::clone(env))
+            },
+            ast::Stmt::Break(_) => Step::Value(Object::Break(Break {})),
+            ast::Stmt::Continue(_) => Step::Value(Object::Continue(Continue {})),
+            ast::Stmt::Return(r) => {
+                self.conts.push(Cont::Return);
+                if tail {
+                    return self.start_tail_expr(&r.val, env);
+                }
+                Step::Expr(r.val.clone(), Rc::clone(env))
+            },
+            ast::Stmt::Let(l) => {
+                self.conts.push(Cont::Let(Rc::clone(l), Rc::clone(env)));
+                Step::Expr(l.val.clone(), Rc::clone(env))
+            },
+        };
+    }
+
+    fn start_while(&mut self, w: Rc<ast::While>, env: Rc<RefCell<Env>>) -> Step {
+        if self.interrupt.load(Ordering::Relaxed) {
+            return Step::Value(interrupted(w.span));
+        }
+        let cond = w.cond.clone();
+        self.conts.push(Cont::WhileCond(w, Rc::clone(&env)));
+        return Step::Expr(cond, env);
+    }
+
+    // the loop variable is bound like a `let`, so it keeps its last value
+    // once the loop is done.
+    fn next_item(
+        &mut self, f: Rc<ast::For>, env: Rc<RefCell<Env>>, iter: Object, cursor: usize
+    ) -> Step {
+        let (item, next) = match next_item(&iter, cursor) {
+            Some(next) => next,
+            None => return Step::Value(Object::Null(Null {})),
+        };
+        if self.interrupt.load(Ordering::Relaxed) {
+            return Step::Value(interrupted(f.span));
+        }
+        if let Err(kind) = env.borrow_mut().bind(f.var.val.clone(), item, false) {
+            return Step::Value(new_error(kind, f.var.val.clone(), Some(f.var.span)));
+        }
+        let body = Rc::clone(&f.body.stmts);
+        self.conts.push(Cont::ForBody { f: f, env: Rc::clone(&env), iter: iter, cursor: next });
+        return self.next_stmt(body, 0, &env, false);
+    }
+
+    fn start_expr(
+        &mut self, expr: &ast::Expr, env: &Rc<RefCell<Env>>
+    ) -> Step {
+        if let Err(e) = self.budget.step() {
+            return Step::Value(with_span(e, expr.span()));
+        }
+        return match expr {
+            ast::Expr::Int(n) => Step::Value(Object::Int(Int { val: n.val })),
+            ast::Expr::Bool(b) => Step::Value(Object::Bool(Bool { val: b.val })),
+            ast::Expr::Str(s) => Step::Value(self.limit_len(Object::Str(Str { val: s.val.clone() }), s.span)),
+            ast::Expr::Prefix(p) => {
+                self.conts.push(Cont::Prefix(Rc::clone(p)));
+                Step::Expr((*p.rhs).clone(), Rc::clone(env))
+            },
+            ast::Expr::Infix(i) => {
+                let (first, chain) = infix_chain(i);
+                self.conts.push(Cont::InfixLhs { chain: chain, n: 0, env: Rc::clone(env) });
+                Step::Expr(first, Rc::clone(env))
+            },
+            ast::Expr::If(i) => self.next_branch(Rc::clone(i), Rc::clone(env), 0, false),
+            ast::Expr::Func(f) => Step::Value(self.eval_func(f, env)),
+            ast::Expr::Call(c) => self.start_call(c, env, false),
+            ast::Expr::Ident(i) => Step::Value(self.eval_ident(i, env)),
+            ast::Expr::Array(a) => self.next_elem(Rc::clone(a), Rc::clone(env), vec![]),
+            ast::Expr::Index(i) => {
+                self.conts.push(Cont::IndexLhs(Rc::clone(i), Rc::clone(env)));
+                Step::Expr((*i.lhs).clone(), Rc::clone(env))
+            },
+            ast::Expr::Hash(h) => self.next_pair(Rc::clone(h), Rc::clone(env), BTreeMap::new(), 0),
+            ast::Expr::Assign(a) => self.start_assign(a, env),
+            ast::Expr::Range(r) => {
+                self.conts.push(Cont::RangeStart(Rc::clone(r), Rc::clone(env)));
+                Step::Expr((*r.start).clone(), Rc::clone(env))
+            },
+        };
+    }
+
+    // a call or `if` whose value a function returns is in tail position.
+    fn start_tail_expr(&mut self, expr: &ast::Expr, env: &Rc<RefCell<Env>>) -> Step {
+        return match expr {
+            ast::Expr::Call(c) => self.start_call(c, env, true),
+            ast::Expr::If(i) => self.next_branch(Rc::clone(i), Rc::clone(env), 0, true),
+            _ => Step::Expr(expr.clone(), Rc::clone(env)),
+        };
+    }
+
+    // collections and strings are only checked against the size limit
+    // where they are made, as that is the only way they grow.
+    fn limit_len(&self, obj: Object, span: Span) -> Object {
+        return match self.budget.check_len(&obj) {
+            Ok(()) => obj,
+            Err(e) => with_span(e, span),
+        };
+    }
+
+    // a chain of operators is evaluated from the left, however long it is.
+    // Each operator after the first is counted as a step, as if it had been
+    // evaluated on its own.
+    fn next_infix(
+        &mut self, chain: Vec<Rc<ast::Infix>>, n: usize, env: Rc<RefCell<Env>>, lhs: Object
+    ) -> Step {
+        let mut n = n;
+        loop {
+            if n == chain.len() || lhs.is_abrupt() {
+                return Step::Value(lhs);
+            }
+            let i = Rc::clone(&chain[n]);
+            if n > 0 {
+                if let Err(e) = self.budget.step() {
+                    return Step::Value(with_span(e, i.span));
+                }
+            }
+            // `&&` and `||` only evaluate rhs when lhs does not decide the
+            // result, and give the operand that did.
+            let logical = i.op == "&&" || i.op == "||";
+            if logical && is_truthy(&lhs) == (i.op == "||") {
+                n += 1;
+                continue;
+            }
+            let rhs = (*i.rhs).clone();
+            if logical {
+                self.conts.push(Cont::InfixLhs { chain: chain, n: n + 1, env: Rc::clone(&env) });
+            } else {
+                self.conts.push(Cont::InfixRhs { chain: chain, n: n, env: Rc::clone(&env), lhs: lhs });
+            }
+            return Step::Expr(rhs, env);
+        }
+    }
+
+    // a compound assignment reads the name before evaluating the value.
+    fn start_assign(
+        &mut self, a: &Rc<ast::Assign>, env: &Rc<RefCell<Env>>
+    ) -> Step {
+        let cur = match a.op.as_str() {
+            "=" => None,
+            _ => {
+                let cur = self.eval_ident(&a.name, env);
+                if cur.is_error() {
+                    return Step::Value(cur);
+                }
+                Some(cur)
+            },
+        };
+        self.conts.push(Cont::Assign { a: Rc::clone(a), env: Rc::clone(env), cur: cur });
+        return Step::Expr((*a.val).clone(), Rc::clone(env));
+    }
+
+    fn eval_ident(
+        &mut self, i: &ast::Ident, env: &Rc<RefCell<Env>>
+    ) -> Object {
+        let val = env.borrow().get(&i.val);
+        if let Some(v) = val {
+            return v;
+        }
+        return match self.builtins.get(&i.val) {
+            Some(b) => b,
+            None => new_error(
+                ErrorKind::UnknownIdent,
+                i.val.clone(),
+                Some(i.span),
+            ),
+        };
+    }
+
+    // elements are evaluated from left to right, stopping at the first
+    // error.
+    fn next_elem(
+        &mut self, a: Rc<ast::Array>, env: Rc<RefCell<Env>>, elems: Vec<Object>
+    ) -> Step {
+        if elems.len() == a.elems.len() {
+            return Step::Value(self.limit_len(Object::Array(Array { elems: Rc::new(elems) }), a.span));
+        }
+        let elem = a.elems[elems.len()].clone();
+        self.conts.push(Cont::Array { a: a, env: Rc::clone(&env), elems: elems });
+        return Step::Expr(elem, env);
+    }
+
+    fn next_pair(
+        &mut self, h: Rc<ast::Hash>, env: Rc<RefCell<Env>>, pairs: BTreeMap<HashKey, HashPair>, n: usize
+    ) -> Step {
+        if n == h.pairs.len() {
+            return Step::Value(self.limit_len(Object::Hash(Hash { pairs: Rc::new(pairs) }), h.span));
+        }
+        let key = h.pairs[n].0.clone();
+        self.conts.push(Cont::HashKey { h: h, env: Rc::clone(&env), pairs: pairs, n: n });
+        return Step::Expr(key, env);
+    }
+
+    // try the condition of the nth branch of i, or take the `else` once
+    // there are no more.
+    fn next_branch(
+        &mut self, i: Rc<ast::If>, env: Rc<RefCell<Env>>, n: usize, tail: bool
+    ) -> Step {
+        let cond = match i.branch(n) {
+            Some((cond, _)) => cond.clone(),
+            None => return match &i.alt {
+                Some(alt) => self.next_stmt(Rc::clone(&alt.stmts), 0, &env, tail),
+                None => Step::Value(Object::Null(Null {})),
+            },
+        };
+        self.conts.push(Cont::If { i: i, env: Rc::clone(&env), branch: n, tail: tail });
+        return Step::Expr(cond, env);
+    }
+
+    // functions capture the defining scope itself rather than a snapshot of
+    // it, so a function bound by `let` can see its own name and later
+    // bindings.
+    fn eval_func(
+        &mut self, f: &ast::Func, env: &Rc<RefCell<Env>>
+    ) -> Object {
+        let def = FuncDef {
+            name: f.name.clone(),
+            params: f.params.clone(),
+            rest: f.rest.clone(),
+            body: f.body.clone(),
+            env: Rc::downgrade(env),
+        };
+        return Object::Func(Func { def: Rc::new(def), env: Some(Rc::clone(env)) });
+    }
+
+    fn start_call(
+        &mut self, c: &Rc<ast::Call>, env: &Rc<RefCell<Env>>, tail: bool
+    ) -> Step {
+        self.conts.push(Cont::Callee { c: Rc::clone(c), env: Rc::clone(env), tail: tail });
+        return Step::Expr((*c.func).clone(), Rc::clone(env));
+    }
+
+    fn next_arg(
+        &mut self, c: Rc<ast::Call>, env: Rc<RefCell<Env>>, tail: bool, func: Object, args: Vec<Object>
+    ) -> Step {
+        if args.len() < c.args.len() {
+            let arg = c.args[args.len()].clone();
+            self.conts.push(Cont::Args { c: c, env: Rc::clone(&env), tail: tail, func: func, args: args });
+            return Step::Expr(arg, env);
+        }
+        return self.call(&c, tail, func, args);
     }
-}
 
-pub fn eval_exprs(args: &Vec<ast::Expr>, env: &mut Env) -> Vec<Object> {
-    let mut exprs: Vec<Object> = vec![];
+    // builtins are called straight away. A Monkey function gets a frame of
+    // the call stack, but a call in tail position takes the frame of the
+    // running one instead, so tail recursion runs in constant space.
+    fn call(&mut self, c: &ast::Call, tail: bool, func: Object, args: Vec<Object>) -> Step {
+        if self.interrupt.load(Ordering::Relaxed) {
+            return Step::Value(interrupted(c.span));
+        }
+        let f = match func {
+            Object::Func(f) => f,
+            Object::Builtin(b) => {
+                let res = with_span(builtins::call(&b, args), c.span);
+                return Step::Value(self.limit_len(res, c.span));
+            },
+            _ => unreachable!(),
+        };
+        if tail {
+            while !matches!(self.conts.last(), Some(Cont::Call(_)) | None) {
+                self.conts.pop();
+            }
+        }
+        if let Err(e) = check_arity(&f.def, args.len()) {
+            return Step::Value(with_span(e, c.span));
+        }
+        let frame = TraceFrame { name: f.def.name.clone(), span: c.span };
+        match self.conts.last_mut() {
+            Some(Cont::Call(span)) if tail => {
+                *span = c.span;
+                if let Some(last) = self.calls.last_mut() {
+                    *last = frame;
+                }
+            },
+            _ => {
+                if self.calls.len() >= self.max_depth {
+                    return Step::Value(stack_overflow(self.max_depth, c.span));
+                }
+                self.calls.push(frame);
+                self.conts.push(Cont::Call(c.span));
+            },
+        }
+        let call_env = env::new_enclosed(&f.captured());
+        return self.next_param(f, call_env, args.into_iter(), 0);
+    }
 
-    for arg in args.iter() {
-        let expr = eval_expr(arg, env);
-        exprs.push(expr);
+    // bind the parameters of f from the nth on, then run its body. A
+    // default is evaluated for each call, and sees the parameters before
+    // it. A `return` in one returns from the call.
+    fn next_param(
+        &mut self, f: Func, env: Rc<RefCell<Env>>, args: vec::IntoIter<Object>, n: usize
+    ) -> Step {
+        let (mut args, mut n) = (args, n);
+        while n < f.def.params.len() {
+            let val = match args.next() {
+                Some(arg) => arg,
+                None => {
+                    let default = match &f.def.params[n].default {
+                        Some(default) => default.clone(),
+                        None => unreachable!(),
+                    };
+                    self.conts.push(Cont::Param { f: f, env: Rc::clone(&env), args: args, n: n });
+                    return Step::Expr(default, env);
+                },
+            };
+            env.borrow_mut().set(f.def.params[n].name.val.clone(), val);
+            n += 1;
+        }
+        if let Some(rest) = &f.def.rest {
+            let rest_args = Object::Array(Array { elems: Rc::new(args.collect()) });
+            env.borrow_mut().set(rest.val.clone(), rest_args);
+        }
+        return self.next_stmt(Rc::clone(&f.def.body.stmts), 0, &env, true);
     }
+}
+
+// what the evaluator does next: evaluate an expression, or hand a value to
+// the innermost continuation.
+enum Step {
+    Expr(ast::Expr, Rc<RefCell<Env>>),
+    Value(Object),
+}
 
-    exprs
+// the rest of the work of a node, waiting on the value of one of its
+// children. The evaluator keeps these on a stack of its own rather than on
+// the Rust stack, so how deep a program recurses is limited only by
+// max_depth.
+enum Cont {
+    Program { stmts: Rc<Vec<ast::Stmt>>, next: usize, env: Rc<RefCell<Env>> },
+    Block { stmts: Rc<Vec<ast::Stmt>>, next: usize, env: Rc<RefCell<Env>>, tail: bool },
+    Let(Rc<ast::Let>, Rc<RefCell<Env>>),
+    Return,
+    WhileCond(Rc<ast::While>, Rc<RefCell<Env>>),
+    WhileBody(Rc<ast::While>, Rc<RefCell<Env>>),
+    ForIter(Rc<ast::For>, Rc<RefCell<Env>>),
+    ForBody { f: Rc<ast::For>, env: Rc<RefCell<Env>>, iter: Object, cursor: usize },
+    Prefix(Rc<ast::Prefix>),
+    // the value is the left operand of chain[n], or its right one.
+    InfixLhs { chain: Vec<Rc<ast::Infix>>, n: usize, env: Rc<RefCell<Env>> },
+    InfixRhs { chain: Vec<Rc<ast::Infix>>, n: usize, env: Rc<RefCell<Env>>, lhs: Object },
+    Assign { a: Rc<ast::Assign>, env: Rc<RefCell<Env>>, cur: Option<Object> },
+    Array { a: Rc<ast::Array>, env: Rc<RefCell<Env>>, elems: Vec<Object> },
+    // the value is the key of the nth pair, or its value.
+    HashKey { h: Rc<ast::Hash>, env: Rc<RefCell<Env>>, pairs: BTreeMap<HashKey, HashPair>, n: usize },
+    HashVal {
+        h: Rc<ast::Hash>,
+        env: Rc<RefCell<Env>>,
+        pairs: BTreeMap<HashKey, HashPair>,
+        n: usize,
+        key: Object,
+        hash_key: HashKey,
+    },
+    IndexLhs(Rc<ast::Index>, Rc<RefCell<Env>>),
+    IndexIndex(Rc<ast::Index>, Object),
+    RangeStart(Rc<ast::Range>, Rc<RefCell<Env>>),
+    RangeEnd(Rc<ast::Range>, Object),
+    // the value is the condition of the branch-th branch.
+    If { i: Rc<ast::If>, env: Rc<RefCell<Env>>, branch: usize, tail: bool },
+    Callee { c: Rc<ast::Call>, env: Rc<RefCell<Env>>, tail: bool },
+    Args { c: Rc<ast::Call>, env: Rc<RefCell<Env>>, tail: bool, func: Object, args: Vec<Object> },
+    // the value is the default of the nth parameter of f.
+    Param { f: Func, env: Rc<RefCell<Env>>, args: vec::IntoIter<Object>, n: usize },
+    // the end of a call to a Monkey function, made at span or, after tail
+    // calls, at the span of the last.
+    Call(Span),
+}
+
+// the first operand of the chain i ends, and the infix expressions that
+// apply the others to it in turn, i last.
+fn infix_chain(i: &Rc<ast::Infix>) -> (ast::Expr, Vec<Rc<ast::Infix>>) {
+    let mut chain = vec![Rc::clone(i)];
+    let mut first = (*i.lhs).clone();
+    while let ast::Expr::Infix(i) = &first {
+        let lhs = (*i.lhs).clone();
+        chain.push(Rc::clone(i));
+        first = lhs;
+    }
+    chain.reverse();
+    (first, chain)
 }
 
-pub fn eval_prefix_expr(p: &ast::Prefix, env: &mut Env) -> Object {
-    let rhs = eval_expr(&*p.rhs, env);
-    return match p.op.as_str() {
-        "!" => eval_prefix_bang(&rhs, env),
-        "-" => eval_prefix_minus(&rhs, env),
-        _ => Object::Null(Null {}),
+// apply a prefix operator to an already evaluated operand. Errors are
+// returned without a location; callers attach their own.
+pub fn eval_prefix(op: &str, rhs: &Object, typing: Typing) -> Object {
+    return match (op, rhs) {
+        ("-", Object::Bool(_)) | ("~", Object::Bool(_)) if typing == Typing::Strict => {
+            new_error(ErrorKind::TypeMismatch, format!("{}BOOLEAN", op), None)
+        },
+        ("!", _) => eval_prefix_bang(rhs),
+        ("-", _) => eval_prefix_minus(rhs),
+        ("~", Object::Int(i)) => Object::Int(Int { val: !i.val }),
+        ("~", _) => new_error(
+            ErrorKind::UnknownOperator,
+            format!("~{}", rhs.type_name()),
+            None,
+        ),
+        _ => new_error(
+            ErrorKind::UnknownOperator,
+            format!("{}{}", op, rhs.type_name()),
+            None,
+        ),
     };
 }
 
-pub fn eval_infix_expr(i: &ast::Infix, env: &mut Env) -> Object {
-    let lhs = eval_expr(&i.lhs, env);
-    let rhs = eval_expr(&i.rhs, env);
+// apply an infix operator to already evaluated operands. Errors are
+// returned without a location; callers attach their own.
+pub fn eval_infix(op: &str, lhs: &Object, rhs: &Object, typing: Typing) -> Object {
+    return match (lhs, rhs) {
+        (Object::Str(l), Object::Str(r)) => eval_str_infix(op, &l.val, &r.val),
+        (Object::Int(l), Object::Int(r)) => eval_int_infix(op, l.val, r.val),
+        (Object::Int(_), Object::Bool(_)) |
+        (Object::Bool(_), Object::Int(_)) |
+        (Object::Bool(_), Object::Bool(_)) if typing == Typing::Legacy
+            => eval_int_infix(op, to_isize(lhs), to_isize(rhs)),
+        _ if op == "==" => Object::Bool(Bool { val: lhs.equals(rhs) }),
+        _ if op == "!=" => Object::Bool(Bool { val: !lhs.equals(rhs) }),
+        _ => {
+            let kind = if lhs.type_name() == rhs.type_name() {
+                ErrorKind::UnknownOperator
+            } else {
+                ErrorKind::TypeMismatch
+            };
+            let msg = format!(
+                "{} {} {}", lhs.type_name(), op, rhs.type_name()
+            );
+            new_error(kind, msg, None)
+        },
+    };
+}
 
-    let lval = match lhs {
-        Object::Int(n) => n.val,
-        Object::Bool(b) => b.val as isize,
-        _ => return Object::Null(Null {}),
+// arithmetic that does not fit in an integer is an error rather than
+// wrapping around. So is shifting by a negative amount or by the width of
+// an integer or more.
+fn eval_int_infix(op: &str, lval: isize, rval: isize) -> Object {
+    let checked = match op {
+        "/" | "%" if rval == 0 => return new_error(
+            ErrorKind::DivisionByZero,
+            format!("{} {} {}", lval, op, rval),
+            None,
+        ),
+        "**" if rval < 0 => return new_error(
+            ErrorKind::NegativeExponent,
+            format!("{} ** {}", lval, rval),
+            None,
+        ),
+        "+" => lval.checked_add(rval),
+        "-" => lval.checked_sub(rval),
+        "*" => lval.checked_mul(rval),
+        "/" => lval.checked_div(rval),
+        "%" => lval.checked_rem(rval),
+        "**" => u32::try_from(rval).ok().and_then(|exp| lval.checked_pow(exp)),
+        "<<" => u32::try_from(rval).ok().and_then(|n| lval.checked_shl(n)),
+        ">>" => u32::try_from(rval).ok().and_then(|n| lval.checked_shr(n)),
+        _ => return eval_int_op(op, lval, rval),
     };
-    let rval = match rhs {
-        Object::Int(n) => n.val,
-        Object::Bool(b) => b.val as isize,
-        _ => return Object::Null(Null {}),
+    return match checked {
+        Some(val) => Object::Int(Int { val: val }),
+        None => new_error(
+            ErrorKind::Overflow,
+            format!("{} {} {}", lval, op, rval),
+            None,
+        ),
     };
+}
 
-    return match i.op.as_str() {
-        "+" => Object::Int(Int { val: lval + rval }),
-        "-" => Object::Int(Int { val: lval - rval }),
-        "*" => Object::Int(Int { val: lval * rval }),
-        "/" => Object::Int(Int { val: lval / rval }),
+// the integer operators that cannot fail.
+fn eval_int_op(op: &str, lval: isize, rval: isize) -> Object {
+    return match op {
+        "&" => Object::Int(Int { val: lval & rval }),
+        "|" => Object::Int(Int { val: lval | rval }),
+        "^" => Object::Int(Int { val: lval ^ rval }),
         "<" => Object::Bool(Bool { val: lval < rval }),
         ">" => Object::Bool(Bool { val: lval > rval }),
+        "<=" => Object::Bool(Bool { val: lval <= rval }),
+        ">=" => Object::Bool(Bool { val: lval >= rval }),
         "==" => Object::Bool(Bool { val: lval == rval }),
         "!=" => Object::Bool(Bool { val: lval != rval }),
-        _ => Object::Null(Null {}),
-    }
+        _ => new_error(
+            ErrorKind::UnknownOperator,
+            format!("INTEGER {} INTEGER", op),
+            None,
+        ),
+    };
+}
+
+fn eval_str_infix(op: &str, lval: &str, rval: &str) -> Object {
+    return match op {
+        "+" => Object::Str(Str { val: format!("{}{}", lval, rval) }),
+        "==" => Object::Bool(Bool { val: lval == rval }),
+        "!=" => Object::Bool(Bool { val: lval != rval }),
+        _ => new_error(
+            ErrorKind::UnknownOperator,
+            format!("STRING {} STRING", op),
+            None,
+        ),
+    };
+}
+
+fn to_isize(obj: &Object) -> isize {
+    return match obj {
+        Object::Int(n) => n.val,
+        Object::Bool(b) => b.val as isize,
+        _ => 0,
+    };
 }
 
-pub fn eval_prefix_bang(rhs: &Object, _env: &mut Env) -> Object {
+pub fn eval_prefix_bang(rhs: &Object) -> Object {
     return match rhs {
         Object::Bool(b) => Object::Bool(Bool { val: !b.val }),
         Object::Null(_) => Object::Bool(Bool { val: true }),
@@ -132,57 +1000,159 @@ pub fn eval_prefix_bang(rhs: &Object, _env: &mut Env) -> Object {
     };
 }
 
-pub fn eval_prefix_minus(rhs: &Object, _env: &mut Env) -> Object {
+pub fn eval_prefix_minus(rhs: &Object) -> Object {
     return match rhs {
-        Object::Int(i) => Object::Int(Int { val: -i.val }),
-        _ => Object::Null(Null {}),
+        Object::Int(i) => match i.val.checked_neg() {
+            Some(val) => Object::Int(Int { val: val }),
+            None => new_error(ErrorKind::Overflow, format!("-({})", i.val), None),
+        },
+        _ => new_error(
+            ErrorKind::UnknownOperator,
+            format!("-{}", rhs.type_name()),
+            None,
+        ),
     };
 }
 
-pub fn eval_ident(i: &ast::Ident, env: &mut Env) -> Object {
-    let val = env.get(i.val.clone());
-    match val {
-        Some(v) => v.clone(),
-        None => Object::Null(Null {}),
-    }
+// the range `start..end` of two already evaluated integers.
+pub fn eval_range(start: &Object, end: &Object) -> Object {
+    return match (start, end) {
+        (Object::Int(s), Object::Int(e)) => Object::Range(Range { start: s.val, end: e.val }),
+        _ => new_error(
+            ErrorKind::TypeMismatch,
+            format!("{}..{}", start.type_name(), end.type_name()),
+            None,
+        ),
+    };
 }
 
-pub fn eval_if_expr(i: &ast::If, env: &mut Env) -> Object {
-    let cond = eval_expr(&i.cond, env);
-    if is_truthy(&cond) {
-        eval_stmt(&ast::Stmt::Block(i.cons.clone()), env)
-    } else {
-        match &i.alt {
-            Some(alt) => eval_stmt(&ast::Stmt::Block(alt.clone()), env),
-            None => Object::Null(Null {}),
-        }
-    }
+// what a for loop steps through for obj: the object itself, or for a hash
+// its keys.
+pub fn iterable(obj: Object) -> Result<Object, Object> {
+    return match obj {
+        Object::Array(_) | Object::Str(_) | Object::Range(_) => Ok(obj),
+        Object::Hash(h) => Ok(Object::Array(Array {
+            elems: Rc::new(h.pairs.values().map(|pair| pair.key.clone()).collect()),
+        })),
+        _ => Err(new_error(ErrorKind::NotIterable, obj.type_name().to_string(), None)),
+    };
 }
 
-pub fn eval_func(f: ast::Func, env: &mut Env) -> Object {
-    return Object::Func(Func {
-        params: f.params,
-        body: f.body,
-        env: env.clone(),
-    });
+// the item at cursor in something returned by iterable, and the cursor of
+// the item after it. Cursors start at 0; for strings they are byte offsets.
+pub fn next_item(iter: &Object, cursor: usize) -> Option<(Object, usize)> {
+    return match iter {
+        Object::Array(a) => a.elems.get(cursor).map(|elem| (elem.clone(), cursor + 1)),
+        Object::Str(s) => s.val[cursor..].chars().next().map(|c| {
+            (Object::Str(Str { val: c.to_string() }), cursor + c.len_utf8())
+        }),
+        Object::Range(r) => {
+            let n = r.start.checked_add(cursor as isize)?;
+            if n < r.end {
+                Some((Object::Int(Int { val: n }), cursor + 1))
+            } else {
+                None
+            }
+        },
+        _ => None,
+    };
 }
 
-pub fn eval_call(c: &ast::Call, env: &mut Env) -> Object {
-    let mut f = match eval_expr(&*c.func, env) {
-        Object::Func(f) => f,
-        _ => return Object::Null(Null {}),
+// index into an already evaluated collection. A negative index counts from
+// the end, so `arr[-1]` is the last element.
+pub fn eval_index(lhs: &Object, index: &Object) -> Object {
+    return match (lhs, index) {
+        (Object::Array(a), Object::Int(n)) => {
+            let len = a.elems.len() as isize;
+            let i = if n.val < 0 { n.val + len } else { n.val };
+            if i < 0 || i >= len {
+                return new_error(
+                    ErrorKind::IndexOutOfRange,
+                    format!("index {} for array of length {}", n.val, len),
+                    None,
+                );
+            }
+            a.elems[i as usize].clone()
+        },
+        (Object::Hash(h), _) => {
+            let key = match index.hash_key() {
+                Some(k) => k,
+                None => return new_error(
+                    ErrorKind::Unhashable,
+                    index.type_name().to_string(),
+                    None,
+                ),
+            };
+            match h.pairs.get(&key) {
+                Some(pair) => pair.val.clone(),
+                None => Object::Null(Null {}),
+            }
+        },
+        _ => new_error(
+            ErrorKind::NotIndexable,
+            format!("{}[{}]", lhs.type_name(), index.type_name()),
+            None,
+        ),
     };
+}
 
-    let args = eval_exprs(&c.args, env);
-    for (i, arg) in args.iter().enumerate() {
-        let param = &f.params[i];
-        f.env.set(param.val.clone(), arg.clone());
+fn check_arity(f: &FuncDef, got: usize) -> Result<(), Object> {
+    let required = f.params.iter().filter(|p| p.default.is_none()).count();
+    let max = match f.rest {
+        Some(_) => None,
+        None => Some(f.params.len()),
+    };
+    if got < required || max.is_some_and(|max| got > max) {
+        return Err(wrong_arity(f.name.as_deref(), required, max, got));
     }
+    Ok(())
+}
 
-    eval_block(&f.body, &mut f.env)
+// a call with too few or too many arguments. A max of None means any
+// number from min up.
+pub fn wrong_arity(name: Option<&str>, min: usize, max: Option<usize>, got: usize) -> Object {
+    let expected = match max {
+        Some(max) if max == min => format!("{}", min),
+        Some(max) => format!("{} to {}", min, max),
+        None => format!("at least {}", min),
+    };
+    let msg = match name {
+        Some(name) => format!("`{}` expected {}, got {}", name, expected, got),
+        None => format!("expected {}, got {}", expected, got),
+    };
+    return new_error(ErrorKind::WrongArity, msg, None);
+}
+
+pub fn stack_overflow(max_depth: usize, span: Span) -> Object {
+    return new_error(
+        ErrorKind::StackOverflow,
+        format!("more than {} nested calls", max_depth),
+        Some(span),
+    );
+}
+
+pub fn interrupted(span: Span) -> Object {
+    return new_error(
+        ErrorKind::Interrupted,
+        String::from("the program was stopped"),
+        Some(span),
+    );
+}
+
+// attach span to an error raised without knowing its location.
+pub fn with_span(obj: Object, span: Span) -> Object {
+    return match obj {
+        Object::Error(mut e) => {
+            if e.span.is_none() {
+                e.span = Some(span);
+            }
+            Object::Error(e)
+        },
+        obj => obj,
+    };
 }
 
-fn is_truthy(obj: &Object) -> bool {
+pub fn is_truthy(obj: &Object) -> bool {
     return match obj {
         Object::Null(_) => false,
         Object::Bool(b) => b.val,
@@ -196,6 +1166,7 @@ mod test {
     use crate::lexer;
     use crate::parser;
     use crate::env;
+    use crate::object::HashKey;
 
     #[test]
     fn eval_return() {
@@ -228,7 +1199,7 @@ mod test {
     }
 
     #[test]
-    fn eval_let() {
+    fn eval_while() {
         struct Test<'a> {
             input: &'a str,
             expected: isize,
@@ -236,20 +1207,40 @@ mod test {
 
         let tests: Vec<Test> = vec! [
             Test {
-                input: "let a = 5; a;",
-                expected: 5
+                input: "let f = fn(n) { let i = 0; let sum = 0; while (i < n) { let i = i + 1; let sum = sum + i; } sum }; f(10)",
+                expected: 55,
             },
             Test {
-                input: "let a = 5 * 5; a;",
-                expected: 25
+                input: "let f = fn() { let i = 0; while (true) { let i = i + 1; if (i == 5) { break; } } i }; f()",
+                expected: 5,
             },
             Test {
-                input: "let a = 5; let b = a; b;",
-                expected: 5
+                input: "let f = fn() {
+                    let i = 0; let odd = 0;
+                    while (i < 10) {
+                        let i = i + 1;
+                        if (i / 2 * 2 == i) { continue; }
+                        let odd = odd + 1;
+                    }
+                    odd
+                }; f()",
+                expected: 5,
             },
             Test {
-                input: "let a = 5; let b = a; let c = a + b + 5; c;",
-                expected: 15
+                input: "let f = fn() { while (true) { while (true) { break; } return 7; } }; f()",
+                expected: 7,
+            },
+            Test {
+                input: "let f = fn() { while (true) { let g = fn() { return 3; }; return g() + 1; } }; f()",
+                expected: 4,
+            },
+            Test {
+                input: "let f = fn(x) { 1 + if (x) { return 10; } }; f(true)",
+                expected: 10,
+            },
+            Test {
+                input: "let i = 0; while (i < 3) { i += 1 }; i",
+                expected: 3,
             },
         ];
 
@@ -257,49 +1248,174 @@ mod test {
             let evaled = test_eval(test.input);
             test_int(evaled, test.expected);
         }
+
+        test_null(test_eval("while (false) { 1 }"));
     }
 
     #[test]
-    fn eval_int() {
+    fn eval_for() {
         struct Test<'a> {
             input: &'a str,
-            expected: isize,
+            expected: &'a str,
         }
 
         let tests: Vec<Test> = vec! [
-            Test { input: "5", expected: 5 },
-            Test { input: "10", expected: 10 },
-            Test { input: "-5", expected: -5 },
-            Test { input: "10", expected: 10 },
-            Test { input: "5 + 5 + 5 - 5", expected: 10 },
-            Test { input: "2 * 2 * 2 * 2", expected: 16 },
-            Test { input: "2 + 3 * 4", expected: 14 },
-            Test { input: "2 * 3 + 4", expected: 10 },
-            Test { input: "-10 + 100 + -10", expected: 80 },
-            Test { input: "50 / 2 * 2 + 10", expected: 60 },
-            Test { input: "2 * (5 + 10)", expected: 30 },
-            Test { input: "3 * 3 * 3 + 10", expected: 37 },
-            Test { input: "3 * (3 * 3) + 10", expected: 37 },
-            Test { input: "(5 + 10 * 2 + 15 / 3) * 2 + -10", expected: 50 },
+            Test {
+                input: "let sum = 0; for (x in [1, 2, 3]) { sum += x; } sum",
+                expected: "6",
+            },
+            Test {
+                input: "let s = \"\"; for (c in \"héllo\") { s = c + s; } s",
+                expected: "olléh",
+            },
+            Test {
+                input: "let ks = []; for (k in {\"b\": 1, \"a\": 2}) { ks = push(ks, k); } ks",
+                expected: "[\"a\", \"b\"]",
+            },
+            Test {
+                input: "let sum = 0; for (i in 0..10) { if (i == 5) { break; } sum += i; } sum",
+                expected: "10",
+            },
+            Test {
+                input: "let odd = []; for (i in 0..6) { if (i / 2 * 2 == i) { continue; } odd = push(odd, i); } odd",
+                expected: "[1, 3, 5]",
+            },
+            Test {
+                input: "let f = fn(xs) { for (x in xs) { if (x > 2) { return x; } } -1 }; [f([1, 5, 3]), f([])]",
+                expected: "[5, -1]",
+            },
+            Test { input: "for (i in 3..1) { i } ", expected: "null" },
+            Test { input: "for (i in 0..3) { } i", expected: "2" },
+            Test { input: "let xs = []; for (i in 0..2) { xs = push(xs, i) }; push(xs, 9)", expected: "[0, 1, 9]" },
+            Test { input: "let n = 2; 1..n * 3", expected: "1..6" },
+            Test { input: "for (x in 5) { }", expected: "ERROR: 1:11: not iterable: INTEGER" },
+            Test { input: "1..\"a\"", expected: "ERROR: 1:1: type mismatch: INTEGER..STRING" },
         ];
 
         for test in tests.iter() {
             let evaled = test_eval(test.input);
-            test_int(evaled, test.expected);
+            assert_eq!(format!("{}", evaled), test.expected, "input: {}", test.input);
         }
     }
 
     #[test]
-    fn eval_bool() {
+    fn eval_assign() {
         struct Test<'a> {
             input: &'a str,
-            expected: bool,
+            expected: isize,
         }
 
         let tests: Vec<Test> = vec! [
-            Test { input: "true", expected: true },
-            Test { input: "false", expected: false },
-            Test { input: "1 < 2", expected: true },
+            Test { input: "let x = 1; x = 2; x", expected: 2 },
+            Test { input: "let x = 1; x = x + 1", expected: 2 },
+            Test { input: "let x = 10; x += 5; x -= 3; x *= 2; x /= 4; x", expected: 6 },
+            Test { input: "let a = 1; let b = 2; a = b = 3; a + b", expected: 6 },
+            Test {
+                input: "let counter = fn() { let n = 0; fn() { n += 1 } }; let c = counter(); c(); c(); c()",
+                expected: 3,
+            },
+            Test {
+                input: "let x = 1; let f = fn() { x = 5; let x = 7; x = 8; }; f(); x",
+                expected: 5,
+            },
+            Test {
+                input: "let f = fn(n) { let total = 0; while (n > 0) { total += n; n -= 1; } total }; f(4)",
+                expected: 10,
+            },
+            Test {
+                input: "const x = 1; let f = fn() { let x = 2; x = 3; x }; f()",
+                expected: 3,
+            },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            test_int(evaled, test.expected);
+        }
+    }
+
+    #[test]
+    fn eval_let() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: isize,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test {
+                input: "let a = 5; a;",
+                expected: 5
+            },
+            Test {
+                input: "let a = 5 * 5; a;",
+                expected: 25
+            },
+            Test {
+                input: "let a = 5; let b = a; b;",
+                expected: 5
+            },
+            Test {
+                input: "let a = 5; let b = a; let c = a + b + 5; c;",
+                expected: 15
+            },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            test_int(evaled, test.expected);
+        }
+    }
+
+    #[test]
+    fn eval_int() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: isize,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "5", expected: 5 },
+            Test { input: "10", expected: 10 },
+            Test { input: "-5", expected: -5 },
+            Test { input: "10", expected: 10 },
+            Test { input: "5 + 5 + 5 - 5", expected: 10 },
+            Test { input: "2 * 2 * 2 * 2", expected: 16 },
+            Test { input: "2 + 3 * 4", expected: 14 },
+            Test { input: "2 * 3 + 4", expected: 10 },
+            Test { input: "-10 + 100 + -10", expected: 80 },
+            Test { input: "50 / 2 * 2 + 10", expected: 60 },
+            Test { input: "2 * (5 + 10)", expected: 30 },
+            Test { input: "3 * 3 * 3 + 10", expected: 37 },
+            Test { input: "3 * (3 * 3) + 10", expected: 37 },
+            Test { input: "(5 + 10 * 2 + 15 / 3) * 2 + -10", expected: 50 },
+            Test { input: "17 % 5 + -7 % 2", expected: 1 },
+            Test { input: "2 ** 10", expected: 1024 },
+            Test { input: "2 ** 3 ** 2", expected: 512 },
+            Test { input: "-2 ** 2", expected: -4 },
+            Test { input: "2 * 3 ** 2", expected: 18 },
+            Test { input: "6 & 3 | 8 ^ 1", expected: 11 },
+            Test { input: "1 << 4 + 1", expected: 32 },
+            Test { input: "-16 >> 2", expected: -4 },
+            Test { input: "~5", expected: -6 },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            test_int(evaled, test.expected);
+        }
+    }
+
+    #[test]
+    fn eval_bool() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: bool,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "true", expected: true },
+            Test { input: "false", expected: false },
+            Test { input: "1 < 2", expected: true },
             Test { input: "1 > 2", expected: false },
             Test { input: "1 < 1", expected: false },
             Test { input: "1 < 1", expected: false },
@@ -316,6 +1432,11 @@ mod test {
             Test { input: "(1 < 2) == false", expected: false },
             Test { input: "(1 > 2) == true", expected: false },
             Test { input: "(1 > 2) == false", expected: true },
+            Test { input: "1 <= 1", expected: true },
+            Test { input: "2 <= 1", expected: false },
+            Test { input: "1 >= 2", expected: false },
+            Test { input: "2 >= 2", expected: true },
+            Test { input: "5 & 1 == 1", expected: true },
         ];
 
         for test in tests.iter() {
@@ -324,6 +1445,203 @@ mod test {
         }
     }
 
+    #[test]
+    fn eval_string() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "\"Hello World!\"", expected: "Hello World!" },
+            Test { input: "\"Hello\" + \" \" + \"World!\"", expected: "Hello World!" },
+            Test {
+                input: "let greet = fn(name) { \"Hello, \" + name }; greet(\"Monkey\")",
+                expected: "Hello, Monkey"
+            },
+            Test { input: "\"tab\\there\"", expected: "tab\there" },
+        ];
+
+        for test in tests.iter() {
+            match test_eval(test.input) {
+                Object::Str(s) => assert_eq!(s.val, test.expected),
+                _ => panic!("We evaled other than string."),
+            };
+        }
+
+        let tests: Vec<(&str, bool)> = vec! [
+            ("\"a\" == \"a\"", true),
+            ("\"a\" == \"b\"", false),
+            ("\"a\" != \"b\"", true),
+            ("\"a\" + \"b\" == \"ab\"", true),
+        ];
+
+        for (input, expected) in tests.iter() {
+            test_bool(test_eval(input), *expected);
+        }
+    }
+
+    #[test]
+    fn eval_array() {
+        let evaled = test_eval("[1, 2 * 2, 3 + 3]");
+        let a = match evaled {
+            Object::Array(a) => a,
+            _ => panic!("We evaled other than array."),
+        };
+
+        assert_eq!(a.elems.len(), 3);
+        test_int(a.elems[0].clone(), 1);
+        test_int(a.elems[1].clone(), 4);
+        test_int(a.elems[2].clone(), 6);
+
+        assert_eq!(format!("{}", test_eval("[1, \"two\", [true]]")), "[1, \"two\", [true]]");
+    }
+
+    #[test]
+    fn eval_index() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: isize,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "[1, 2, 3][0]", expected: 1 },
+            Test { input: "[1, 2, 3][1]", expected: 2 },
+            Test { input: "[1, 2, 3][2]", expected: 3 },
+            Test { input: "let i = 0; [1][i];", expected: 1 },
+            Test { input: "[1, 2, 3][1 + 1];", expected: 3 },
+            Test { input: "let myArray = [1, 2, 3]; myArray[2];", expected: 3 },
+            Test {
+                input: "let a = [1, 2, 3]; a[0] + a[1] + a[2];",
+                expected: 6
+            },
+            Test { input: "let a = [1, 2, 3]; let i = a[0]; a[i]", expected: 2 },
+            Test { input: "[1, 2, 3][-1]", expected: 3 },
+            Test { input: "[1, 2, 3][-3]", expected: 1 },
+            Test { input: "[[1, 2], [3, 4]][1][0]", expected: 3 },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            test_int(evaled, test.expected);
+        }
+    }
+
+    #[test]
+    fn eval_hash() {
+        let input = "let two = \"two\";
+            {
+                \"one\": 10 - 9,
+                two: 1 + 1,
+                \"thr\" + \"ee\": 6 / 2,
+                4: 4,
+                true: 5,
+                false: 6
+            }";
+        let h = match test_eval(input) {
+            Object::Hash(h) => h,
+            _ => panic!("We evaled other than hash."),
+        };
+
+        let expects = [
+            (HashKey::Str(String::from("one")), 1),
+            (HashKey::Str(String::from("two")), 2),
+            (HashKey::Str(String::from("three")), 3),
+            (HashKey::Int(4), 4),
+            (HashKey::Bool(true), 5),
+            (HashKey::Bool(false), 6),
+        ];
+
+        assert_eq!(h.pairs.len(), expects.len());
+        for (key, val) in expects.iter() {
+            match h.pairs.get(key) {
+                Some(pair) => test_int(pair.val.clone(), *val),
+                None => panic!("We got no pair for {:?}.", key),
+            };
+        }
+
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "{\"foo\": 5}[\"foo\"]", expected: "5" },
+            Test { input: "{\"foo\": 5}[\"bar\"]", expected: "null" },
+            Test { input: "let key = \"foo\"; {\"foo\": 5}[key]", expected: "5" },
+            Test { input: "{}[\"foo\"]", expected: "null" },
+            Test { input: "{5: 5}[5]", expected: "5" },
+            Test { input: "{true: 5}[true]", expected: "5" },
+            Test { input: "{1: \"int\", true: \"bool\"}[true]", expected: "bool" },
+            Test { input: "{\"a\": 1, \"a\": 2}[\"a\"]", expected: "2" },
+            Test { input: "len({1: 1, 2: 2})", expected: "2" },
+            Test { input: "{\"b\": [1], \"a\": \"x\"}", expected: "{\"a\": \"x\", \"b\": [1]}" },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            assert_eq!(format!("{}", evaled), test.expected);
+        }
+    }
+
+    #[test]
+    fn eval_builtins() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "len(\"\")", expected: "0" },
+            Test { input: "len(\"four\")", expected: "4" },
+            Test { input: "len(\"héllo\")", expected: "5" },
+            Test { input: "len([1, 2, 3])", expected: "3" },
+            Test { input: "len([])", expected: "0" },
+            Test { input: "first([1, 2, 3])", expected: "1" },
+            Test { input: "first([])", expected: "null" },
+            Test { input: "last([1, 2, 3])", expected: "3" },
+            Test { input: "last([])", expected: "null" },
+            Test { input: "rest([1, 2, 3])", expected: "[2, 3]" },
+            Test { input: "rest([1])", expected: "[]" },
+            Test { input: "rest([])", expected: "null" },
+            Test { input: "push([], 1)", expected: "[1]" },
+            Test { input: "puts(\"hello\", 1)", expected: "null" },
+            Test { input: "let a = [1]; push(a, 2); a", expected: "[1]" },
+            Test {
+                input: "let map = fn(arr, f) {
+                            let iter = fn(arr, acc) {
+                                if (len(arr) == 0) { return acc; }
+                                iter(rest(arr), push(acc, f(first(arr))));
+                            };
+                            iter(arr, []);
+                        };
+                        map([1, 2, 3], fn(x) { x * 2 })",
+                expected: "[2, 4, 6]"
+            },
+            Test {
+                input: "len(1)",
+                expected: "ERROR: 1:1: type mismatch: argument to `len` not supported, got INTEGER"
+            },
+            Test {
+                input: "len(\"one\", \"two\")",
+                expected: "ERROR: 1:1: wrong number of arguments: `len` expected 1, got 2"
+            },
+            Test {
+                input: "first(1)",
+                expected: "ERROR: 1:1: type mismatch: argument to `first` not supported, got INTEGER"
+            },
+            Test {
+                input: "let len = fn(x) { 42 }; len([1]);",
+                expected: "42"
+            },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            assert_eq!(format!("{}", evaled), test.expected);
+        }
+    }
+
     #[test]
     fn eval_bang() {
         struct Test<'a> {
@@ -346,6 +1664,112 @@ mod test {
         }
     }
 
+    #[test]
+    fn eval_typing() {
+        struct Test<'a> {
+            input: &'a str,
+            strict: &'a str,
+            legacy: &'a str,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test {
+                input: "true + 5",
+                strict: "ERROR: 1:1: type mismatch: BOOLEAN + INTEGER",
+                legacy: "6",
+            },
+            Test {
+                input: "1 < false",
+                strict: "ERROR: 1:1: type mismatch: INTEGER < BOOLEAN",
+                legacy: "false",
+            },
+            Test {
+                input: "true * true",
+                strict: "ERROR: 1:1: unknown operator: BOOLEAN * BOOLEAN",
+                legacy: "1",
+            },
+            Test {
+                input: "-true",
+                strict: "ERROR: 1:1: type mismatch: -BOOLEAN",
+                legacy: "ERROR: 1:1: unknown operator: -BOOLEAN",
+            },
+            Test { input: "true == 1", strict: "false", legacy: "true" },
+            Test { input: "true != false", strict: "true", legacy: "true" },
+            Test { input: "1 == \"1\"", strict: "false", legacy: "false" },
+            Test {
+                input: "1 + \"1\"",
+                strict: "ERROR: 1:1: type mismatch: INTEGER + STRING",
+                legacy: "ERROR: 1:1: type mismatch: INTEGER + STRING",
+            },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval_with(test.input, Typing::Strict);
+            assert_eq!(format!("{}", evaled), test.strict, "input: {}", test.input);
+            let evaled = test_eval_with(test.input, Typing::Legacy);
+            assert_eq!(format!("{}", evaled), test.legacy, "input: {}", test.input);
+        }
+    }
+
+    #[test]
+    fn eval_equality() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: bool,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "if (false) { 1 } == if (false) { 2 }", expected: true },
+            Test { input: "if (false) { 1 } != 0", expected: true },
+            Test { input: "[1, [2, \"a\"]] == [1, [2, \"a\"]]", expected: true },
+            Test { input: "[1, 2] == [1, 2, 3]", expected: false },
+            Test { input: "[1, 2] != [2, 1]", expected: true },
+            Test { input: "{\"a\": [1], 2: true} == {2: true, \"a\": [1]}", expected: true },
+            Test { input: "{\"a\": 1} == {\"a\": 2}", expected: false },
+            Test { input: "let f = fn(x) { x }; f == f", expected: true },
+            Test { input: "fn(x) { x } == fn(x) { x }", expected: false },
+            Test { input: "len == len", expected: true },
+            Test { input: "len != first", expected: true },
+            Test { input: "0..3 == 0..3", expected: true },
+            Test { input: "[1] == 1", expected: false },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            test_bool(evaled, test.expected);
+        }
+    }
+
+    #[test]
+    fn eval_logical() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test { input: "true && false", expected: "false" },
+            Test { input: "true || false", expected: "true" },
+            Test { input: "1 && \"a\"", expected: "a" },
+            Test { input: "if (false) { 1 } && 2", expected: "null" },
+            Test { input: "0 || 2", expected: "0" },
+            Test { input: "false || [1]", expected: "[1]" },
+            Test { input: "false && undefined", expected: "false" },
+            Test { input: "true || undefined()", expected: "true" },
+            Test { input: "1 < 2 && 2 < 3", expected: "true" },
+            Test {
+                input: "let n = 0; let f = fn() { n += 1; true }; false && f(); true || f(); true && f(); n",
+                expected: "1",
+            },
+            Test { input: "true && undefined", expected: "ERROR: 1:9: identifier not found: undefined" },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            assert_eq!(format!("{}", evaled), test.expected, "input: {}", test.input);
+        }
+    }
+
     #[test]
     fn eval_if() {
         struct Test<'a> {
@@ -359,6 +1783,9 @@ mod test {
             Test { input: "if (1 < 2) { 10 }", expected: 10 },
             Test { input: "if (1 < 2) { 10 } else { 20 }", expected: 10 },
             Test { input: "if (1 > 2) { 10 } else { 20 }", expected: 20 },
+            Test { input: "if (1 > 2) { 10 } else if (1 < 2) { 20 } else { 30 }", expected: 20 },
+            Test { input: "if (1 > 2) { 10 } else if (false) { 20 } else { 30 }", expected: 30 },
+            Test { input: "let f = fn(n) { if (n == 0) { 1 } else if (n == 1) { 2 } }; f(1)", expected: 2 },
         ];
 
         for test in tests.iter() {
@@ -369,6 +1796,7 @@ mod test {
         let inputs = vec! [
             "if (false) { 10 }",
             "if (1 > 2) { 10 }",
+            "if (false) { 10 } else if (false) { 20 }",
         ];
         for input in inputs.iter() {
             let evaled = test_eval(input);
@@ -385,9 +1813,150 @@ mod test {
             _ => panic!("We evaled other than function"),
         };
 
-        assert_eq!(f.params.len(), 1);
-        assert_eq!(f.params[0].val, "x");
-        assert_eq!(format!("{}", f.body), "(x + 2)");
+        assert_eq!(f.def.params.len(), 1);
+        assert_eq!(f.def.params[0].name.val, "x");
+        assert_eq!(format!("{}", f.def.body), "(x + 2)");
+    }
+
+    #[test]
+    fn eval_frees_envs() {
+        let input = "fn() { let g = fn(n) { if (n > 0) { g(n - 1) } else { n } }; g }()";
+        let g = test_eval(input);
+        let env = match &g {
+            Object::Func(g) => Rc::downgrade(g.env.as_ref().unwrap()),
+            _ => panic!("We evaled other than function"),
+        };
+
+        // the function bound in the scope it was defined in does not keep
+        // that scope alive once the last outside copy is gone.
+        drop(g);
+        assert!(env.upgrade().is_none());
+    }
+
+    #[test]
+    fn eval_tail_calls() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+        }
+
+        // far deeper than the call stack allows.
+        let tests: Vec<Test> = vec! [
+            Test {
+                input: "let loop = fn(n, acc) { if (n == 0) { acc } else { loop(n - 1, acc + n) } };
+                        loop(100000, 0)",
+                expected: "5000050000",
+            },
+            Test {
+                input: "let even = fn(n) { if (n == 0) { return true; } odd(n - 1) };
+                        let odd = fn(n) { if (n == 0) { return false; } return even(n - 1); };
+                        [even(50001), odd(50001)]",
+                expected: "[false, true]",
+            },
+            Test {
+                input: "let f = fn(n) { let m = n - 1; if (m < 0) { \"done\" } else { if (m % 2 == 0) { f(m) } else { return f(m); } } };
+                        f(50000)",
+                expected: "done",
+            },
+            Test {
+                input: "let f = fn(n, step = 1) { if (n <= 0) { len([]) } else { f(n - step) } }; f(50000)",
+                expected: "0",
+            },
+            Test {
+                input: "let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(100)",
+                expected: "ERROR: 1:46: stack overflow: more than 50 nested calls",
+            },
+            Test {
+                input: "let f = fn(n) { while (true) { return f(n - 1); } }; f(100)",
+                expected: "ERROR: 1:39: stack overflow: more than 50 nested calls",
+            },
+            Test {
+                input: "let f = fn(n) { if (n == 0) { g(1, 2) } else { f(n - 1) } }; let g = fn(x) { x }; f(100)",
+                expected: "ERROR: 1:31: wrong number of arguments: `g` expected 1, got 2",
+            },
+        ];
+
+        for test in tests.iter() {
+            let mut l = lexer::new(test.input);
+            let mut p = parser::new(&mut l);
+            let program = p.parse_program().unwrap();
+            let mut e = new();
+            e.set_max_depth(50);
+            let evaled = e.eval(ast::Node::Program(program), &env::new());
+            assert_eq!(format!("{}", evaled), test.expected, "input: {}", test.input);
+            assert!(e.calls.is_empty());
+        }
+    }
+
+    #[test]
+    fn eval_limits() {
+        struct Test<'a> {
+            input: &'a str,
+            limits: Limits,
+            expected: &'a str,
+        }
+
+        let steps = |n| Limits { steps: Some(n), ..Limits::default() };
+        let max_len = |n| Limits { max_len: Some(n), ..Limits::default() };
+        let tests: Vec<Test> = vec! [
+            Test { input: "1 + 2", limits: steps(3), expected: "3" },
+            Test {
+                input: "1 + 2",
+                limits: steps(2),
+                expected: "ERROR: 1:5: resource limit exceeded: more than 2 steps",
+            },
+            Test {
+                input: "let f = fn() { f() }; f()",
+                limits: steps(10000),
+                expected: "ERROR: 1:16: resource limit exceeded: more than 10000 steps",
+            },
+            Test {
+                input: "while (true) { }",
+                limits: Limits { timeout: Some(Duration::from_millis(10)), ..Limits::default() },
+                expected: "ERROR: 1:8: resource limit exceeded: ran longer than 10ms",
+            },
+            Test { input: "[1, 2]; \"ab\"; {1: 2}", limits: max_len(2), expected: "{1: 2}" },
+            Test {
+                input: "let a = [1, 2]; push(a, 3)",
+                limits: max_len(2),
+                expected: "ERROR: 1:17: resource limit exceeded: ARRAY larger than 2",
+            },
+            Test {
+                input: "let s = \"a\"; for (i in 0..10) { s += s; }",
+                limits: max_len(100),
+                expected: "ERROR: 1:33: resource limit exceeded: STRING larger than 100",
+            },
+            Test {
+                input: "{1: 1, 2: 2, 3: 3}",
+                limits: max_len(2),
+                expected: "ERROR: 1:1: resource limit exceeded: HASH larger than 2",
+            },
+            Test {
+                input: "[\"abc\", {\"de\": [1]}]",
+                limits: max_len(9),
+                expected: "[\"abc\", {\"de\": [1]}]",
+            },
+            Test {
+                input: "[\"abc\", {\"de\": [1]}]",
+                limits: max_len(8),
+                expected: "ERROR: 1:1: resource limit exceeded: ARRAY larger than 8",
+            },
+            Test {
+                input: "let a = [1]; let i = 0; while (i < 40) { a = [a, a]; i += 1; }",
+                limits: max_len(10),
+                expected: "ERROR: 1:46: resource limit exceeded: ARRAY larger than 10",
+            },
+        ];
+
+        for test in tests.iter() {
+            let mut l = lexer::new(test.input);
+            let mut p = parser::new(&mut l);
+            let program = p.parse_program().unwrap();
+            let mut e = new();
+            e.set_limits(test.limits);
+            let evaled = e.eval(ast::Node::Program(program), &env::new());
+            assert_eq!(format!("{}", evaled), test.expected, "input: {}", test.input);
+        }
     }
 
     #[test]
@@ -428,6 +1997,93 @@ mod test {
                         addTwo(2);",
                 expected: 4
             },
+            Test {
+                input: "let add = fn(x, y = x * 10) { x + y }; add(1) + add(1, 2);",
+                expected: 14
+            },
+            Test {
+                input: "let y = 100; let f = fn(x = y) { let y = 1; x }; f();",
+                expected: 100
+            },
+            Test {
+                input: "let f = fn(x = if (true) { return 7; }) { 0 }; f() + f(1);",
+                expected: 7
+            },
+            Test {
+                input: "let f = fn(a, ...rest) { a * 10 + len(rest) }; f(1) + f(2, 3, 4);",
+                expected: 32
+            },
+            Test {
+                input: "fn(...xs) { xs[1] }(5, 6, 7)",
+                expected: 6
+            },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            test_int(evaled, test.expected);
+        }
+    }
+
+    #[test]
+    fn eval_deep_recursion() {
+        let input = "let count = fn(n) { if (n == 0) { 0 } else { 1 + count(n - 1) } }; count(100000)";
+        let mut l = lexer::new(input);
+        let mut p = parser::new(&mut l);
+        let program = p.parse_program().unwrap();
+        let mut e = new();
+        let evaled = e.eval(ast::Node::Program(program.clone()), &env::new());
+        assert_eq!(
+            format!("{}", evaled),
+            "ERROR: 1:50: stack overflow: more than 1000 nested calls"
+        );
+
+        // calls are kept on the heap rather than the Rust stack, so only
+        // max_depth limits how deep they go.
+        e.set_max_depth(200000);
+        let evaled = e.eval(ast::Node::Program(program), &env::new());
+        assert_eq!(format!("{}", evaled), "100000");
+        assert!(e.calls.is_empty());
+    }
+
+    #[test]
+    fn eval_recursion() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: isize,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test {
+                input: "let fib = fn(n) {
+                            if (n < 2) { return n; }
+                            fib(n - 1) + fib(n - 2);
+                        };
+                        fib(10);",
+                expected: 55
+            },
+            Test {
+                input: "let is_even = fn(n) { if (n == 0) { true } else { is_odd(n - 1) } };
+                        let is_odd = fn(n) { if (n == 0) { false } else { is_even(n - 1) } };
+                        if (is_even(10)) { 1 } else { 0 };",
+                expected: 1
+            },
+            Test {
+                input: "let count = fn(n) {
+                            let go = fn(i) { if (i == n) { i } else { go(i + 1) } };
+                            go(0);
+                        };
+                        count(7);",
+                expected: 7
+            },
+            Test {
+                input: "let f = fn() { return 1; }; f(); 2;",
+                expected: 2
+            },
+            Test {
+                input: "let x = 1; let f = fn(x) { x; }; f(5); x;",
+                expected: 1
+            },
         ];
 
         for test in tests.iter() {
@@ -436,12 +2092,234 @@ mod test {
         }
     }
 
+    #[test]
+    fn eval_error() {
+        struct Test<'a> {
+            input: &'a str,
+            kind: ErrorKind,
+            expected: &'a str,
+        }
+
+        let tests: Vec<Test> = vec! [
+            Test {
+                input: "5 + fn(x) { x };",
+                kind: ErrorKind::TypeMismatch,
+                expected: "ERROR: 1:1: type mismatch: INTEGER + FUNCTION",
+            },
+            Test {
+                input: "let f = fn(x) { x }; f + f; 5;",
+                kind: ErrorKind::UnknownOperator,
+                expected: "ERROR: 1:22: unknown operator: FUNCTION + FUNCTION",
+            },
+            Test {
+                input: "-fn(x) { x }",
+                kind: ErrorKind::UnknownOperator,
+                expected: "ERROR: 1:1: unknown operator: -FUNCTION",
+            },
+            Test {
+                input: "\"Hello\" - \"World\"",
+                kind: ErrorKind::UnknownOperator,
+                expected: "ERROR: 1:1: unknown operator: STRING - STRING",
+            },
+            Test {
+                input: "let x = 1; \"x\" + x",
+                kind: ErrorKind::TypeMismatch,
+                expected: "ERROR: 1:12: type mismatch: STRING + INTEGER",
+            },
+            Test {
+                input: "[1, 2, 3][3]",
+                kind: ErrorKind::IndexOutOfRange,
+                expected: "ERROR: 1:1: index out of range: index 3 for array of length 3",
+            },
+            Test {
+                input: "[1, 2, 3][-4]",
+                kind: ErrorKind::IndexOutOfRange,
+                expected: "ERROR: 1:1: index out of range: index -4 for array of length 3",
+            },
+            Test {
+                input: "let x = 1; x[0]",
+                kind: ErrorKind::NotIndexable,
+                expected: "ERROR: 1:12: index operator not supported: INTEGER[INTEGER]",
+            },
+            Test {
+                input: "[1][true]",
+                kind: ErrorKind::NotIndexable,
+                expected: "ERROR: 1:1: index operator not supported: ARRAY[BOOLEAN]",
+            },
+            Test {
+                input: "{\"name\": \"Monkey\"}[fn(x) { x }];",
+                kind: ErrorKind::Unhashable,
+                expected: "ERROR: 1:1: unusable as hash key: FUNCTION",
+            },
+            Test {
+                input: "{[1]: 2}",
+                kind: ErrorKind::Unhashable,
+                expected: "ERROR: 1:2: unusable as hash key: ARRAY",
+            },
+            Test {
+                input: "foobar",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:1: identifier not found: foobar",
+            },
+            Test {
+                input: "let x = 5; x(1);",
+                kind: ErrorKind::NotCallable,
+                expected: "ERROR: 1:12: not a function: INTEGER",
+            },
+            Test {
+                input: "let id = fn(x) { x }; id(1, 2);",
+                kind: ErrorKind::WrongArity,
+                expected: "ERROR: 1:23: wrong number of arguments: `id` expected 1, got 2",
+            },
+            Test {
+                input: "fn(x) { x }()",
+                kind: ErrorKind::WrongArity,
+                expected: "ERROR: 1:1: wrong number of arguments: expected 1, got 0",
+            },
+            Test {
+                input: "let f = fn(a, b = 1) { a }; f(1, 2, 3)",
+                kind: ErrorKind::WrongArity,
+                expected: "ERROR: 1:29: wrong number of arguments: `f` expected 1 to 2, got 3",
+            },
+            Test {
+                input: "const f = fn(a, b, ...c) { a }; f(1)",
+                kind: ErrorKind::WrongArity,
+                expected: "ERROR: 1:33: wrong number of arguments: `f` expected at least 2, got 1",
+            },
+            Test {
+                input: "let f = fn(a = b) { a }; f()",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:16: identifier not found: b",
+            },
+            Test {
+                input: "if (10 > 1) { if (true) { foo; } return 1; }",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:27: identifier not found: foo",
+            },
+            Test {
+                input: "let f = fn(x) { x + y; }; f(1); 5;",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:21: identifier not found: y",
+            },
+            Test {
+                input: "let add = fn(x, y) { x + y }; add(1, bar);",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:38: identifier not found: bar",
+            },
+            Test {
+                input: "if (baz) { 1 } else { 2 }",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:5: identifier not found: baz",
+            },
+            Test {
+                input: "let f = fn(x) { 10 / x }; f(0)",
+                kind: ErrorKind::DivisionByZero,
+                expected: "ERROR: 1:17: division by zero: 10 / 0",
+            },
+            Test {
+                input: "5 % 0",
+                kind: ErrorKind::DivisionByZero,
+                expected: "ERROR: 1:1: division by zero: 5 % 0",
+            },
+            Test {
+                input: "let max = 9223372036854775807; 1 + (max + 1)",
+                kind: ErrorKind::Overflow,
+                expected: "ERROR: 1:37: integer overflow: 9223372036854775807 + 1",
+            },
+            Test {
+                input: "let min = -9223372036854775807 - 1;\n-min",
+                kind: ErrorKind::Overflow,
+                expected: "ERROR: 2:1: integer overflow: -(-9223372036854775808)",
+            },
+            Test {
+                input: "let min = -9223372036854775807 - 1; min / -1",
+                kind: ErrorKind::Overflow,
+                expected: "ERROR: 1:37: integer overflow: -9223372036854775808 / -1",
+            },
+            Test {
+                input: "3 ** 40",
+                kind: ErrorKind::Overflow,
+                expected: "ERROR: 1:1: integer overflow: 3 ** 40",
+            },
+            Test {
+                input: "1 << 64",
+                kind: ErrorKind::Overflow,
+                expected: "ERROR: 1:1: integer overflow: 1 << 64",
+            },
+            Test {
+                input: "let x = 4611686018427387904; x *= 2",
+                kind: ErrorKind::Overflow,
+                expected: "ERROR: 1:30: integer overflow: 4611686018427387904 * 2",
+            },
+            Test {
+                input: "2 ** -1",
+                kind: ErrorKind::NegativeExponent,
+                expected: "ERROR: 1:1: negative exponent: 2 ** -1",
+            },
+            Test {
+                input: "~true",
+                kind: ErrorKind::TypeMismatch,
+                expected: "ERROR: 1:1: type mismatch: ~BOOLEAN",
+            },
+            Test {
+                input: "\"a\" <= \"b\"",
+                kind: ErrorKind::UnknownOperator,
+                expected: "ERROR: 1:1: unknown operator: STRING <= STRING",
+            },
+            Test {
+                input: "let f = fn() { y = 1 }; f()",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:16: identifier not found: y",
+            },
+            Test {
+                input: "z += 1",
+                kind: ErrorKind::UnknownIdent,
+                expected: "ERROR: 1:1: identifier not found: z",
+            },
+            Test {
+                input: "const x = 1; let f = fn() { x += 1 }; f()",
+                kind: ErrorKind::ConstAssign,
+                expected: "ERROR: 1:29: assignment to constant: x",
+            },
+            Test {
+                input: "const x = 1; let x = 2;",
+                kind: ErrorKind::ConstAssign,
+                expected: "ERROR: 1:18: assignment to constant: x",
+            },
+            Test {
+                input: "const x = 1; for (x in [2]) { }",
+                kind: ErrorKind::ConstAssign,
+                expected: "ERROR: 1:19: assignment to constant: x",
+            },
+            Test {
+                input: "let s = \"a\"; s -= 1",
+                kind: ErrorKind::TypeMismatch,
+                expected: "ERROR: 1:14: type mismatch: STRING - INTEGER",
+            },
+        ];
+
+        for test in tests.iter() {
+            let evaled = test_eval(test.input);
+            match &evaled {
+                Object::Error(e) => assert_eq!(e.kind, test.kind),
+                _ => panic!("We evaled other than error."),
+            };
+            assert_eq!(format!("{}", evaled), test.expected);
+        }
+    }
+
     fn test_eval(input: &str) -> Object {
-        let mut l = lexer::new(&input);
+        return test_eval_with(input, Typing::Strict);
+    }
+
+    fn test_eval_with(input: &str, typing: Typing) -> Object {
+        let mut l = lexer::new(input);
         let mut p = parser::new(&mut l);
-        let program = p.parse_program();
-        let mut env = env::new();
-        return eval(ast::Node::Program(program), &mut env);
+        let program = p.parse_program().unwrap();
+        let env = env::new();
+        let mut e = new();
+        e.set_typing(typing);
+        return e.eval(ast::Node::Program(program), &env);
     }
 
     fn test_int(obj: Object, expected: isize) {
@@ -0,0 +1,530 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use crate::ast;
+use crate::compiler;
+use crate::env;
+use crate::env::Env;
+use crate::eval;
+use crate::lexer;
+use crate::object::{
+    Object,
+    ErrorKind,
+    new_error,
+};
+use crate::parser;
+use crate::vm;
+
+// Interp runs Monkey source against a global environment that persists
+// between runs. It is what the REPL uses and what applications embed.
+pub struct Interp {
+    backend: Backend,
+    env: Rc<RefCell<Env>>,
+    evaluator: eval::Evaluator,
+    compiler: compiler::Compiler,
+    vm: vm::Vm,
+    interrupt: Arc<AtomicBool>,
+    // what the host bound with define, in order, to bind again on reset.
+    defined: Vec<(String, Object)>,
+}
+
+// how programs are run. Both give the same results.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Backend {
+    // walk the AST.
+    Eval,
+    // compile to bytecode and run it on the vm.
+    Vm,
+}
+
+pub fn new() -> Interp {
+    return with_backend(Backend::Eval);
+}
+
+pub fn with_backend(backend: Backend) -> Interp {
+    let interrupt = Arc::new(AtomicBool::new(false));
+    let mut evaluator = eval::new();
+    evaluator.set_interrupt(Arc::clone(&interrupt));
+    let mut vm = vm::new();
+    vm.set_interrupt(Arc::clone(&interrupt));
+
+    return Interp {
+        backend: backend,
+        env: env::new(),
+        evaluator: evaluator,
+        compiler: compiler::new(),
+        vm: vm,
+        interrupt: interrupt,
+        defined: vec![],
+    };
+}
+
+impl Interp {
+    // make a Rust function callable from Monkey as `name`, e.g.
+    //
+    //     interp.register("double", Some(1), |args| match &args[0] {
+    //         Object::Int(n) => Object::Int(Int { val: n.val * 2 }),
+    //         _ => Object::Null(Null {}),
+    //     });
+    //
+    // An arity of None accepts any number of arguments.
+    pub fn register<F>(&mut self, name: &str, arity: Option<usize>, func: F)
+    where
+        F: Fn(Vec<Object>) -> Object + 'static,
+    {
+        let func = Rc::new(func);
+        let f = Rc::clone(&func);
+        self.evaluator.register(name, arity, move |args| f(args));
+        self.vm.register(name, arity, move |args| func(args));
+    }
+
+    // parse and evaluate input. Runtime errors are returned as an
+    // Object::Error.
+    pub fn run(
+        &mut self, input: &str
+    ) -> Result<Object, Vec<parser::ParseError>> {
+        let mut l = lexer::new(input);
+        let mut p = parser::new(&mut l);
+        let program = p.parse_program()?;
+        self.interrupt.store(false, Ordering::Relaxed);
+        return Ok(match self.backend {
+            Backend::Eval => {
+                self.evaluator.eval(ast::Node::Program(program), &self.env)
+            },
+            Backend::Vm => match self.compiler.compile(&program) {
+                Ok(bc) => self.vm.run(bc),
+                Err(e) => new_error(ErrorKind::ProgramTooLarge, format!("{}", e), None),
+            },
+        });
+    }
+
+    // a flag that stops the running program when set, for example from a
+    // signal handler or another thread. Each run starts with it cleared.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        return Arc::clone(&self.interrupt);
+    }
+
+    // bind name in the global scope, as a top-level `let` would. Unlike
+    // bindings made by programs, it survives a reset.
+    pub fn define(&mut self, name: &str, val: Object) {
+        self.defined.push((String::from(name), val.clone()));
+        self.bind(name, val);
+    }
+
+    fn bind(&mut self, name: &str, val: Object) {
+        match self.backend {
+            Backend::Eval => self.env.borrow_mut().set(String::from(name), val),
+            Backend::Vm => {
+                let i = self.compiler.global(name);
+                self.vm.set_global(i, val);
+            },
+        }
+    }
+
+    // the global bindings, sorted by name.
+    pub fn bindings(&self) -> Vec<(String, Object)> {
+        let mut bindings: Vec<(String, Object)> = match self.backend {
+            Backend::Eval => {
+                let env = self.env.borrow();
+                env.idents.keys()
+                    .filter_map(|name| env.get(name).map(|val| (name.clone(), val)))
+                    .collect()
+            },
+            Backend::Vm => self.compiler.global_names().iter().enumerate()
+                .filter_map(|(i, name)| {
+                    self.vm.global(i).map(|val| (name.clone(), val.clone()))
+                })
+                .collect(),
+        };
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
+
+    // forget the global bindings programs made. Registered builtins and
+    // what the host defined are kept.
+    pub fn reset(&mut self) {
+        self.env = env::new();
+        self.compiler = compiler::new();
+        self.vm.reset();
+        for (name, val) in self.defined.clone().into_iter() {
+            self.bind(&name, val);
+        }
+    }
+
+    // choose whether booleans count as integers, in both backends.
+    pub fn set_typing(&mut self, typing: eval::Typing) {
+        self.evaluator.set_typing(typing);
+        self.vm.set_typing(typing);
+    }
+
+    // limit how many function calls may be in progress at once. Going past
+    // it is a StackOverflow error.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.evaluator.set_max_depth(depth);
+        self.vm.set_max_depth(depth);
+    }
+
+    // cap the steps, time and collection sizes of each run, for programs
+    // that are not trusted. Steps are counted differently by the two
+    // backends.
+    pub fn set_limits(&mut self, limits: eval::Limits) {
+        self.evaluator.set_limits(limits);
+        self.vm.set_limits(limits);
+    }
+
+    pub fn backend(&self) -> Backend {
+        return self.backend;
+    }
+
+    // the global environment programs are evaluated in by Backend::Eval.
+    pub fn env(&self) -> &Rc<RefCell<Env>> {
+        return &self.env;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::object::{
+        Int,
+        Null,
+    };
+
+    #[test]
+    fn register_builtin() {
+        let mut interp = new();
+        interp.register("double", Some(1), |args| match &args[0] {
+            Object::Int(n) => Object::Int(Int { val: n.val * 2 }),
+            _ => Object::Null(Null {}),
+        });
+
+        let calls = Rc::new(RefCell::new(0));
+        let counter = Rc::clone(&calls);
+        interp.register("tick", None, move |args| {
+            *counter.borrow_mut() += args.len();
+            Object::Null(Null {})
+        });
+
+        let evaled = interp.run("tick(1, 2); tick(); tick(3); double(21)");
+        assert_eq!(format!("{}", evaled.ok().unwrap()), "42");
+        assert_eq!(*calls.borrow(), 3);
+
+        let evaled = interp.run("double(1, 2)");
+        assert_eq!(
+            format!("{}", evaled.ok().unwrap()),
+            "ERROR: 1:1: wrong number of arguments: `double` expected 1, got 2"
+        );
+    }
+
+    #[test]
+    fn builtin_shadowed_by_binding() {
+        let mut interp = new();
+        interp.register("answer", Some(0), |_| Object::Int(Int { val: 42 }));
+
+        let evaled = interp.run("answer()");
+        assert_eq!(format!("{}", evaled.ok().unwrap()), "42");
+
+        let evaled = interp.run("let answer = fn() { 0 }; answer()");
+        assert_eq!(format!("{}", evaled.ok().unwrap()), "0");
+    }
+
+    #[test]
+    fn run_keeps_bindings() {
+        let mut interp = new();
+        assert!(interp.run("let x = 5;").is_ok());
+        assert!(interp.run("let y = x * 2;").is_ok());
+
+        let evaled = interp.run("x + y");
+        assert_eq!(format!("{}", evaled.ok().unwrap()), "15");
+        assert!(interp.env().borrow().get("y").is_some());
+
+        assert!(interp.run("let = 1;").is_err());
+    }
+
+    #[test]
+    fn vm_backend() {
+        let mut interp = with_backend(Backend::Vm);
+        assert_eq!(interp.backend(), Backend::Vm);
+        interp.register("double", Some(1), |args| match &args[0] {
+            Object::Int(n) => Object::Int(Int { val: n.val * 2 }),
+            _ => Object::Null(Null {}),
+        });
+
+        assert!(interp.run("let f = fn(x) { double(x) + y };").is_ok());
+        assert!(interp.run("let y = 1;").is_ok());
+        let evaled = interp.run("f(20)");
+        assert_eq!(format!("{}", evaled.ok().unwrap()), "41");
+
+        let evaled = interp.run("double(1, 2)");
+        assert_eq!(
+            format!("{}", evaled.ok().unwrap()),
+            "ERROR: 1:1: wrong number of arguments: `double` expected 1, got 2"
+        );
+    }
+
+    #[test]
+    fn legacy_typing() {
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            let evaled = interp.run("true + 1");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:1: type mismatch: BOOLEAN + INTEGER"
+            );
+
+            interp.set_typing(eval::Typing::Legacy);
+            let evaled = interp.run("let f = fn(x) { x + true }; f(1)");
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "2");
+        }
+    }
+
+    #[test]
+    fn call_stack() {
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            interp.set_max_depth(20);
+            assert!(interp.run("let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } };").is_ok());
+
+            let evaled = interp.run("f(19)");
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "19");
+
+            let trace = match interp.run("f(1);\nf(20)").ok().unwrap() {
+                Object::Error(e) => {
+                    assert_eq!(
+                        format!("{}", e),
+                        "ERROR: 1:46: stack overflow: more than 20 nested calls"
+                    );
+                    e.trace.iter().map(|frame| format!("{}", frame)).collect::<Vec<_>>()
+                },
+                obj => panic!("We ran other than an error: {}", obj),
+            };
+            assert_eq!(trace.len(), 20);
+            assert_eq!(trace[0], "at f (1:46)");
+            assert_eq!(trace[19], "at f (2:1)");
+
+            // the program that overflowed leaves nothing behind.
+            let evaled = interp.run("let g = fn() { f(18) + f(0) }; g()");
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "18");
+
+            match interp.run("let h = fn() { [][0] + 1 };\nfn() { let x = h(); x }()").ok().unwrap() {
+                Object::Error(e) => {
+                    let trace: Vec<String> = e.trace.iter().map(|frame| format!("{}", frame)).collect();
+                    assert_eq!(trace, vec!["at h (2:16)", "at <anonymous> (2:1)"]);
+                },
+                obj => panic!("We ran other than an error: {}", obj),
+            }
+
+            // a call in tail position takes the place of its caller.
+            match interp.run("fn() { h() }()").ok().unwrap() {
+                Object::Error(e) => {
+                    let trace: Vec<String> = e.trace.iter().map(|frame| format!("{}", frame)).collect();
+                    assert_eq!(trace, vec!["at h (1:8)"]);
+                },
+                obj => panic!("We ran other than an error: {}", obj),
+            }
+        }
+    }
+
+    #[test]
+    fn default_depth() {
+        // both backends make eval::DEFAULT_MAX_DEPTH calls, and no more, on
+        // the default stack of a test thread.
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            let input = "let f = fn(n) {
+                             let i = 0;
+                             while (i < 1) {
+                                 i += 1;
+                                 for (x in [1]) { if (n > 0) { return [1][0] + f(n - 1) * 1; } }
+                             }
+                             0
+                         };
+                         f(5000)";
+            match interp.run(input).ok().unwrap() {
+                Object::Error(e) => {
+                    assert_eq!(e.kind, ErrorKind::StackOverflow);
+                    assert_eq!(e.msg, "more than 1000 nested calls");
+                },
+                obj => panic!("We ran other than an error: {}", obj),
+            }
+
+            let evaled = interp.run("f(999)");
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "999");
+
+            let evaled = interp.run("let g = fn(n) { if (n == 0) { 0 } else { 1 + g(n - 1) } }; g(999)");
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "999");
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        fn nested(depth: usize) -> Vec<String> {
+            return vec![
+                format!("{}1{}", "(".repeat(depth - 1), ")".repeat(depth - 1)),
+                format!("{}1{}[0]", "[".repeat(depth - 2), "]".repeat(depth - 2)),
+                format!("{}1", "-".repeat(depth - 1)),
+                format!("let f = fn() {{ f }}; f{}", "()".repeat(depth - 1)),
+                format!("{}1{}", "if (true) { ".repeat(depth / 2 - 1), " }".repeat(depth / 2 - 1)),
+                format!("{}1{}", "fn() { ".repeat(depth / 2 - 1), " }()".repeat(depth / 2 - 1)),
+            ];
+        }
+
+        // the default stack of a test thread holds the deepest input.
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            for input in nested(parser::MAX_NESTING).iter() {
+                match interp.run(input) {
+                    Ok(Object::Error(e)) => panic!("We failed to run {}: {}", input, e.msg),
+                    Ok(_) => (),
+                    Err(errors) => panic!("We failed to parse {}: {}", input, errors[0]),
+                }
+            }
+
+            // a chain of operators is not nested, however long.
+            let evaled = interp.run(&format!("1{}", " + 1".repeat(10000)));
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "10001");
+            let evaled = interp.run(&format!("len(\"\"{})", " + \"ab\"".repeat(10000)));
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "20000");
+            let evaled = interp.run(&format!("let f = fn() {{ true{} }}; f()", " && 1 < 2".repeat(2000)));
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "true");
+
+            // nor is a chain of `else if`.
+            let branches: String = (0..2000).map(|i| format!(" else if (n == {}) {{ {} }}", i, i * 2)).collect();
+            let evaled = interp.run(&format!("let f = fn(n) {{ if (n < 0) {{ 0 }}{} }}; f(1999)", branches));
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "3998");
+        }
+
+        let mut inputs = nested(parser::MAX_NESTING + 2);
+        inputs.push("(".repeat(200000));
+        let mut interp = new();
+        for input in inputs.iter() {
+            match interp.run(input) {
+                Err(errors) => assert_eq!(errors[0].kind, parser::ParseErrorKind::TooDeep),
+                Ok(obj) => panic!("We parsed {} without errors: {}", input, obj),
+            }
+        }
+    }
+
+    #[test]
+    fn resource_limits() {
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            interp.set_limits(eval::Limits {
+                steps: Some(100000),
+                timeout: Some(std::time::Duration::from_secs(10)),
+                max_len: Some(1000),
+            });
+
+            let evaled = interp.run("let a = []; for (i in 0..2000) { a = push(a, i); }");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:38: resource limit exceeded: ARRAY larger than 1000"
+            );
+
+            // nesting an array in itself doubles its size each time.
+            let evaled = interp.run("let b = [1]; let i = 0; while (i < 40) { b = [b, b]; i += 1; }");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:46: resource limit exceeded: ARRAY larger than 1000"
+            );
+
+            let evaled = interp.run("let f = fn(n) { f(n + 1) }; f(0)");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:17: resource limit exceeded: more than 100000 steps"
+            );
+
+            // a loop that evaluates nothing that can fail still says where it
+            // ran out.
+            let evaled = format!("{}", interp.run("while (true) { }").ok().unwrap());
+            assert!(evaled.starts_with("ERROR: 1:"), "{}", evaled);
+            assert!(evaled.ends_with("more than 100000 steps"), "{}", evaled);
+
+            // every run has the whole budget.
+            for _ in 0..3 {
+                let evaled = interp.run("let n = 0; while (n < 1000) { n += 1; } len(a)");
+                assert_eq!(format!("{}", evaled.ok().unwrap()), "1000");
+            }
+        }
+    }
+
+    #[test]
+    fn define_global() {
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            interp.define("answer", Object::Int(Int { val: 42 }));
+            let evaled = interp.run("let f = fn() { answer }; f() + 1");
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "43");
+        }
+    }
+
+    #[test]
+    fn bindings_and_reset() {
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            interp.register("answer", Some(0), |_| Object::Int(Int { val: 42 }));
+            interp.define("host", Object::Int(Int { val: 1 }));
+            assert!(interp.run("let b = \"x\"; let a = answer(); let host = 2; c;").is_ok());
+
+            let bindings: Vec<String> = interp.bindings().iter()
+                .map(|(name, val)| format!("{} = {}", name, val))
+                .collect();
+            assert_eq!(bindings, vec!["a = 42", "b = x", "host = 2"]);
+
+            interp.reset();
+            let bindings: Vec<String> = interp.bindings().iter()
+                .map(|(name, val)| format!("{} = {}", name, val))
+                .collect();
+            assert_eq!(bindings, vec!["host = 1"]);
+            let evaled = interp.run("a");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:1: identifier not found: a"
+            );
+            let evaled = interp.run("answer()");
+            assert_eq!(format!("{}", evaled.ok().unwrap()), "42");
+        }
+    }
+
+    #[test]
+    fn interrupt_run() {
+        for backend in [Backend::Eval, Backend::Vm].iter() {
+            let mut interp = with_backend(*backend);
+            let flag = interp.interrupt_flag();
+            interp.register("stop", Some(0), move |_| {
+                flag.store(true, Ordering::Relaxed);
+                Object::Null(Null {})
+            });
+
+            let input = "let loop = fn(n) { if (n == 0) { stop() } loop(n + 1) }; loop(0)";
+            let evaled = interp.run(input);
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:43: interrupted: the program was stopped"
+            );
+
+            let evaled = interp.run("loop(-3)");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:43: interrupted: the program was stopped"
+            );
+            assert!(interp.run("").is_ok());
+
+            let evaled = interp.run("stop(); while (true) { }");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:9: interrupted: the program was stopped"
+            );
+
+            let evaled = interp.run("stop(); for (i in 0..1000000) { continue; }");
+            assert_eq!(
+                format!("{}", evaled.ok().unwrap()),
+                "ERROR: 1:9: interrupted: the program was stopped"
+            );
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use crate::token::{
     Token,
     Type,
+    Pos,
+    Span,
 };
 
 pub struct Lexer {
@@ -8,10 +10,26 @@ pub struct Lexer {
     pos: usize,
     next_pos: usize,
     ch: char,
+    // source position of self.ch
+    line: usize,
+    col: usize,
+    offset: usize,
 }
 
 impl Lexer {
     fn read_char(&mut self) {
+        // step the source position over the char we are leaving.
+        if self.next_pos > 0 && self.pos < self.input.len() {
+            let prev = self.input[self.pos];
+            self.offset += prev.len_utf8();
+            if prev == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
+
         if self.next_pos >= self.input.len() {
             self.ch = '\0';
         } else {
@@ -24,6 +42,13 @@ impl Lexer {
     pub fn next_token(&mut self) -> Token {
         self.skip_space();
 
+        let start = self.cur_pos();
+        let mut tok = self.read_token();
+        tok.span = Span { start: start, end: self.cur_pos() };
+        tok
+    }
+
+    fn read_token(&mut self) -> Token {
         let tok: Token = match self.ch {
             '=' => {
                 if self.peek_char() == '=' {
@@ -33,12 +58,42 @@ impl Lexer {
                     new_token(Type::Assign, "=")
                 }
             },
+            '&' => {
+                if self.peek_char() == '&' {
+                    self.read_char();
+                    new_token(Type::And, "&&")
+                } else {
+                    new_token(Type::BitAnd, "&")
+                }
+            },
+            '|' => {
+                if self.peek_char() == '|' {
+                    self.read_char();
+                    new_token(Type::Or, "||")
+                } else {
+                    new_token(Type::BitOr, "|")
+                }
+            },
             ';' => new_token(Type::Semicolon, ";"),
+            ':' => new_token(Type::Colon, ":"),
+            '.' => {
+                if self.peek_char() == '.' {
+                    self.read_char();
+                    if self.peek_char() == '.' {
+                        self.read_char();
+                        new_token(Type::Ellipsis, "...")
+                    } else {
+                        new_token(Type::DotDot, "..")
+                    }
+                } else {
+                    new_token(Type::Illegal, ".")
+                }
+            },
             '(' => new_token(Type::Lparen, "("),
             ')' => new_token(Type::Rparen, ")"),
             ',' => new_token(Type::Comma, ","),
-            '+' => new_token(Type::Plus, "+"),
-            '-' => new_token(Type::Minus, "-"),
+            '+' => self.with_assign(Type::Plus, Type::PlusAssign),
+            '-' => self.with_assign(Type::Minus, Type::MinusAssign),
             '!' => {
                 if self.peek_char() == '=' {
                     self.read_char();
@@ -47,12 +102,45 @@ impl Lexer {
                     new_token(Type::Bang, "!")
                 }
             },
-            '/' => new_token(Type::Slash, "/"),
-            '*' => new_token(Type::Asterisk, "*"),
-            '<' => new_token(Type::Lt, "<"),
-            '>' => new_token(Type::Gt, ">"),
+            '/' => self.with_assign(Type::Slash, Type::SlashAssign),
+            '*' => {
+                if self.peek_char() == '*' {
+                    self.read_char();
+                    new_token(Type::Power, "**")
+                } else {
+                    self.with_assign(Type::Asterisk, Type::AsteriskAssign)
+                }
+            },
+            '%' => new_token(Type::Percent, "%"),
+            '^' => new_token(Type::BitXor, "^"),
+            '~' => new_token(Type::BitNot, "~"),
+            '<' => match self.peek_char() {
+                '=' => {
+                    self.read_char();
+                    new_token(Type::Le, "<=")
+                },
+                '<' => {
+                    self.read_char();
+                    new_token(Type::Shl, "<<")
+                },
+                _ => new_token(Type::Lt, "<"),
+            },
+            '>' => match self.peek_char() {
+                '=' => {
+                    self.read_char();
+                    new_token(Type::Ge, ">=")
+                },
+                '>' => {
+                    self.read_char();
+                    new_token(Type::Shr, ">>")
+                },
+                _ => new_token(Type::Gt, ">"),
+            },
+            '"' => return self.read_string(),
             '{' => new_token(Type::Lbrace, "{"),
             '}' => new_token(Type::Rbrace, "}"),
+            '[' => new_token(Type::Lbracket, "["),
+            ']' => new_token(Type::Rbracket, "]"),
             '\0' => new_token(Type::Eof, ""),
             _ => {
                 if is_letter(self.ch) {
@@ -72,6 +160,20 @@ impl Lexer {
         tok
     }
 
+    // an operator, or its compound assignment form if `=` follows it.
+    fn with_assign(&mut self, op: Type, assign: Type) -> Token {
+        if self.peek_char() == '=' {
+            let lit = format!("{}=", self.ch);
+            self.read_char();
+            return new_token(assign, &lit);
+        }
+        new_token(op, &self.ch.to_string())
+    }
+
+    fn cur_pos(&self) -> Pos {
+        return Pos { line: self.line, col: self.col, offset: self.offset };
+    }
+
     fn skip_space(&mut self) {
         while is_space(self.ch) {
             self.read_char();
@@ -94,6 +196,70 @@ impl Lexer {
         self.extract_token(from, self.pos)
     }
 
+    // read a string literal starting at the opening quote, decoding escape
+    // sequences. An unterminated literal or an unknown escape makes the
+    // whole literal an Illegal token.
+    fn read_string(&mut self) -> Token {
+        let from = self.pos;
+        let mut s = String::new();
+        let mut valid = true;
+        loop {
+            self.read_char();
+            if self.pos >= self.input.len() {
+                let lit = self.extract_token(from, self.input.len());
+                return new_token(Type::Illegal, &lit);
+            }
+            match self.ch {
+                '"' => break,
+                '\\' => {
+                    self.read_char();
+                    match self.read_escape() {
+                        Some(c) => s.push(c),
+                        None => valid = false,
+                    }
+                },
+                c => s.push(c),
+            }
+        }
+
+        // skip closing quote
+        self.read_char();
+        if !valid {
+            let lit = self.extract_token(from, self.pos);
+            return new_token(Type::Illegal, &lit);
+        }
+        new_token(Type::String, &s)
+    }
+
+    // decode the escape sequence whose first char after the backslash is
+    // self.ch.
+    fn read_escape(&mut self) -> Option<char> {
+        return match self.ch {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            '"' => Some('"'),
+            '\\' => Some('\\'),
+            'u' => {
+                if self.peek_char() != '{' {
+                    return None;
+                }
+                self.read_char();
+                let mut hex = String::new();
+                while self.peek_char().is_ascii_hexdigit() {
+                    self.read_char();
+                    hex.push(self.ch);
+                }
+                if self.peek_char() != '}' {
+                    return None;
+                }
+                self.read_char();
+                u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+            },
+            _ => None,
+        };
+    }
+
     // extract token by indexing [i..j) from self.input.
     fn extract_token(&mut self, i: usize, j: usize) -> String {
         let token: String = self.input[i..j].iter().collect();
@@ -111,13 +277,15 @@ impl Lexer {
 
 pub fn new(input: &str) -> Lexer {
     let chars = input.chars().collect::<Vec<char>>();
-    let first_char = chars[0];
 
     let mut l = Lexer {
         input: chars,
         pos: 0,
         next_pos: 0,
-        ch: first_char,
+        ch: '\0',
+        line: 1,
+        col: 1,
+        offset: 0,
     };
     l.read_char();
     l
@@ -127,37 +295,47 @@ fn new_token(t: Type, lit: &str) -> Token {
     return Token {
         t: t,
         literal: String::from(lit),
+        span: Span::default(),
     };
 }
 
-fn look_up_ident(ident: &String) -> Type {
-    if ident == "fn" {
-        return Type::Function;
-    } else if ident == "let" {
-        return Type::Let;
-    } else if ident == "true" {
-        return Type::True;
-    } else if ident == "false" {
-        return Type::False;
-    } else if ident == "if" {
-        return Type::If;
-    } else if ident == "else" {
-        return Type::Else;
-    } else if ident == "return" {
-        return Type::Return;
-    } else {
-        return Type::Ident;
+// the reserved words and the token type each one lexes as.
+const KEYWORDS: [(&str, Type); 13] = [
+    ("fn", Type::Function),
+    ("let", Type::Let),
+    ("true", Type::True),
+    ("false", Type::False),
+    ("if", Type::If),
+    ("else", Type::Else),
+    ("return", Type::Return),
+    ("while", Type::While),
+    ("break", Type::Break),
+    ("continue", Type::Continue),
+    ("const", Type::Const),
+    ("for", Type::For),
+    ("in", Type::In),
+];
+
+pub fn look_up_ident(ident: &str) -> Type {
+    for (keyword, t) in KEYWORDS.iter() {
+        if *keyword == ident {
+            return t.clone();
+        }
     }
+    return Type::Ident;
+}
+
+// every word look_up_ident reserves.
+pub fn keywords() -> Vec<&'static str> {
+    return KEYWORDS.iter().map(|(keyword, _)| *keyword).collect();
 }
 
 fn is_letter(c: char) -> bool {
-    return 'a' <= c && c <= 'z' ||
-           'A' <= c && c <= 'Z' ||
-           c == '_';
+    return c.is_ascii_alphabetic() || c == '_';
 }
 
 fn is_digit(c: char) -> bool {
-    return '0' <= c && c <= '9';
+    return c.is_ascii_digit();
 }
 
 fn is_space(c: char) -> bool {
@@ -169,9 +347,18 @@ fn is_space(c: char) -> bool {
 mod test {
     use super::*;
 
+    #[test]
+    fn tokenize_empty() {
+        for input in ["", " \n\t"].iter() {
+            let mut l = new(input);
+            assert!(l.next_token().t == Type::Eof);
+            assert!(l.next_token().t == Type::Eof);
+        }
+    }
+
     #[test]
     fn tokenize1() {
-        let input = "=+(){},;";
+        let input = "=+(){},;[]:";
 
         let expects = [
             new_token(Type::Assign, "="),
@@ -182,6 +369,9 @@ mod test {
             new_token(Type::Rbrace, "}"),
             new_token(Type::Comma, ","),
             new_token(Type::Semicolon, ";"),
+            new_token(Type::Lbracket, "["),
+            new_token(Type::Rbracket, "]"),
+            new_token(Type::Colon, ":"),
             new_token(Type::Eof, ""),
         ];
 
@@ -300,4 +490,116 @@ mod test {
             assert_eq!(tok.literal, expect.literal);
         }
     }
+
+    #[test]
+    fn tokenize_operators() {
+        let input = "const x = 1; x += 2; x-=3 *= /=/ * while break continue for (i in 0..n) . ...a && b || c
+            <= >= < > << >> % ** * & | ^ ~";
+
+        let expects = [
+            new_token(Type::Const, "const"),
+            new_token(Type::Ident, "x"),
+            new_token(Type::Assign, "="),
+            new_token(Type::Int, "1"),
+            new_token(Type::Semicolon, ";"),
+            new_token(Type::Ident, "x"),
+            new_token(Type::PlusAssign, "+="),
+            new_token(Type::Int, "2"),
+            new_token(Type::Semicolon, ";"),
+            new_token(Type::Ident, "x"),
+            new_token(Type::MinusAssign, "-="),
+            new_token(Type::Int, "3"),
+            new_token(Type::AsteriskAssign, "*="),
+            new_token(Type::SlashAssign, "/="),
+            new_token(Type::Slash, "/"),
+            new_token(Type::Asterisk, "*"),
+            new_token(Type::While, "while"),
+            new_token(Type::Break, "break"),
+            new_token(Type::Continue, "continue"),
+            new_token(Type::For, "for"),
+            new_token(Type::Lparen, "("),
+            new_token(Type::Ident, "i"),
+            new_token(Type::In, "in"),
+            new_token(Type::Int, "0"),
+            new_token(Type::DotDot, ".."),
+            new_token(Type::Ident, "n"),
+            new_token(Type::Rparen, ")"),
+            new_token(Type::Illegal, "."),
+            new_token(Type::Ellipsis, "..."),
+            new_token(Type::Ident, "a"),
+            new_token(Type::And, "&&"),
+            new_token(Type::Ident, "b"),
+            new_token(Type::Or, "||"),
+            new_token(Type::Ident, "c"),
+            new_token(Type::Le, "<="),
+            new_token(Type::Ge, ">="),
+            new_token(Type::Lt, "<"),
+            new_token(Type::Gt, ">"),
+            new_token(Type::Shl, "<<"),
+            new_token(Type::Shr, ">>"),
+            new_token(Type::Percent, "%"),
+            new_token(Type::Power, "**"),
+            new_token(Type::Asterisk, "*"),
+            new_token(Type::BitAnd, "&"),
+            new_token(Type::BitOr, "|"),
+            new_token(Type::BitXor, "^"),
+            new_token(Type::BitNot, "~"),
+            new_token(Type::Eof, ""),
+        ];
+
+        let mut l = new(input);
+        for expect in expects.iter() {
+            let tok = l.next_token();
+            assert_eq!(tok.t, expect.t);
+            assert_eq!(tok.literal, expect.literal);
+        }
+    }
+
+    #[test]
+    fn tokenize_strings() {
+        let input = r#""foobar" "foo bar" "a\nb\t\"c\"\\" "\u{48}\u{1F600}" "" "bad\q" "open"#;
+
+        let expects = [
+            new_token(Type::String, "foobar"),
+            new_token(Type::String, "foo bar"),
+            new_token(Type::String, "a\nb\t\"c\"\\"),
+            new_token(Type::String, "H\u{1F600}"),
+            new_token(Type::String, ""),
+            new_token(Type::Illegal, "\"bad\\q\""),
+            new_token(Type::Illegal, "\"open"),
+            new_token(Type::Eof, ""),
+        ];
+
+        let mut l = new(input);
+        for expect in expects.iter() {
+            let tok = l.next_token();
+            assert_eq!(tok.t, expect.t);
+            assert_eq!(tok.literal, expect.literal);
+        }
+    }
+
+    #[test]
+    fn token_spans() {
+        let input = "let x = 10;\n  é + foo";
+
+        // (line, col, offset) of the start and end of each token
+        let expects = [
+            ((1, 1, 0), (1, 4, 3)),
+            ((1, 5, 4), (1, 6, 5)),
+            ((1, 7, 6), (1, 8, 7)),
+            ((1, 9, 8), (1, 11, 10)),
+            ((1, 11, 10), (1, 12, 11)),
+            ((2, 3, 14), (2, 4, 16)),
+            ((2, 5, 17), (2, 6, 18)),
+            ((2, 7, 19), (2, 10, 22)),
+            ((2, 10, 22), (2, 10, 22)),
+        ];
+
+        let mut l = new(input);
+        for (start, end) in expects.iter() {
+            let tok = l.next_token();
+            assert_eq!(tok.span.start, Pos { line: start.0, col: start.1, offset: start.2 });
+            assert_eq!(tok.span.end, Pos { line: end.0, col: end.1, offset: end.2 });
+        }
+    }
 }
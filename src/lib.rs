@@ -5,3 +5,8 @@ pub mod parser;
 pub mod object;
 pub mod eval;
 pub mod env;
+pub mod builtins;
+pub mod code;
+pub mod compiler;
+pub mod vm;
+pub mod interp;
@@ -1,30 +1,434 @@
-use std::io::Write;
-mod token;
-mod lexer;
-mod parser;
-mod ast;
-mod eval;
-mod object;
-mod env;
+mod editor;
+
+use std::io::{
+    IsTerminal,
+    Read,
+};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    OnceLock,
+};
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::time::Duration;
+use monkey_by_rust::{
+    ast,
+    eval,
+    eval::Limits,
+    eval::Typing,
+    interp,
+    interp::Backend,
+    interp::Interp,
+    lexer,
+    parser,
+    token,
+    object::Error,
+    object::Object,
+    object::Str,
+    object::Array,
+};
+
+const USAGE: &str = "usage: monkey_by_rust [--vm] [--legacy] [--max-depth n] \
+    [--max-steps n] [--timeout ms] [--max-len n] [script.mk | -e code | -] [args...]";
+
+// what the binary was asked to run.
+#[derive(PartialEq, Debug)]
+enum Mode {
+    Repl,
+    Script(String),
+    Code(String),
+    Stdin,
+}
+
+#[derive(PartialEq, Debug)]
+struct Options {
+    backend: Backend,
+    typing: Typing,
+    max_depth: usize,
+    limits: Limits,
+    mode: Mode,
+    // the arguments after the program, visible to it as `args`.
+    args: Vec<String>,
+}
 
 fn main() {
-    let mut env = env::new();
+    let opts = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("{}\n{}", msg, USAGE);
+            process::exit(2);
+        },
+    };
+
+    let mut interp = interp::with_backend(opts.backend);
+    interp.set_typing(opts.typing);
+    interp.set_max_depth(opts.max_depth);
+    interp.set_limits(opts.limits);
+    let args = opts.args.into_iter()
+        .map(|arg| Object::Str(Str { val: arg }))
+        .collect();
+    interp.define("args", Object::Array(Array { elems: Rc::new(args) }));
+
+    let input = match opts.mode {
+        Mode::Repl => return repl(interp),
+        Mode::Code(code) => code,
+        Mode::Script(path) => match std::fs::read_to_string(&path) {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("cannot read {}: {}", path, e);
+                process::exit(1);
+            },
+        },
+        Mode::Stdin => {
+            let mut input = String::new();
+            if let Err(e) = std::io::stdin().read_to_string(&mut input) {
+                eprintln!("cannot read standard input: {}", e);
+                process::exit(1);
+            }
+            input
+        },
+    };
+    if !run(&mut interp, &input) {
+        process::exit(1);
+    }
+}
+
+// when no program is given, an interactive session is started if standard
+// input is a terminal, and the program is read from it otherwise.
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut opts = Options {
+        backend: Backend::Eval,
+        typing: Typing::Strict,
+        max_depth: eval::DEFAULT_MAX_DEPTH,
+        limits: Limits::default(),
+        mode: Mode::Repl,
+        args: vec![],
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--vm" => opts.backend = Backend::Vm,
+            "--legacy" => opts.typing = Typing::Legacy,
+            "--max-depth" => opts.max_depth = number(&arg, args.next())?,
+            "--max-steps" => opts.limits.steps = Some(number(&arg, args.next())?),
+            "--timeout" => {
+                let ms = number(&arg, args.next())?;
+                opts.limits.timeout = Some(Duration::from_millis(ms));
+            },
+            "--max-len" => opts.limits.max_len = Some(number(&arg, args.next())?),
+            "-e" => match args.next() {
+                Some(code) => {
+                    opts.mode = Mode::Code(code);
+                    break;
+                },
+                None => return Err(String::from("-e requires an argument")),
+            },
+            "-" => {
+                opts.mode = Mode::Stdin;
+                break;
+            },
+            flag if flag.starts_with('-') => {
+                return Err(format!("unknown option {}", flag));
+            },
+            _ => {
+                opts.mode = Mode::Script(arg);
+                break;
+            },
+        }
+    }
+    opts.args = args.collect();
+
+    if opts.mode == Mode::Repl && !std::io::stdin().is_terminal() {
+        opts.mode = Mode::Stdin;
+    }
+    Ok(opts)
+}
+
+// the number given after flag.
+fn number<T: FromStr>(flag: &str, arg: Option<String>) -> Result<T, String> {
+    return arg.and_then(|n| n.parse().ok())
+        .ok_or_else(|| format!("{} requires a number", flag));
+}
+
+// run a whole program, reporting errors on stderr. Returns whether it ran
+// without error.
+fn run(interp: &mut Interp, input: &str) -> bool {
+    return match interp.run(input) {
+        Ok(Object::Error(e)) => {
+            print_error(&e);
+            false
+        },
+        Ok(_) => true,
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("parse error: {}", e);
+            }
+            false
+        },
+    };
+}
+
+// read a program, which may span several lines, and run it. A blank line
+// ends the program early, so a mistake can be abandoned. Lines starting
+// with `:` are commands to the REPL itself.
+fn repl(mut interp: Interp) {
+    catch_interrupts(interp.interrupt_flag());
+    let mut editor = editor::new(history_path());
+    while let Some(input) = read_program(&mut editor, &interp) {
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        if input.starts_with(':') {
+            if !run_command(&mut interp, input) {
+                break;
+            }
+            continue;
+        }
+        print_result(interp.run(input));
+    }
+}
+
+fn print_result(result: Result<Object, Vec<parser::ParseError>>) {
+    match result {
+        Ok(Object::Error(e)) => print_error(&e),
+        Ok(evaled) => println!("{}", evaled),
+        Err(errors) => {
+            for e in errors.iter() {
+                eprintln!("parse error: {}", e);
+            }
+        },
+    }
+}
 
-    let prompt = ">> ";
+// a runtime error and the calls that led to it. Runs of the same line, as
+// deep recursion makes, are shown once.
+fn print_error(e: &Error) {
+    eprintln!("{}", e);
+    let lines: Vec<String> = e.trace.iter().map(|frame| format!("{}", frame)).collect();
+    let mut i = 0;
+    while i < lines.len() {
+        let run = lines[i..].iter().take_while(|line| **line == lines[i]).count();
+        eprintln!("    {}", lines[i]);
+        if run > 1 {
+            eprintln!("    ... repeated {} more times", run - 1);
+        }
+        i += run;
+    }
+}
+
+// run a REPL command. Returns false when the session should end.
+fn run_command(interp: &mut Interp, line: &str) -> bool {
+    let (cmd, arg) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+
+    match cmd {
+        ":tokens" if !arg.is_empty() => {
+            let mut l = lexer::new(arg);
+            loop {
+                let tok = l.next_token();
+                if tok.t == token::Type::Eof {
+                    break;
+                }
+                println!("{} {:?} '{}'", tok.span, tok.t, tok.literal);
+            }
+        },
+        ":ast" if !arg.is_empty() => {
+            let mut l = lexer::new(arg);
+            let mut p = parser::new(&mut l);
+            match p.parse_program() {
+                Ok(program) => print!("{}", ast::dump(&program)),
+                Err(errors) => print_result(Err(errors)),
+            }
+        },
+        ":tokens" | ":ast" => eprintln!("usage: {} <code>", cmd),
+        ":env" => {
+            for (name, val) in interp.bindings() {
+                println!("{} = {}", name, val);
+            }
+        },
+        ":reset" => interp.reset(),
+        ":load" if !arg.is_empty() => match std::fs::read_to_string(arg) {
+            Ok(input) => print_result(interp.run(&input)),
+            Err(e) => eprintln!("cannot read {}: {}", arg, e),
+        },
+        ":load" => eprintln!("usage: :load <file>"),
+        ":quit" => return false,
+        _ => eprintln!(
+            "unknown command {}; try :tokens, :ast, :env, :reset, :load or :quit",
+            cmd,
+        ),
+    }
+    true
+}
+
+// read lines until they form a complete program, prompting with `..` after
+// the first. Ctrl-C abandons the program. Returns None at the end of input.
+fn read_program(editor: &mut editor::Editor, interp: &Interp) -> Option<String> {
+    let mut words: Vec<String> = lexer::keywords().into_iter().map(String::from).collect();
+    words.extend(interp.bindings().into_iter().map(|(name, _)| name));
+
+    let mut input = String::new();
     loop {
-        print!("{}", prompt);
-        std::io::stdout().flush().unwrap();
+        let prompt = if input.is_empty() { ">> " } else { ".. " };
+        let line = match editor.read_line(prompt, &words) {
+            Ok(editor::Input::Line(line)) => line,
+            Ok(editor::Input::Interrupted) => return Some(String::new()),
+            Ok(editor::Input::Eof) | Err(_) if input.is_empty() => return None,
+            Ok(editor::Input::Eof) | Err(_) => return Some(input),
+        };
+
+        if line.trim().is_empty() {
+            return Some(input);
+        }
+        input.push_str(&line);
+        input.push('\n');
+        if input.trim_start().starts_with(':') || !parser::is_incomplete(&input) {
+            return Some(input);
+        }
+    }
+}
+
+// the flag SIGINT sets. A signal handler can do little else safely.
+static INTERRUPT: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+// make Ctrl-C stop the running program rather than the process. While a
+// line is being edited the terminal is in raw mode and Ctrl-C arrives as a
+// key instead.
+#[cfg(unix)]
+fn catch_interrupts(flag: Arc<AtomicBool>) {
+    use std::os::raw::c_int;
+
+    const SIGINT: c_int = 2;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+
+    extern "C" fn handle(_: c_int) {
+        if let Some(flag) = INTERRUPT.get() {
+            flag.store(true, Ordering::Relaxed);
+        }
+    }
+
+    let _ = INTERRUPT.set(flag);
+    unsafe {
+        signal(SIGINT, handle);
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_interrupts(flag: Arc<AtomicBool>) {
+    let _ = INTERRUPT.set(flag);
+}
+
+// where REPL history is kept between sessions.
+fn history_path() -> Option<PathBuf> {
+    return std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".monkey_by_rust_history"));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).ok();
+    #[test]
+    fn parse_cli_args() {
+        struct Test<'a> {
+            input: Vec<&'a str>,
+            expected: Options,
+        }
 
-        let mut l = lexer::new(input.trim());
-        let mut p = parser::new(&mut l);
+        let tests: Vec<Test> = vec![
+            Test {
+                input: vec!["script.mk"],
+                expected: Options {
+                    backend: Backend::Eval,
+                    typing: Typing::Strict,
+                    max_depth: eval::DEFAULT_MAX_DEPTH,
+                    limits: Limits::default(),
+                    mode: Mode::Script(String::from("script.mk")),
+                    args: vec![],
+                },
+            },
+            Test {
+                input: vec!["--vm", "script.mk", "a", "--vm", "-e"],
+                expected: Options {
+                    backend: Backend::Vm,
+                    typing: Typing::Strict,
+                    max_depth: eval::DEFAULT_MAX_DEPTH,
+                    limits: Limits::default(),
+                    mode: Mode::Script(String::from("script.mk")),
+                    args: vec![String::from("a"), String::from("--vm"), String::from("-e")],
+                },
+            },
+            Test {
+                input: vec!["--legacy", "-e", "puts(args)", "x"],
+                expected: Options {
+                    backend: Backend::Eval,
+                    typing: Typing::Legacy,
+                    max_depth: eval::DEFAULT_MAX_DEPTH,
+                    limits: Limits::default(),
+                    mode: Mode::Code(String::from("puts(args)")),
+                    args: vec![String::from("x")],
+                },
+            },
+            Test {
+                input: vec!["--max-depth", "50", "--vm", "-e", "x"],
+                expected: Options {
+                    backend: Backend::Vm,
+                    typing: Typing::Strict,
+                    max_depth: 50,
+                    limits: Limits::default(),
+                    mode: Mode::Code(String::from("x")),
+                    args: vec![],
+                },
+            },
+            Test {
+                input: vec!["--max-steps", "1000", "--timeout", "250", "--max-len", "64", "-"],
+                expected: Options {
+                    backend: Backend::Eval,
+                    typing: Typing::Strict,
+                    max_depth: eval::DEFAULT_MAX_DEPTH,
+                    limits: Limits {
+                        steps: Some(1000),
+                        timeout: Some(Duration::from_millis(250)),
+                        max_len: Some(64),
+                    },
+                    mode: Mode::Stdin,
+                    args: vec![],
+                },
+            },
+            Test {
+                input: vec!["-", "x"],
+                expected: Options {
+                    backend: Backend::Eval,
+                    typing: Typing::Strict,
+                    max_depth: eval::DEFAULT_MAX_DEPTH,
+                    limits: Limits::default(),
+                    mode: Mode::Stdin,
+                    args: vec![String::from("x")],
+                },
+            },
+        ];
 
-        let program = p.parse_program();
-        let root_node = ast::Node::Program(program);
+        for test in tests.into_iter() {
+            let args = test.input.iter().map(|s| String::from(*s)).collect();
+            assert_eq!(parse_args(args), Ok(test.expected));
+        }
 
-        let evaled = eval::eval(root_node, &mut env);
-        println!("{}", evaled);
+        assert!(parse_args(vec![String::from("-e")]).is_err());
+        assert!(parse_args(vec![String::from("--bogus")]).is_err());
+        assert!(parse_args(vec![String::from("--max-depth"), String::from("x")]).is_err());
+        assert!(parse_args(vec![String::from("--timeout")]).is_err());
     }
 }
@@ -1,14 +1,33 @@
+use std::cell::RefCell;
+use std::collections::{
+    BTreeMap,
+    HashSet,
+};
 use std::fmt;
+use std::rc::{
+    Rc,
+    Weak,
+};
 use crate::ast;
 use crate::env;
+use crate::token;
 
 #[derive(Clone)]
 pub enum Object {
     Int(Int),
     Bool(Bool),
+    Str(Str),
     Null(Null),
     Return(Return),
+    Break(Break),
+    Continue(Continue),
     Func(Func),
+    Closure(Closure),
+    Array(Array),
+    Hash(Hash),
+    Range(Range),
+    Builtin(Builtin),
+    Error(Error),
 }
 
 #[derive(Clone)]
@@ -21,6 +40,11 @@ pub struct Bool {
     pub val: bool,
 }
 
+#[derive(Clone)]
+pub struct Str {
+    pub val: String,
+}
+
 #[derive(Clone)]
 pub struct Null {}
 
@@ -29,35 +53,384 @@ pub struct Return {
     pub val: Box<Object>,
 }
 
+// the signals `break` and `continue` send to the enclosing loop.
+#[derive(Clone)]
+pub struct Break {}
+
+#[derive(Clone)]
+pub struct Continue {}
+
+// a function value. Copies of it share the definition, and each holds the
+// scope the function was defined in to keep it alive, except a copy bound
+// in that scope itself: the scope already holds that copy, and a reference
+// back would be a cycle that Rc never frees. Reading the binding gives a
+// copy that holds the scope again.
 #[derive(Clone)]
 pub struct Func {
-    pub params: Vec<ast::Ident>,
+    pub def: Rc<FuncDef>,
+    pub env: Option<Rc<RefCell<env::Env>>>,
+}
+
+pub struct FuncDef {
+    pub name: Option<String>,
+    pub params: Vec<ast::Param>,
+    pub rest: Option<ast::Ident>,
     pub body: ast::Block,
-    pub env: env::Env,
+    pub env: Weak<RefCell<env::Env>>,
+}
+
+impl Func {
+    // the scope the function was defined in.
+    pub fn captured(&self) -> Rc<RefCell<env::Env>> {
+        return self.def.env.upgrade().expect("function outlived its scope");
+    }
+}
+
+// a function compiled to bytecode by the compiler module.
+#[derive(PartialEq)]
+pub struct CompiledFunc {
+    pub instructions: Vec<u8>,
+    // the source location of the instructions, by the offset each starts
+    // at.
+    pub spans: Vec<(usize, token::Span)>,
+    pub name: Option<String>,
+    // the parameters, not counting a rest parameter, and how many of them
+    // have no default.
+    pub num_params: usize,
+    pub num_required: usize,
+    // whether the slot after the parameters collects the extra arguments.
+    pub rest: bool,
+    // the source text of the parameter list, for printing.
+    pub params: String,
+    // the names of the local slots, parameters first.
+    pub locals: Vec<String>,
+    // the source text of the body, for printing.
+    pub body: String,
+}
+
+impl CompiledFunc {
+    // the location of the instruction at offset ip: the last span recorded
+    // at or before it.
+    pub fn span_at(&self, ip: usize) -> Option<token::Span> {
+        return match self.spans.binary_search_by_key(&ip, |&(at, _)| at) {
+            Ok(i) => Some(self.spans[i].1),
+            Err(0) => None,
+            Err(i) => Some(self.spans[i - 1].1),
+        };
+    }
+}
+
+// a compiled function value. Like Func, it captures the scope it was
+// defined in, and a copy stored in a slot of that scope does not hold it.
+// Top-level functions capture no scope and see only globals.
+#[derive(Clone)]
+pub struct Closure {
+    pub def: Rc<ClosureDef>,
+    pub scope: Option<Rc<Scope>>,
+}
+
+pub struct ClosureDef {
+    pub func: Rc<CompiledFunc>,
+    pub scope: Option<Weak<Scope>>,
+}
+
+impl Closure {
+    // the scope the closure was made in.
+    pub fn captured(&self) -> Option<Rc<Scope>> {
+        return self.def.scope.as_ref()
+            .map(|scope| scope.upgrade().expect("function outlived its scope"));
+    }
+}
+
+// the local slots of one call of a compiled function. A slot is None until
+// its `let` has run.
+pub struct Scope {
+    pub func: Rc<CompiledFunc>,
+    pub slots: RefCell<Vec<Option<Object>>>,
+    // the slots bound with `const`.
+    pub consts: RefCell<HashSet<usize>>,
+    pub outer: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    // the value in slot i. A closure made in this scope gets back its hold
+    // on it.
+    pub fn get(&self, i: usize) -> Option<Object> {
+        return match &self.slots.borrow()[i] {
+            Some(Object::Closure(c)) if c.scope.is_none() => {
+                Some(Object::Closure(Closure { def: Rc::clone(&c.def), scope: c.captured() }))
+            },
+            val => val.clone(),
+        };
+    }
+
+    // store val in slot i, without the hold a closure made in this scope has
+    // on it, so the two do not keep each other alive.
+    pub fn set(&self, i: usize, val: Option<Object>) {
+        let val = match val {
+            Some(Object::Closure(c)) if c.scope.as_ref().is_some_and(|s| std::ptr::eq(Rc::as_ptr(s), self)) => {
+                Some(Object::Closure(Closure { def: c.def, scope: None }))
+            },
+            val => val,
+        };
+        self.slots.borrow_mut()[i] = val;
+    }
+}
+
+// copies of an array share its elements, which are never changed in
+// place: push and the like make a new array.
+#[derive(Clone)]
+pub struct Array {
+    pub elems: Rc<Vec<Object>>,
+}
+
+// the integers from start up to but not including end.
+#[derive(Clone)]
+pub struct Range {
+    pub start: isize,
+    pub end: isize,
+}
+
+// pairs are kept ordered by key so that printing and iteration are
+// deterministic. like an array's elements they are shared between
+// copies and never changed in place.
+#[derive(Clone)]
+pub struct Hash {
+    pub pairs: Rc<BTreeMap<HashKey, HashPair>>,
+}
+
+// the key a hashable object is stored under in a Hash.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
+pub enum HashKey {
+    Int(isize),
+    Bool(bool),
+    Str(String),
+}
+
+// the original key object is kept next to the value for printing.
+#[derive(Clone)]
+pub struct HashPair {
+    pub key: Object,
+    pub val: Object,
+}
+
+pub type BuiltinFn = Rc<dyn Fn(Vec<Object>) -> Object>;
+
+// a function implemented in Rust. An arity of None accepts any number of
+// arguments.
+#[derive(Clone)]
+pub struct Builtin {
+    pub name: String,
+    pub arity: Option<usize>,
+    pub func: BuiltinFn,
+}
+
+#[derive(Clone)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub msg: String,
+    // where in the source the error was raised, if known.
+    pub span: Option<token::Span>,
+    // the calls in progress when it was raised, innermost first.
+    pub trace: Vec<TraceFrame>,
+}
+
+// a call in progress: the function called, by the name `let` gave it, and
+// where it was called from.
+#[derive(Clone)]
+pub struct TraceFrame {
+    pub name: Option<String>,
+    pub span: token::Span,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.name.as_deref().unwrap_or("<anonymous>");
+        write!(f, "at {} ({})", name, self.span)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ErrorKind {
+    TypeMismatch,
+    UnknownOperator,
+    UnknownIdent,
+    NotCallable,
+    WrongArity,
+    NotIndexable,
+    IndexOutOfRange,
+    Unhashable,
+    Interrupted,
+    ConstAssign,
+    NotIterable,
+    NegativeExponent,
+    DivisionByZero,
+    Overflow,
+    StackOverflow,
+    ResourceLimit,
+    ProgramTooLarge,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ErrorKind::TypeMismatch => "type mismatch",
+            ErrorKind::UnknownOperator => "unknown operator",
+            ErrorKind::UnknownIdent => "identifier not found",
+            ErrorKind::NotCallable => "not a function",
+            ErrorKind::WrongArity => "wrong number of arguments",
+            ErrorKind::NotIndexable => "index operator not supported",
+            ErrorKind::IndexOutOfRange => "index out of range",
+            ErrorKind::Unhashable => "unusable as hash key",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::ConstAssign => "assignment to constant",
+            ErrorKind::NotIterable => "not iterable",
+            ErrorKind::NegativeExponent => "negative exponent",
+            ErrorKind::DivisionByZero => "division by zero",
+            ErrorKind::Overflow => "integer overflow",
+            ErrorKind::StackOverflow => "stack overflow",
+            ErrorKind::ResourceLimit => "resource limit exceeded",
+            ErrorKind::ProgramTooLarge => "program too large",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ERROR: ")?;
+        if let Some(span) = self.span {
+            write!(f, "{}: ", span)?;
+        }
+        write!(f, "{}: {}", self.kind, self.msg)
+    }
+}
+
+impl Object {
+    pub fn type_name(&self) -> &'static str {
+        return match self {
+            Object::Int(_) => "INTEGER",
+            Object::Bool(_) => "BOOLEAN",
+            Object::Str(_) => "STRING",
+            Object::Null(_) => "NULL",
+            Object::Return(r) => r.val.type_name(),
+            Object::Break(_) => "BREAK",
+            Object::Continue(_) => "CONTINUE",
+            Object::Func(_) | Object::Closure(_) => "FUNCTION",
+            Object::Array(_) => "ARRAY",
+            Object::Hash(_) => "HASH",
+            Object::Range(_) => "RANGE",
+            Object::Builtin(_) => "BUILTIN",
+            Object::Error(_) => "ERROR",
+        };
+    }
+
+    pub fn hash_key(&self) -> Option<HashKey> {
+        return match self {
+            Object::Int(i) => Some(HashKey::Int(i.val)),
+            Object::Bool(b) => Some(HashKey::Bool(b.val)),
+            Object::Str(s) => Some(HashKey::Str(s.val.clone())),
+            _ => None,
+        };
+    }
+
+    pub fn is_error(&self) -> bool {
+        return matches!(self, Object::Error(_));
+    }
+
+    // what `==` means for values of any types. Values of different types
+    // are never equal. Arrays and hashes are equal when their contents are,
+    // while functions are only equal to themselves.
+    pub fn equals(&self, other: &Object) -> bool {
+        return match (self, other) {
+            (Object::Int(l), Object::Int(r)) => l.val == r.val,
+            (Object::Bool(l), Object::Bool(r)) => l.val == r.val,
+            (Object::Str(l), Object::Str(r)) => l.val == r.val,
+            (Object::Null(_), Object::Null(_)) => true,
+            (Object::Range(l), Object::Range(r)) => l.start == r.start && l.end == r.end,
+            (Object::Array(l), Object::Array(r)) => {
+                l.elems.len() == r.elems.len() &&
+                    l.elems.iter().zip(r.elems.iter()).all(|(l, r)| l.equals(r))
+            },
+            (Object::Hash(l), Object::Hash(r)) => {
+                l.pairs.len() == r.pairs.len() &&
+                    l.pairs.iter().all(|(key, pair)| match r.pairs.get(key) {
+                        Some(other) => pair.val.equals(&other.val),
+                        None => false,
+                    })
+            },
+            (Object::Func(l), Object::Func(r)) => Rc::ptr_eq(&l.def, &r.def),
+            (Object::Closure(l), Object::Closure(r)) => Rc::ptr_eq(&l.def, &r.def),
+            (Object::Builtin(l), Object::Builtin(r)) => Rc::ptr_eq(&l.func, &r.func),
+            _ => false,
+        };
+    }
+
+    // whether the value cuts evaluation short: an error, or a `return`,
+    // `break` or `continue` on its way out to whatever handles it.
+    pub fn is_abrupt(&self) -> bool {
+        return matches!(
+            self,
+            Object::Error(_) | Object::Return(_) | Object::Break(_) | Object::Continue(_)
+        );
+    }
 }
 
 impl fmt::Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         return match self {
-            Object::Int(i) => write!(f, "{}", i.val.to_string()),
-            Object::Bool(b) => write!(f, "{}", b.val.to_string()),
+            Object::Int(i) => write!(f, "{}", i.val),
+            Object::Bool(b) => write!(f, "{}", b.val),
+            Object::Str(s) => write!(f, "{}", s.val),
             Object::Null(_) => write!(f, "null"),
             Object::Return(r) => write!(f, "{}", r.val),
+            Object::Break(_) => write!(f, "break"),
+            Object::Continue(_) => write!(f, "continue"),
             Object::Func(func) => {
-                write!(f, "fn")?;
-                write!(f, "(")?;
-                let args = &func.params.len();
-                let mut i = 0;
-                for param in &func.params {
-                    write!(f, "{}", param)?;
-                    if i != args - 1 {
+                let params = ast::params_string(&func.def.params, &func.def.rest);
+                write!(f, "fn({}){}", params, func.def.body)
+            },
+            Object::Closure(c) => write!(f, "fn({}){}", c.def.func.params, c.def.func.body),
+            Object::Array(a) => {
+                write!(f, "[")?;
+                let len = a.elems.len();
+                for (i, elem) in a.elems.iter().enumerate() {
+                    fmt_elem(f, elem)?;
+                    if i != len - 1 {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "]")
+            },
+            Object::Range(r) => write!(f, "{}..{}", r.start, r.end),
+            Object::Hash(h) => {
+                write!(f, "{{")?;
+                let len = h.pairs.len();
+                for (i, pair) in h.pairs.values().enumerate() {
+                    fmt_elem(f, &pair.key)?;
+                    write!(f, ": ")?;
+                    fmt_elem(f, &pair.val)?;
+                    if i != len - 1 {
                         write!(f, ", ")?;
                     }
-                    i += 1;
                 }
-                write!(f, ")")?;
-                write!(f, "{}", func.body)
+                write!(f, "}}")
             },
+            Object::Builtin(b) => write!(f, "builtin function {}", b.name),
+            Object::Error(e) => write!(f, "{}", e),
         };
     }
 }
+
+// strings nested in a collection are quoted so that `["a, b"]` and
+// `["a", "b"]` print differently.
+fn fmt_elem(f: &mut fmt::Formatter<'_>, obj: &Object) -> fmt::Result {
+    return match obj {
+        Object::Str(s) => write!(f, "\"{}\"", s.val.escape_debug()),
+        _ => write!(f, "{}", obj),
+    };
+}
+
+pub fn new_error(kind: ErrorKind, msg: String, span: Option<token::Span>) -> Object {
+    return Object::Error(Error { kind: kind, msg: msg, span: span, trace: vec![] });
+}
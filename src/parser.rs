@@ -1,3 +1,5 @@
+use std::fmt;
+use std::rc::Rc;
 use crate::ast::{
     Program,
     Stmt,
@@ -6,14 +8,26 @@ use crate::ast::{
     Return,
     ExprStmt,
     Block,
+    While,
+    For,
+    Break,
+    Continue,
     Ident,
     Int,
+    Str,
     Prefix,
     Infix,
     Bool,
     If,
+    ElseIf,
     Func,
+    Param,
     Call,
+    Array,
+    Index,
+    Hash,
+    Assign,
+    Range,
 };
 use crate::lexer;
 use crate::token;
@@ -22,21 +36,86 @@ pub struct Parser<'a> {
     l: &'a mut lexer::Lexer,
     cur_token: token::Token,
     peek_token: token::Token,
-    errors: Vec<String>,
+    errors: Vec<ParseError>,
+    // how many loops enclose the current token within its function.
+    loop_depth: usize,
+    // how deeply the current token is nested in expressions and blocks.
+    depth: usize,
+}
+
+// the deepest nesting the parser accepts. Parsing and compiling recurse on
+// the tree, so deeper input would overflow the native stack. At this depth
+// even a debug build does both within the 2 MiB a thread has by default.
+pub const MAX_NESTING: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub found: token::Token,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ParseErrorKind {
+    Expected(token::Type),
+    NoPrefixParse,
+    InvalidInt,
+    Illegal,
+    OutsideLoop,
+    InvalidAssign,
+    MissingDefault,
+    TooDeep,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let found = match self.found.t {
+            token::Type::Eof => String::from("end of input"),
+            _ => format!("{:?} '{}'", self.found.t, self.found.literal),
+        };
+        write!(f, "{}: ", self.found.span)?;
+        match &self.kind {
+            ParseErrorKind::Expected(t) =>
+                write!(f, "expected {:?}, but found {}", t, found),
+            ParseErrorKind::NoPrefixParse =>
+                write!(f, "expected an expression, but found {}", found),
+            ParseErrorKind::InvalidInt =>
+                write!(f, "could not parse {} as an integer", found),
+            ParseErrorKind::Illegal =>
+                write!(f, "invalid token '{}'", self.found.literal),
+            ParseErrorKind::OutsideLoop =>
+                write!(f, "'{}' outside of a loop", self.found.literal),
+            ParseErrorKind::InvalidAssign =>
+                write!(f, "expected a name to the left of '{}'", self.found.literal),
+            ParseErrorKind::MissingDefault =>
+                write!(f, "parameter '{}' follows one with a default, so needs one too", self.found.literal),
+            ParseErrorKind::TooDeep =>
+                write!(f, "nested more than {} levels deep", MAX_NESTING),
+        }
+    }
 }
 
 #[derive(PartialOrd, PartialEq)]
 enum Precedence {
     Lowest,
+    Assign, // x = y or x += y
+    Or,     // ||
+    And,    // &&
     Equals, // ==
     Lt,     // <, >, <=, >=
+    Range,  // a..b
+    BitOr,  // |
+    BitXor, // ^
+    BitAnd, // &
+    Shift,  // << or >>
     Add,    // + or -
-    Mul,    // * or /
-    Prefix, // -x or !x
+    Mul,    // *, / or %
+    Prefix, // -x, !x or ~x
+    Power,  // x ** y
     Call,   // func(x)
+    Index,  // array[i]
 }
 
-pub fn new(l: &mut lexer::Lexer) -> Parser {
+pub fn new(l: &mut lexer::Lexer) -> Parser<'_> {
     let first_token = l.next_token();
     let second_token = l.next_token();
     Parser {
@@ -44,93 +123,212 @@ pub fn new(l: &mut lexer::Lexer) -> Parser {
         cur_token: first_token,
         peek_token: second_token,
         errors: vec![],
+        loop_depth: 0,
+        depth: 0,
     }
 }
 
 impl Parser<'_> {
-    pub fn parse_program(&mut self) -> Program {
+    // parse the whole input. On failure every statement that could not be
+    // parsed is reported, not only the first one.
+    pub fn parse_program(&mut self) -> Result<Program, Vec<ParseError>> {
         let stmts: Vec<Stmt> = vec![];
         let mut p = Program {
             stmts: stmts,
         };
 
         while self.cur_token.t != token::Type::Eof {
-            let stmt = self.parse_stmt();
-            p.stmts.push(stmt);
+            match self.parse_stmt() {
+                Ok(stmt) => p.stmts.push(stmt),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.depth = 0;
+                    self.skip_stmt();
+                },
+            }
+            self.next_token();
+        }
+
+        if self.errors.is_empty() {
+            Ok(p)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    // skip to the end of the broken statement so that parsing can resume
+    // from the next one.
+    fn skip_stmt(&mut self) {
+        while !self.cur_token_is(token::Type::Semicolon) &&
+              !self.cur_token_is(token::Type::Eof) {
             self.next_token();
         }
-        p
     }
 
-    fn parse_stmt(&mut self) -> Stmt {
+    fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
         return match self.cur_token.t {
-            token::Type::Let => Stmt::Let(self.parse_let_stmt()),
-            token::Type::Return => Stmt::Return(self.parse_return_stmt()),
-            _ => Stmt::ExprStmt(self.parse_expr_stmt()),
+            token::Type::Let | token::Type::Const
+                => Ok(Stmt::Let(self.parse_let_stmt()?)),
+            token::Type::Return => Ok(Stmt::Return(self.parse_return_stmt()?)),
+            token::Type::While => Ok(Stmt::While(self.parse_while_stmt()?)),
+            token::Type::For => Ok(Stmt::For(self.parse_for_stmt()?)),
+            token::Type::Break => {
+                let t = self.parse_loop_control()?;
+                Ok(Stmt::Break(Rc::new(Break { span: t.span, token: t })))
+            },
+            token::Type::Continue => {
+                let t = self.parse_loop_control()?;
+                Ok(Stmt::Continue(Rc::new(Continue { span: t.span, token: t })))
+            },
+            _ => Ok(Stmt::ExprStmt(self.parse_expr_stmt()?)),
         };
     }
 
-    fn parse_let_stmt(&mut self) -> Let {
+    fn parse_while_stmt(&mut self) -> Result<Rc<While>, ParseError> {
         let t = self.cur_token.clone();
+        self.expect_peek(token::Type::Lparen)?;
+        self.next_token();
+        let cond = self.parse_expr(Precedence::Lowest)?;
+        self.expect_peek(token::Type::Rparen)?;
+        self.expect_peek(token::Type::Lbrace)?;
 
-        let _ = self.expect_peek(token::Type::Ident);
+        self.loop_depth += 1;
+        let body = self.parse_block();
+        self.loop_depth -= 1;
+        let body = body?;
 
-        let ident = Ident {
-            token: self.cur_token.clone(),
-            val: self.cur_token.clone().literal,
-        };
+        if self.peek_token_is(token::Type::Semicolon) {
+            self.next_token();
+        }
+        let span = self.span_from(t.span);
+        Ok(Rc::new(While { token: t, span: span, cond: cond, body: body }))
+    }
 
-        let _ = self.expect_peek(token::Type::Assign);
+    fn parse_for_stmt(&mut self) -> Result<Rc<For>, ParseError> {
+        let t = self.cur_token.clone();
+        self.expect_peek(token::Type::Lparen)?;
+        self.expect_peek(token::Type::Ident)?;
+        let var = self.parse_ident();
+        self.expect_peek(token::Type::In)?;
         self.next_token();
-        let val = self.parse_expr(Precedence::Lowest);
+        let iter = self.parse_expr(Precedence::Lowest)?;
+        self.expect_peek(token::Type::Rparen)?;
+        self.expect_peek(token::Type::Lbrace)?;
+
+        self.loop_depth += 1;
+        let body = self.parse_block();
+        self.loop_depth -= 1;
+        let body = body?;
 
         if self.peek_token_is(token::Type::Semicolon) {
             self.next_token();
         }
+        let span = self.span_from(t.span);
+        Ok(Rc::new(For { token: t, span: span, var: var, iter: iter, body: body }))
+    }
 
-        Let { token: t, name: ident, val: val }
+    // `break` or `continue`, which only make sense inside a loop. Misplaced
+    // ones are still well formed, so parsing carries on past them.
+    fn parse_loop_control(&mut self) -> Result<token::Token, ParseError> {
+        let t = self.cur_token.clone();
+        if self.loop_depth == 0 {
+            let e = self.error(ParseErrorKind::OutsideLoop, t.clone());
+            self.errors.push(e);
+        }
+        if self.peek_token_is(token::Type::Semicolon) {
+            self.next_token();
+        }
+        Ok(t)
     }
 
-    fn parse_return_stmt(&mut self) -> Return {
+    fn parse_let_stmt(&mut self) -> Result<Rc<Let>, ParseError> {
         let t = self.cur_token.clone();
+
+        self.expect_peek(token::Type::Ident)?;
+
+        let ident = self.parse_ident();
+
+        self.expect_peek(token::Type::Assign)?;
         self.next_token();
+        let mut val = self.parse_expr(Precedence::Lowest)?;
+        if let Expr::Func(f) = &mut val {
+            Rc::make_mut(f).name = Some(ident.val.clone());
+        }
 
-        let val = self.parse_expr(Precedence::Lowest);
+        if self.peek_token_is(token::Type::Semicolon) {
+            self.next_token();
+        }
+
+        let span = self.span_from(t.span);
+        let constant = t.t == token::Type::Const;
+        Ok(Rc::new(Let { token: t, span: span, name: ident, val: val, constant: constant }))
+    }
+
+    fn parse_return_stmt(&mut self) -> Result<Rc<Return>, ParseError> {
+        let t = self.cur_token.clone();
+        self.next_token();
+
+        let val = self.parse_expr(Precedence::Lowest)?;
 
         if self.peek_token_is(token::Type::Semicolon) {
             self.next_token();
         }
-        Return { token: t, val: val }
+        let span = self.span_from(t.span);
+        Ok(Rc::new(Return { token: t, span: span, val: val }))
     }
 
-    fn parse_expr_stmt(&mut self) -> ExprStmt {
+    fn parse_expr_stmt(&mut self) -> Result<Rc<ExprStmt>, ParseError> {
         let t = self.cur_token.clone();
-        let expr = self.parse_expr(Precedence::Lowest);
+        let expr = self.parse_expr(Precedence::Lowest)?;
 
         if self.peek_token_is(token::Type::Semicolon) {
             self.next_token();
         }
-        ExprStmt { token: t, expr: expr }
+        let span = self.span_from(t.span);
+        Ok(Rc::new(ExprStmt { token: t, span: span, expr: expr }))
     }
 
-    fn parse_block(&mut self) -> Block {
+    fn parse_block(&mut self) -> Result<Block, ParseError> {
         let mut stmts: Vec<Stmt> = vec![];
 
+        self.nest()?;
         let t = self.cur_token.clone();
         self.next_token();
 
-        while !self.cur_token_is(token::Type::Rbrace) &&
-              !self.cur_token_is(token::Type::Eof) {
-            let stmt = self.parse_stmt();
+        while !self.cur_token_is(token::Type::Rbrace) {
+            if self.cur_token_is(token::Type::Eof) {
+                return Err(self.error(
+                    ParseErrorKind::Expected(token::Type::Rbrace),
+                    self.cur_token.clone(),
+                ));
+            }
+            let stmt = self.parse_stmt()?;
             stmts.push(stmt);
             self.next_token();
         }
-        Block { token: t, stmts: stmts }
+        self.depth -= 1;
+        let span = self.span_from(t.span);
+        Ok(Block { token: t, span: span, stmts: Rc::new(stmts) })
     }
 
-    fn parse_expr(&mut self, prec: Precedence) -> Expr {
-        let mut lhs = self.prefix_parse(self.cur_token.clone().t);
+    // what is nested counts towards MAX_NESTING: operands of prefix and
+    // right-associative operators, brackets, blocks, and each call, index
+    // or range applied to lhs. A chain of infix operators such as
+    // `1 + 2 + 3` does not, as everything that walks the tree follows
+    // such chains in a loop.
+    fn parse_expr(&mut self, prec: Precedence) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        self.nest()?;
+        let lhs = self.prefix_parse(self.cur_token.t.clone())?;
+        let expr = self.parse_operators(lhs, prec)?;
+        self.depth = depth;
+        Ok(expr)
+    }
 
+    // extend lhs with the operators that follow it, as long as they bind
+    // more tightly than prec. Kept apart from parse_expr so that only the
+    // operators being parsed take up the stack.
+    fn parse_operators(&mut self, mut lhs: Expr, prec: Precedence) -> Result<Expr, ParseError> {
         while !self.peek_token_is(token::Type::Semicolon) &&
             prec < self.peek_precedence() {
 
@@ -138,136 +336,338 @@ impl Parser<'_> {
             match self.peek_token.clone().t {
                 token::Type::Plus | token::Type::Minus |
                 token::Type::Slash | token::Type::Asterisk |
+                token::Type::Percent | token::Type::Power |
                 token::Type::Equ | token::Type::Neq |
-                token::Type::Lt | token::Type::Gt
+                token::Type::Lt | token::Type::Gt |
+                token::Type::Le | token::Type::Ge |
+                token::Type::And | token::Type::Or |
+                token::Type::BitAnd | token::Type::BitOr |
+                token::Type::BitXor | token::Type::Shl | token::Type::Shr
                 => {
                     self.next_token();
-                    lhs = Expr::Infix(self.parse_infix(lhs));
+                    lhs = Expr::Infix(self.parse_infix(lhs)?);
+                },
+                token::Type::Assign | token::Type::PlusAssign |
+                token::Type::MinusAssign | token::Type::AsteriskAssign |
+                token::Type::SlashAssign
+                => {
+                    self.next_token();
+                    lhs = Expr::Assign(self.parse_assign(lhs)?);
+                },
+                token::Type::DotDot => {
+                    self.next_token();
+                    self.nest()?;
+                    lhs = Expr::Range(self.parse_range(lhs)?);
                 },
                 token::Type::Lparen => {
                     self.next_token();
-                    lhs = Expr::Call(self.parse_call(lhs));
+                    self.nest()?;
+                    lhs = Expr::Call(self.parse_call(lhs)?);
                 },
-                _ => return lhs,
+                token::Type::Lbracket => {
+                    self.next_token();
+                    self.nest()?;
+                    lhs = Expr::Index(self.parse_index(lhs)?);
+                },
+                _ => break,
             }
         }
-        lhs
+        Ok(lhs)
+    }
+
+    // enter one more level of nesting, failing past MAX_NESTING.
+    fn nest(&mut self) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(self.error(ParseErrorKind::TooDeep, self.cur_token.clone()));
+        }
+        Ok(())
     }
 
     fn parse_ident(&mut self) -> Ident {
         let t = self.cur_token.clone();
-        Ident { token: t.clone(), val: t.literal }
+        Ident { token: t.clone(), span: t.span, val: t.literal }
     }
 
-    fn parse_int(&mut self) -> Int {
+    fn parse_int(&mut self) -> Result<Int, ParseError> {
         let t = self.cur_token.clone();
-        let n: isize = t.clone().literal.parse().unwrap();
-        Int { token: t, val: n }
+        let n: isize = match t.literal.parse() {
+            Ok(n) => n,
+            Err(_) => return Err(self.error(ParseErrorKind::InvalidInt, t)),
+        };
+        Ok(Int { token: t.clone(), span: t.span, val: n })
     }
 
-    fn parse_if(&mut self) -> If {
+    fn parse_str(&mut self) -> Str {
         let t = self.cur_token.clone();
-        self.expect_peek(token::Type::Lparen);
-        self.next_token();
-        let cond = self.parse_expr(Precedence::Lowest);
-        let _ = self.expect_peek(token::Type::Rparen);
-        let _ = self.expect_peek(token::Type::Lbrace);
-        let cons = self.parse_block();
+        Str { token: t.clone(), span: t.span, val: t.literal }
+    }
 
-        let has_alt = self.peek_token_is(token::Type::Else);
+    // the `else if` branches are parsed in a loop, so they do not count
+    // towards MAX_NESTING however many there are.
+    fn parse_if(&mut self) -> Result<Rc<If>, ParseError> {
+        let t = self.cur_token.clone();
+        let (cond, cons) = self.parse_branch()?;
 
-        let alt: Option<Block> = if has_alt {
+        let mut else_ifs = vec![];
+        let mut alt: Option<Block> = None;
+        while self.peek_token_is(token::Type::Else) {
+            self.next_token();
+            if !self.peek_token_is(token::Type::If) {
+                self.expect_peek(token::Type::Lbrace)?;
+                alt = Some(self.parse_block()?);
+                break;
+            }
             self.next_token();
-            let _ = self.expect_peek(token::Type::Lbrace);
-            Some(self.parse_block())
-        } else { None };
+            let t = self.cur_token.clone();
+            let (cond, cons) = self.parse_branch()?;
+            let span = self.span_from(t.span);
+            else_ifs.push(ElseIf { token: t, span: span, cond: Rc::new(cond), cons: cons });
+        }
 
-        If { token: t, cond: Box::new(cond), cons: cons, alt: alt }
+        let span = self.span_from(t.span);
+        Ok(Rc::new(If {
+            token: t,
+            span: span,
+            cond: Rc::new(cond),
+            cons: cons,
+            else_ifs: else_ifs,
+            alt: alt,
+        }))
     }
 
-    fn parse_func(&mut self) -> Func {
-        let t = self.cur_token.clone();
-        let _ = self.expect_peek(token::Type::Lparen);
-        let params = self.parse_func_params();
-        let _ = self.expect_peek(token::Type::Lbrace);
-        let body = self.parse_block();
-        Func { token: t, params: params, body: body }
+    // the parenthesized condition after an `if` and the block it picks.
+    fn parse_branch(&mut self) -> Result<(Expr, Block), ParseError> {
+        self.expect_peek(token::Type::Lparen)?;
+        self.next_token();
+        let cond = self.parse_expr(Precedence::Lowest)?;
+        self.expect_peek(token::Type::Rparen)?;
+        self.expect_peek(token::Type::Lbrace)?;
+        let cons = self.parse_block()?;
+        Ok((cond, cons))
     }
 
-    fn parse_func_params(&mut self) -> Vec<Ident> {
-        let mut params: Vec<Ident> = vec![];
-        self.next_token();
-        while !self.cur_token_is(token::Type::Rparen) {
-            let param = self.parse_ident();
-            params.push(param);
+    fn parse_func(&mut self) -> Result<Rc<Func>, ParseError> {
+        let t = self.cur_token.clone();
+        self.expect_peek(token::Type::Lparen)?;
+
+        // a loop around the function does not enclose its defaults or body.
+        let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let parsed = self.parse_func_params().and_then(|params| {
+            self.expect_peek(token::Type::Lbrace)?;
+            Ok((params, self.parse_block()?))
+        });
+        self.loop_depth = loop_depth;
+        let ((params, rest), body) = parsed?;
+
+        let span = self.span_from(t.span);
+        Ok(Rc::new(Func {
+            token: t,
+            span: span,
+            name: None,
+            params: params,
+            rest: rest,
+            body: body,
+        }))
+    }
 
-            // skip identifier
+    // `a, b = 2, ...rest`. Only a rest parameter may follow the defaults,
+    // and nothing may follow it.
+    fn parse_func_params(
+        &mut self
+    ) -> Result<(Vec<Param>, Option<Ident>), ParseError> {
+        let mut params: Vec<Param> = vec![];
+        if self.peek_token_is(token::Type::Rparen) {
             self.next_token();
+            return Ok((params, None));
+        }
 
-            if self.cur_token_is(token::Type::Comma) {
+        loop {
+            if self.peek_token_is(token::Type::Ellipsis) {
+                self.next_token();
+                self.expect_peek(token::Type::Ident)?;
+                let rest = self.parse_ident();
+                self.expect_peek(token::Type::Rparen)?;
+                return Ok((params, Some(rest)));
+            }
+
+            self.expect_peek(token::Type::Ident)?;
+            let name = self.parse_ident();
+            let default = if self.peek_token_is(token::Type::Assign) {
+                self.next_token();
                 self.next_token();
+                Some(self.parse_expr(Precedence::Lowest)?)
+            } else {
+                None
+            };
+            let follows_default = params.last().is_some_and(|p| p.default.is_some());
+            if default.is_none() && follows_default {
+                let e = self.error(ParseErrorKind::MissingDefault, name.token.clone());
+                self.errors.push(e);
+            }
+            params.push(Param { name: name, default: default });
+
+            if !self.peek_token_is(token::Type::Comma) {
+                break;
             }
+            // skip comma
+            self.next_token();
         }
-        params
+
+        self.expect_peek(token::Type::Rparen)?;
+        Ok((params, None))
     }
 
     fn parse_boolean(&mut self) -> Bool {
         let t = self.cur_token.clone();
-        let b: bool = if self.cur_token.clone().literal == "true"
-            { true } else { false };
-        Bool { token:t , val: b}
+        let b: bool = self.cur_token.literal == "true";
+        Bool { token: t.clone(), span: t.span, val: b }
     }
 
-    fn parse_grouped_expr(&mut self) -> Expr {
+    fn parse_grouped_expr(&mut self) -> Result<Expr, ParseError> {
         self.next_token();
-        let e = self.parse_expr(Precedence::Lowest);
-        let _ = self.expect_peek(token::Type::Rparen);
-        e
+        let e = self.parse_expr(Precedence::Lowest)?;
+        self.expect_peek(token::Type::Rparen)?;
+        Ok(e)
     }
 
-    fn parse_call(&mut self, func: Expr) -> Call {
+    fn parse_call(&mut self, func: Expr) -> Result<Rc<Call>, ParseError> {
         let t = self.cur_token.clone();
-        let args = self.parse_call_args();
-        Call { token: t, func: Box::new(func), args: args }
+        let args = self.parse_expr_list(token::Type::Rparen)?;
+        let span = self.span_from(func.span());
+        Ok(Rc::new(Call { token: t, span: span, func: Rc::new(func), args: args }))
     }
 
-    fn parse_call_args(&mut self) -> Vec<Expr> {
-        let mut args: Vec<Expr> = vec![];
-        self.next_token();
-        while !self.cur_token_is(token::Type::Rparen) {
-            let arg = self.parse_expr(Precedence::Lowest);
-            args.push(arg);
+    fn parse_array(&mut self) -> Result<Rc<Array>, ParseError> {
+        let t = self.cur_token.clone();
+        let elems = self.parse_expr_list(token::Type::Rbracket)?;
+        let span = self.span_from(t.span);
+        Ok(Rc::new(Array { token: t, span: span, elems: elems }))
+    }
+
+    fn parse_hash(&mut self) -> Result<Rc<Hash>, ParseError> {
+        let t = self.cur_token.clone();
+        let mut pairs: Vec<(Expr, Expr)> = vec![];
 
-            // skip argument
+        while !self.peek_token_is(token::Type::Rbrace) {
             self.next_token();
+            let key = self.parse_expr(Precedence::Lowest)?;
+            self.expect_peek(token::Type::Colon)?;
+            self.next_token();
+            let val = self.parse_expr(Precedence::Lowest)?;
+            pairs.push((key, val));
 
-            if self.cur_token_is(token::Type::Comma) {
-                self.next_token();
+            if !self.peek_token_is(token::Type::Rbrace) {
+                self.expect_peek(token::Type::Comma)?;
             }
         }
-        args
+
+        self.expect_peek(token::Type::Rbrace)?;
+        let span = self.span_from(t.span);
+        Ok(Rc::new(Hash { token: t, span: span, pairs: pairs }))
+    }
+
+    fn parse_index(&mut self, lhs: Expr) -> Result<Rc<Index>, ParseError> {
+        let t = self.cur_token.clone();
+        self.next_token();
+        let index = self.parse_expr(Precedence::Lowest)?;
+        self.expect_peek(token::Type::Rbracket)?;
+        let span = self.span_from(lhs.span());
+        Ok(Rc::new(Index {
+            token: t,
+            span: span,
+            lhs: Rc::new(lhs),
+            index: Rc::new(index),
+        }))
+    }
+
+    // parse comma separated expressions up to the closing `end` token, e.g.
+    // call arguments or array elements.
+    fn parse_expr_list(
+        &mut self, end: token::Type
+    ) -> Result<Vec<Expr>, ParseError> {
+        let mut list: Vec<Expr> = vec![];
+        if self.peek_token_is(end.clone()) {
+            self.next_token();
+            return Ok(list);
+        }
+
+        self.next_token();
+        list.push(self.parse_expr(Precedence::Lowest)?);
+
+        while self.peek_token_is(token::Type::Comma) {
+            // skip comma
+            self.next_token();
+            self.next_token();
+            list.push(self.parse_expr(Precedence::Lowest)?);
+        }
+
+        self.expect_peek(end)?;
+        Ok(list)
     }
 
-    fn parse_prefix(&mut self) -> Prefix {
+    fn parse_prefix(&mut self) -> Result<Rc<Prefix>, ParseError> {
         let t = self.cur_token.clone();
         let op = self.cur_token.clone().literal;
         self.next_token();
-        let rhs = self.parse_expr(Precedence::Prefix);
-        Prefix { token: t, op: op, rhs: Box::new(rhs) }
+        let rhs = self.parse_expr(Precedence::Prefix)?;
+        let span = self.span_from(t.span);
+        Ok(Rc::new(Prefix { token: t, span: span, op: op, rhs: Rc::new(rhs) }))
     }
 
-    fn parse_infix(&mut self, lhs: Expr) -> Infix {
+    fn parse_infix(&mut self, lhs: Expr) -> Result<Rc<Infix>, ParseError> {
         let t = self.cur_token.clone();
         let op = self.cur_token.clone().literal;
-        let prec = self.cur_precedence();
+        // the rhs of a right-associative operator may contain the operator
+        // again, so `2 ** 3 ** 2` is `2 ** (3 ** 2)`.
+        let prec = match t.t {
+            token::Type::Power => Precedence::Prefix,
+            _ => self.cur_precedence(),
+        };
         self.next_token();
-        let rhs = self.parse_expr(prec);
-        Infix {
+        let rhs = self.parse_expr(prec)?;
+        let span = self.span_from(lhs.span());
+        Ok(Rc::new(Infix {
             token: t,
-            lhs: Box::new(lhs),
+            span: span,
+            lhs: Rc::new(lhs),
             op: op,
-            rhs: Box::new(rhs),
-        }
+            rhs: Rc::new(rhs),
+        }))
+    }
+
+    fn parse_range(&mut self, start: Expr) -> Result<Rc<Range>, ParseError> {
+        let t = self.cur_token.clone();
+        self.next_token();
+        let end = self.parse_expr(Precedence::Range)?;
+        let span = self.span_from(start.span());
+        Ok(Rc::new(Range {
+            token: t,
+            span: span,
+            start: Rc::new(start),
+            end: Rc::new(end),
+        }))
+    }
+
+    // assignment is right-associative, so the value extends as far as it
+    // can: `a = b = 1` assigns 1 to b and then to a.
+    fn parse_assign(&mut self, lhs: Expr) -> Result<Rc<Assign>, ParseError> {
+        let t = self.cur_token.clone();
+        let name = match lhs {
+            Expr::Ident(i) => i,
+            _ => return Err(self.error(ParseErrorKind::InvalidAssign, t)),
+        };
+        let op = t.literal.clone();
+        self.next_token();
+        let val = self.parse_expr(Precedence::Lowest)?;
+        let span = self.span_from(name.span);
+        Ok(Rc::new(Assign {
+            token: t,
+            span: span,
+            name: (*name).clone(),
+            op: op,
+            val: Rc::new(val),
+        }))
     }
 
     fn next_token(&mut self) {
@@ -275,6 +675,11 @@ impl Parser<'_> {
         self.peek_token = self.l.next_token();
     }
 
+    // the span from start to the end of the current token.
+    fn span_from(&self, start: token::Span) -> token::Span {
+        return start.to(self.cur_token.span);
+    }
+
     fn cur_token_is(&self, t: token::Type) -> bool {
         return self.cur_token.t == t;
     }
@@ -291,59 +696,135 @@ impl Parser<'_> {
         return to_precedence(self.peek_token.clone().t);
     }
 
-    fn expect_peek(&mut self, t: token::Type) -> bool {
+    fn expect_peek(&mut self, t: token::Type) -> Result<(), ParseError> {
         if self.peek_token_is(t.clone()) {
             self.next_token();
-            return true;
+            return Ok(());
         }
-        self.peek_error(t);
-        false
+        Err(self.error(ParseErrorKind::Expected(t), self.peek_token.clone()))
     }
 
-    fn peek_error(&mut self, t: token::Type) {
-        let msg = format!(
-            "expected peek token: {:?}, but got: {:?}", t, self.peek_token.t
-        );
-        self.errors.push(msg);
+    fn error(&self, kind: ParseErrorKind, found: token::Token) -> ParseError {
+        return ParseError { kind: kind, found: found };
     }
 
-    fn prefix_parse(&mut self, t: token::Type) -> Expr {
+    fn prefix_parse(&mut self, t: token::Type) -> Result<Expr, ParseError> {
         return match t {
             token::Type::Ident => {
-                Expr::Ident(self.parse_ident())
+                Ok(Expr::Ident(Rc::new(self.parse_ident())))
+            },
+            token::Type::Int => {
+                Ok(Expr::Int(Rc::new(self.parse_int()?)))
+            },
+            token::Type::String => {
+                Ok(Expr::Str(Rc::new(self.parse_str())))
             },
             token::Type::Lparen => {
                 self.parse_grouped_expr()
             },
+            token::Type::Lbracket => {
+                Ok(Expr::Array(self.parse_array()?))
+            },
+            // a block never starts an expression, so `{` here is a hash.
+            token::Type::Lbrace => {
+                Ok(Expr::Hash(self.parse_hash()?))
+            },
             token::Type::If => {
-                Expr::If(self.parse_if())
+                Ok(Expr::If(self.parse_if()?))
             },
             token::Type::Function => {
-                Expr::Func(self.parse_func())
+                Ok(Expr::Func(self.parse_func()?))
             },
-            token::Type::Minus | token::Type::Bang => {
-                Expr::Prefix(self.parse_prefix())
+            token::Type::Minus | token::Type::Bang | token::Type::BitNot => {
+                Ok(Expr::Prefix(self.parse_prefix()?))
             },
             token::Type::True | token::Type::False => {
-                Expr::Bool(self.parse_boolean())
+                Ok(Expr::Bool(Rc::new(self.parse_boolean())))
             },
-            _ => Expr::Int(self.parse_int()),
+            token::Type::Illegal => Err(self.error(
+                ParseErrorKind::Illegal,
+                self.cur_token.clone(),
+            )),
+            _ => Err(self.error(
+                ParseErrorKind::NoPrefixParse,
+                self.cur_token.clone(),
+            )),
         };
     }
 }
 
+// whether input is the start of a program rather than a wrong one: it has
+// unclosed brackets, ends with an operator, or runs out in the middle of a
+// construct. The REPL keeps reading while this holds.
+pub fn is_incomplete(input: &str) -> bool {
+    let mut l = lexer::new(input);
+    let mut depth = 0;
+    let mut last = token::Type::Eof;
+    loop {
+        let tok = l.next_token();
+        match tok.t {
+            token::Type::Eof => break,
+            token::Type::Lparen | token::Type::Lbrace | token::Type::Lbracket
+                => depth += 1,
+            token::Type::Rparen | token::Type::Rbrace | token::Type::Rbracket
+                => depth -= 1,
+            _ => (),
+        }
+        last = tok.t;
+    }
+    if depth > 0 {
+        return true;
+    }
+    // every binary operator has a precedence above Lowest.
+    match last {
+        token::Type::Bang | token::Type::BitNot |
+        token::Type::Comma | token::Type::Colon
+            => return true,
+        t if to_precedence(t.clone()) > Precedence::Lowest => return true,
+        _ => (),
+    }
+
+    let mut l = lexer::new(input);
+    let mut p = new(&mut l);
+    return match p.parse_program() {
+        Ok(_) => false,
+        Err(errors) => errors.iter().any(|e| e.found.t == token::Type::Eof),
+    };
+}
+
 fn to_precedence(t: token::Type) -> Precedence {
     return match t {
+        token::Type::Assign | token::Type::PlusAssign | token::Type::MinusAssign |
+        token::Type::AsteriskAssign | token::Type::SlashAssign
+            => Precedence::Assign,
+        token::Type::Or
+            => Precedence::Or,
+        token::Type::And
+            => Precedence::And,
         token::Type::Equ | token::Type::Neq
             => Precedence::Equals,
-        token::Type::Lt | token::Type::Gt
+        token::Type::Lt | token::Type::Gt | token::Type::Le | token::Type::Ge
             => Precedence::Lt,
+        token::Type::DotDot
+            => Precedence::Range,
+        token::Type::BitOr
+            => Precedence::BitOr,
+        token::Type::BitXor
+            => Precedence::BitXor,
+        token::Type::BitAnd
+            => Precedence::BitAnd,
+        token::Type::Shl | token::Type::Shr
+            => Precedence::Shift,
         token::Type::Plus | token::Type::Minus
             => Precedence::Add,
-        token::Type::Slash | token::Type::Asterisk
+        token::Type::Slash | token::Type::Asterisk | token::Type::Percent
             => Precedence::Mul,
-        token::Type::Lparen 
+        token::Type::Power
+            => Precedence::Power,
+        token::Type::Lparen
             => Precedence::Call,
+        token::Type::Lbracket
+            => Precedence::Index,
         _ => Precedence::Lowest,
     };
 }
@@ -358,9 +839,7 @@ mod test {
             let y = 10;
             let foobar = 838383;";
 
-        let mut l = lexer::new(input);
-        let mut p = new(&mut l);
-        let program = p.parse_program();
+        let program = test_parse_program(input);
 
         assert_eq!(program.stmts.len(), 3);
 
@@ -430,6 +909,22 @@ mod test {
         test_int(&es.expr, 5);
     }
 
+    #[test]
+    fn string_expr() {
+        let input = "\"hello world\";";
+        let program = test_parse_program(input);
+
+        assert_eq!(program.stmts.len(), 1);
+
+        let stmt = &program.stmts[0];
+        let es = test_expr_stmt(stmt);
+
+        match &es.expr {
+            Expr::Str(s) => assert_eq!(s.val, "hello world"),
+            _ => panic!("We parsed other than string literal."),
+        }
+    }
+
     #[test]
     fn boolean_expr() {
         let inputs = vec![ "true;", "false;"];
@@ -454,10 +949,13 @@ mod test {
     fn if_expr() {
         let inputs= vec![
             "if (x < y) { x };",
-            "if (x < y) { x } else { y };"
+            "if (x < y) { x } else { y };",
+            "if (x < y) { x } else if (y) { y } else if (z) { z };",
+            "if (x < y) { x } else if (y) { y } else { z };",
         ];
 
-        let has_alt = vec![ false, true ];
+        let has_alt = vec![ false, true, false, true ];
+        let else_ifs = vec![ 0, 0, 2, 1 ];
 
         for (i, input) in inputs.iter().enumerate() {
             let program = test_parse_program(input);
@@ -477,9 +975,63 @@ mod test {
             } else {
                 assert!(ifstmt.alt.is_none());
             }
+            assert_eq!(ifstmt.else_ifs.len(), else_ifs[i]);
+        }
+    }
+
+    #[test]
+    fn assign_expr() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test { input: "x = 5", expected: "(x = 5)" },
+            Test { input: "x += y * 2", expected: "(x += (y * 2))" },
+            Test { input: "a = b -= c == d", expected: "(a = (b -= (c == d)))" },
+            Test { input: "f(x /= 2, y *= 3)", expected: "f((x /= 2), (y *= 3))" },
+            Test { input: "const x = y = 1;", expected: "const x = (y = 1);" },
+        ];
+
+        for test in tests.iter() {
+            let program = test_parse_program(test.input);
+            assert_eq!(format!("{}", program), test.expected);
         }
     }
 
+    #[test]
+    fn while_stmt() {
+        let program = test_parse_program("while (x < 3) { if (x) { break; } continue };");
+        assert_eq!(program.stmts.len(), 1);
+
+        let w = match &program.stmts[0] {
+            Stmt::While(w) => w,
+            _ => panic!("We parsed other than while."),
+        };
+        assert_eq!(format!("{}", w.cond), "(x < 3)");
+        assert_eq!(w.body.stmts.len(), 2);
+        assert!(matches!(w.body.stmts[1], Stmt::Continue(_)));
+        assert_eq!(format!("{}", program), "while (x < 3) if x break;continue;");
+    }
+
+    #[test]
+    fn for_stmt() {
+        let program = test_parse_program("for (i in 0..n + 1) { if (i) { continue; } };");
+        assert_eq!(program.stmts.len(), 1);
+
+        let f = match &program.stmts[0] {
+            Stmt::For(f) => f,
+            _ => panic!("We parsed other than for."),
+        };
+        assert_eq!(f.var.val, "i");
+        assert_eq!(format!("{}", f.iter), "(0..(n + 1))");
+        assert_eq!(format!("{}", program), "for (i in (0..(n + 1))) if i continue;");
+
+        let program = test_parse_program("a < 1..2 == b; f(x)..len(y)");
+        assert_eq!(format!("{}", program), "((a < (1..2)) == b)(f(x)..len(y))");
+    }
+
     #[test]
     fn fn_expr() {
         let input = "fn(x, y) { x + y; }";
@@ -495,8 +1047,34 @@ mod test {
             _ => panic!("We parsed other than function expression."),
         };
 
-        assert_eq!(f.params[0].val, "x");
-        assert_eq!(f.params[1].val, "y");
+        assert_eq!(f.params[0].name.val, "x");
+        assert_eq!(f.params[1].name.val, "y");
+        assert!(f.name.is_none());
+        assert!(f.rest.is_none());
+    }
+
+    #[test]
+    fn fn_params() {
+        let program = test_parse_program("let f = fn(a, b = a * 2, ...rest) { rest };");
+        let l = match &program.stmts[0] {
+            Stmt::Let(l) => l,
+            _ => panic!("We parsed other than let."),
+        };
+        let f = match &l.val {
+            Expr::Func(f) => f,
+            _ => panic!("We parsed other than function expression."),
+        };
+
+        assert_eq!(f.name.as_deref(), Some("f"));
+        assert_eq!(f.params.len(), 2);
+        assert!(f.params[0].default.is_none());
+        assert_eq!(format!("{}", f.params[1].default.as_ref().unwrap()), "(a * 2)");
+        assert_eq!(f.rest.as_ref().unwrap().val, "rest");
+        assert_eq!(f.required(), 1);
+        assert_eq!(format!("{}", program), "let f = fn(a, b = (a * 2), ...rest)rest;");
+
+        let program = test_parse_program("fn(...args) { args }");
+        assert_eq!(format!("{}", program), "fn(...args)args");
     }
 
     #[test]
@@ -514,15 +1092,107 @@ mod test {
             _ => panic!("We parsed other than function call."),
         };
 
-        test_ident(&*(c.func), "add");
+        test_ident(&c.func, "add");
         test_int(&c.args[0], 1);
     }
 
+    #[test]
+    fn array_expr() {
+        let input = "[1, 2 * 2, 3 + 3]";
+        let program = test_parse_program(input);
+
+        assert_eq!(program.stmts.len(), 1);
+
+        let stmt = &program.stmts[0];
+        let es = test_expr_stmt(stmt);
+
+        let a = match &es.expr {
+            Expr::Array(a) => a,
+            _ => panic!("We parsed other than array literal."),
+        };
+
+        assert_eq!(a.elems.len(), 3);
+        test_int(&a.elems[0], 1);
+        assert_eq!(format!("{}", a.elems[1]), "(2 * 2)");
+        assert_eq!(format!("{}", a.elems[2]), "(3 + 3)");
+
+        let program = test_parse_program("[]");
+        match &test_expr_stmt(&program.stmts[0]).expr {
+            Expr::Array(a) => assert_eq!(a.elems.len(), 0),
+            _ => panic!("We parsed other than array literal."),
+        };
+    }
+
+    #[test]
+    fn hash_expr() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: &'a str,
+            len: usize,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                input: "{\"one\": 1, \"two\": 2, \"three\": 3}",
+                expected: "{\"one\": 1, \"two\": 2, \"three\": 3}",
+                len: 3,
+            },
+            Test {
+                input: "{}",
+                expected: "{}",
+                len: 0,
+            },
+            Test {
+                input: "{1: true, true: \"a\"}",
+                expected: "{1: true, true: \"a\"}",
+                len: 2,
+            },
+            Test {
+                input: "{\"one\": 0 + 1, \"two\": 10 - 8, x: 15 / 5,}",
+                expected: "{\"one\": (0 + 1), \"two\": (10 - 8), x: (15 / 5)}",
+                len: 3,
+            },
+        ];
+
+        for test in tests.iter() {
+            let program = test_parse_program(test.input);
+            assert_eq!(program.stmts.len(), 1);
+
+            let es = test_expr_stmt(&program.stmts[0]);
+            let h = match &es.expr {
+                Expr::Hash(h) => h,
+                _ => panic!("We parsed other than hash literal."),
+            };
+
+            assert_eq!(h.pairs.len(), test.len);
+            assert_eq!(format!("{}", h), test.expected);
+        }
+    }
+
+    #[test]
+    fn index_expr() {
+        let input = "myArray[1 + 1]";
+        let program = test_parse_program(input);
+
+        assert_eq!(program.stmts.len(), 1);
+
+        let stmt = &program.stmts[0];
+        let es = test_expr_stmt(stmt);
+
+        let i = match &es.expr {
+            Expr::Index(i) => i,
+            _ => panic!("We parsed other than index expression."),
+        };
+
+        test_ident(&i.lhs, "myArray");
+        assert_eq!(format!("{}", i.index), "(1 + 1)");
+    }
+
     #[test]
     fn prefix_exprs() {
-        let inputs = vec![ "!5;", "-15;" ];
-        let expect_prefixes = vec![ "!", "-" ];
-        let expect_ints = vec![ 5, 15 ];
+        let inputs = vec![ "!5;", "-15;", "~7;" ];
+        let expect_prefixes = vec![ "!", "-", "~" ];
+        let expect_ints = vec![ 5, 15, 7 ];
 
         for (i, input) in inputs.iter().enumerate() {
             let program = test_parse_program(input);
@@ -538,7 +1208,7 @@ mod test {
             };
 
             assert_eq!(pre.op, expect_prefixes[i]);
-            test_int(&*pre.rhs, expect_ints[i]);
+            test_int(&pre.rhs, expect_ints[i]);
         }
     }
 
@@ -560,10 +1230,19 @@ mod test {
             Test { input: "5<5;", lhs: 5, op: "<", rhs: 5 },
             Test { input: "5==5;", lhs: 5, op: "==", rhs: 5 },
             Test { input: "5!=5;", lhs: 5, op: "!=", rhs: 5 },
+            Test { input: "5<=5;", lhs: 5, op: "<=", rhs: 5 },
+            Test { input: "5>=5;", lhs: 5, op: ">=", rhs: 5 },
+            Test { input: "5%5;", lhs: 5, op: "%", rhs: 5 },
+            Test { input: "5**5;", lhs: 5, op: "**", rhs: 5 },
+            Test { input: "5&5;", lhs: 5, op: "&", rhs: 5 },
+            Test { input: "5|5;", lhs: 5, op: "|", rhs: 5 },
+            Test { input: "5^5;", lhs: 5, op: "^", rhs: 5 },
+            Test { input: "5<<5;", lhs: 5, op: "<<", rhs: 5 },
+            Test { input: "5>>5;", lhs: 5, op: ">>", rhs: 5 },
         ];
 
         for test in tests.iter() {
-            let program = test_parse_program(&test.input);
+            let program = test_parse_program(test.input);
             assert_eq!(program.stmts.len(), 1);
 
             let stmt = &program.stmts[0];
@@ -574,8 +1253,8 @@ mod test {
                 _ => panic!("We parsed other than infix expression."),
             };
 
-            test_int(&*(i.lhs), test.lhs);
-            test_int(&*(i.rhs), test.rhs);
+            test_int(&i.lhs, test.lhs);
+            test_int(&i.rhs, test.rhs);
             assert_eq!(i.op, test.op);
         }
     }
@@ -636,22 +1315,184 @@ mod test {
                 input: "add(a + b + c * d / f + g)",
                 expected: "add((((a + b) + ((c * d) / f)) + g))"
             },
+            Test {
+                input: "a * [1, 2, 3, 4][b * c] * d",
+                expected: "((a * ([1, 2, 3, 4][(b * c)])) * d)"
+            },
+            Test {
+                input: "add(a * b[2], b[1], 2 * [1, 2][1])",
+                expected: "add((a * (b[2])), (b[1]), (2 * ([1, 2][1])))"
+            },
+            Test {
+                input: "a || b && c == d || !e",
+                expected: "((a || (b && (c == d))) || (!e))"
+            },
+            Test {
+                input: "a | b ^ c & d << e + f % g ** h ** i",
+                expected: "(a | (b ^ (c & (d << (e + (f % (g ** (h ** i))))))))"
+            },
+            Test {
+                input: "-a ** 2 <= ~b * c",
+                expected: "((-(a ** 2)) <= ((~b) * c))"
+            },
+            Test {
+                input: "x & 1 == 0 || 0..n >= m",
+                expected: "(((x & 1) == 0) || ((0..n) >= m))"
+            },
+            Test {
+                input: "x = a && b || c",
+                expected: "(x = ((a && b) || c))"
+            },
         ];
 
         for test in tests.iter() {
-            let program = test_parse_program(&test.input);
+            let program = test_parse_program(test.input);
             let stmt = &program.stmts[0];
             let stmt_str = format!("{}", stmt);
             assert_eq!(stmt_str, test.expected);
         }
     }
 
+    #[test]
+    fn parse_errors() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: Vec<&'a str>,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                input: "let = 5;",
+                expected: vec!["1:5: expected Ident, but found Assign '='"],
+            },
+            Test {
+                input: "let x 5;",
+                expected: vec!["1:7: expected Assign, but found Int '5'"],
+            },
+            Test {
+                input: "let x = 5; let = 10; let 838383;",
+                expected: vec![
+                    "1:16: expected Ident, but found Assign '='",
+                    "1:26: expected Ident, but found Int '838383'",
+                ],
+            },
+            Test {
+                input: "1 + ;",
+                expected: vec!["1:5: expected an expression, but found Semicolon ';'"],
+            },
+            Test {
+                input: "99999999999999999999999",
+                expected: vec![
+                    "1:1: could not parse Int '99999999999999999999999' as an integer"
+                ],
+            },
+            Test {
+                input: "add(1, 2",
+                expected: vec!["1:9: expected Rparen, but found end of input"],
+            },
+            Test {
+                input: "fn(x, 1) { x }",
+                expected: vec!["1:7: expected Ident, but found Int '1'"],
+            },
+            Test {
+                input: "fn(a = 1, b) { b }; fn(...a, b) { a }",
+                expected: vec![
+                    "1:11: parameter 'b' follows one with a default, so needs one too",
+                    "1:28: expected Rparen, but found Comma ','",
+                ],
+            },
+            Test {
+                input: "let s = \"abc;",
+                expected: vec!["1:9: invalid token '\"abc;'"],
+            },
+            Test {
+                input: "{\"a\" 1}",
+                expected: vec!["1:6: expected Colon, but found Int '1'"],
+            },
+            Test {
+                input: "{\"a\": 1 \"b\": 2}",
+                expected: vec!["1:9: expected Comma, but found String 'b'"],
+            },
+            Test {
+                input: "if (x) { x",
+                expected: vec!["1:11: expected Rbrace, but found end of input"],
+            },
+            Test {
+                input: "if (x) { break; }",
+                expected: vec!["1:10: 'break' outside of a loop"],
+            },
+            Test {
+                input: "while (x) { fn() { continue } }",
+                expected: vec!["1:20: 'continue' outside of a loop"],
+            },
+            Test {
+                input: "1 + x = 2; f() += 1",
+                expected: vec![
+                    "1:7: expected a name to the left of '='",
+                    "1:16: expected a name to the left of '+='",
+                ],
+            },
+            Test {
+                input: "for (x of y) { x }",
+                expected: vec!["1:8: expected In, but found Ident 'of'"],
+            },
+            Test {
+                input: "for (1 in y) { break }",
+                expected: vec!["1:6: expected Ident, but found Int '1'"],
+            },
+            Test {
+                input: "while x { x }",
+                expected: vec!["1:7: expected Lparen, but found Ident 'x'"],
+            },
+        ];
+
+        for test in tests.iter() {
+            let mut l = lexer::new(test.input);
+            let mut p = new(&mut l);
+            let errors = match p.parse_program() {
+                Ok(_) => panic!("We parsed {} without errors.", test.input),
+                Err(errors) => errors,
+            };
+            let msgs: Vec<String> = errors.iter()
+                .map(|e| format!("{}", e))
+                .collect();
+            assert_eq!(msgs, test.expected);
+        }
+    }
+
+    #[test]
+    fn node_spans() {
+        let input = "let x = 1;\nlet y = add(x,\n  2 * x);";
+        let program = test_parse_program(input);
+
+        assert_eq!(program.stmts.len(), 2);
+        assert_eq!(format!("{}", program.stmts[0].span().start), "1:1");
+        assert_eq!(program.stmts[0].span().end.offset, 10);
+
+        let l = match &program.stmts[1] {
+            Stmt::Let(l) => l,
+            _ => panic!("We parsed other than let statement."),
+        };
+        assert_eq!(format!("{}", l.span.start), "2:1");
+        assert_eq!(l.span.end.offset, input.len());
+
+        let c = match &l.val {
+            Expr::Call(c) => c,
+            _ => panic!("We parsed other than function call."),
+        };
+        assert_eq!(format!("{}", c.span.start), "2:9");
+        assert_eq!(format!("{}", c.span.end), "3:9");
+        assert_eq!(format!("{}", c.args[1].span().start), "3:3");
+        assert_eq!(format!("{}", c.args[1].span().end), "3:8");
+    }
+
     fn test_parse_program(input: &str) -> Program {
         let mut l = lexer::new(input);
         let mut p = new(&mut l);
-        let program = p.parse_program();
-        assert_eq!(p.errors.len(), 0);
-        program
+        return match p.parse_program() {
+            Ok(program) => program,
+            Err(errors) => panic!("We got parse errors: {:?}", errors),
+        };
     }
 
     fn test_expr_stmt(stmt: &Stmt) -> &ExprStmt {
@@ -674,4 +1515,65 @@ mod test {
             _ => panic!("We parsed other than identifer."),
         };
     }
+
+    #[test]
+    fn incomplete_input() {
+        struct Test<'a> {
+            input: &'a str,
+            expected: bool,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test { input: "let add = fn(a, b) {", expected: true },
+            Test { input: "let add = fn(a, b) {\n  a + b\n}", expected: false },
+            Test { input: "add(1,", expected: true },
+            Test { input: "[1, 2", expected: true },
+            Test { input: "1 +", expected: true },
+            Test { input: "let x =", expected: true },
+            Test { input: "let x", expected: true },
+            Test { input: "if (x) { 1 } else", expected: true },
+            Test { input: "if (x) { 1 } else if (y) {", expected: true },
+            Test { input: "{\"a\":", expected: true },
+            Test { input: "x += 2 **", expected: true },
+            Test { input: "a &&", expected: true },
+            Test { input: "~", expected: true },
+            Test { input: "while (x) {", expected: true },
+            Test { input: "1 + 2", expected: false },
+            Test { input: "1 + )", expected: false },
+            Test { input: "let = 5;", expected: false },
+            Test { input: "fn(x) { x }}", expected: false },
+        ];
+
+        for test in tests.iter() {
+            assert_eq!(is_incomplete(test.input), test.expected, "input: {}", test.input);
+        }
+    }
+
+    #[test]
+    fn dump_ast() {
+        let input = "let f = fn(x) { if (x) { -x } else { [x, {\"a\": 1}] } };
+f(2)[0]";
+        let expected = "Program
+  Let f @1:1
+    Func(x) @1:9
+      If @1:17
+        Ident x @1:21
+        Then @1:24
+          Prefix - @1:26
+            Ident x @1:27
+        Else @1:36
+          Array @1:38
+            Ident x @1:39
+            Hash @1:42
+              Str \"a\" @1:43
+                Int 1 @1:48
+  Index @2:1
+    Call @2:1
+      Ident f @2:1
+      Int 2 @2:3
+    Int 0 @2:6
+";
+        let program = test_parse_program(input);
+        assert_eq!(crate::ast::dump(&program), expected);
+    }
 }
@@ -1,7 +1,45 @@
-#[derive(Clone)]
+use std::fmt;
+
+#[derive(Debug, Clone)]
 pub struct Token {
     pub t: Type,
     pub literal: String,
+    pub span: Span,
+}
+
+// a location in the source. `line` and `col` are 1-based and count chars,
+// `offset` is the 0-based byte offset.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+    pub offset: usize,
+}
+
+// the source range [start, end) covered by a token or an AST node.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+impl Span {
+    // the span from the start of self to the end of other.
+    pub fn to(&self, other: Span) -> Span {
+        return Span { start: self.start, end: other.end };
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.start)
+    }
 }
 
 #[derive(PartialOrd, PartialEq, Debug, Clone)]
@@ -10,22 +48,44 @@ pub enum Type {
     Eof,
     Ident,
     Int,
+    String,
     Assign,
+    PlusAssign,
+    MinusAssign,
+    AsteriskAssign,
+    SlashAssign,
     Plus,
     Minus,
     Bang,
     Asterisk,
     Slash,
+    Percent,
+    Power,
     Lt,
     Gt,
+    Le,
+    Ge,
     Equ,
     Neq,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
     Comma,
+    Colon,
+    DotDot,
+    Ellipsis,
     Semicolon,
     Lparen,
     Rparen,
     Lbrace,
     Rbrace,
+    Lbracket,
+    Rbracket,
     Function,
     Let,
     True,
@@ -33,5 +93,11 @@ pub enum Type {
     If,
     Else,
     Return,
+    While,
+    Break,
+    Continue,
+    Const,
+    For,
+    In,
 }
 
@@ -0,0 +1,837 @@
+use std::cell::RefCell;
+use std::collections::{
+    BTreeMap,
+    HashSet,
+};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use crate::builtins;
+use crate::code::{
+    Op,
+    read_u16,
+};
+use crate::compiler::Bytecode;
+use crate::eval;
+use crate::object::{
+    Object,
+    Int,
+    Bool,
+    Null,
+    Array,
+    Hash,
+    HashPair,
+    CompiledFunc,
+    Closure,
+    ClosureDef,
+    Scope,
+    ErrorKind,
+    TraceFrame,
+    new_error,
+};
+use crate::token::Span;
+
+// Vm runs bytecode from the compiler module on an operand stack. Globals
+// persist between runs, like the global environment of the evaluator.
+pub struct Vm {
+    builtins: builtins::Registry,
+    typing: eval::Typing,
+    globals: Vec<Option<Object>>,
+    // the globals bound with `const`.
+    consts: HashSet<usize>,
+    stack: Vec<Object>,
+    frames: Vec<Frame>,
+    max_depth: usize,
+    budget: eval::Budget,
+    interrupt: Arc<AtomicBool>,
+}
+
+// what a caller needs to resume once the function it called returns.
+struct Frame {
+    func: Rc<CompiledFunc>,
+    scope: Option<Rc<Scope>>,
+    ip: usize,
+    // the height of the stack when the frame was entered.
+    base: usize,
+    // the function called and where from, for stack traces. After a tail
+    // call these are the function that took its place.
+    callee: Rc<CompiledFunc>,
+    site: Span,
+}
+
+pub fn new() -> Vm {
+    return Vm {
+        builtins: builtins::defaults(),
+        typing: eval::Typing::Strict,
+        globals: vec![],
+        consts: HashSet::new(),
+        stack: vec![],
+        frames: vec![],
+        max_depth: eval::DEFAULT_MAX_DEPTH,
+        budget: eval::budget(eval::Limits::default()),
+        interrupt: Arc::new(AtomicBool::new(false)),
+    };
+}
+
+impl Vm {
+    // make a Rust function callable from Monkey as `name`. An arity of None
+    // accepts any number of arguments.
+    pub fn register<F>(&mut self, name: &str, arity: Option<usize>, func: F)
+    where
+        F: Fn(Vec<Object>) -> Object + 'static,
+    {
+        self.builtins.register(name, arity, func);
+    }
+
+    pub fn set_typing(&mut self, typing: eval::Typing) {
+        self.typing = typing;
+    }
+
+    // the vm keeps calls on the heap, but has the evaluator's limit so that
+    // both run the same programs.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    // limit each run from now on. A step is an instruction.
+    pub fn set_limits(&mut self, limits: eval::Limits) {
+        self.budget = eval::budget(limits);
+    }
+
+    // share the flag that stops a running program when set, as with
+    // eval::Evaluator::set_interrupt.
+    pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = flag;
+    }
+
+    // forget every global, for use with a fresh compiler.
+    pub fn reset(&mut self) {
+        self.globals.clear();
+        self.consts.clear();
+    }
+
+    // the global in slot i, if it has been set.
+    pub fn global(&self, i: usize) -> Option<&Object> {
+        return self.globals.get(i).and_then(|g| g.as_ref());
+    }
+
+    // set the global in slot i, as allocated by the compiler.
+    pub fn set_global(&mut self, i: usize, val: Object) {
+        if i >= self.globals.len() {
+            self.globals.resize(i + 1, None);
+        }
+        self.globals[i] = Some(val);
+        self.consts.remove(&i);
+    }
+
+    // run a program and return the value of its last statement, or the
+    // first runtime error.
+    pub fn run(&mut self, bc: Bytecode<'_>) -> Object {
+        self.globals.resize(bc.globals.len(), None);
+        self.budget.start();
+        let result = match self.execute(&bc) {
+            Object::Error(mut e) => {
+                e.trace = self.trace();
+                Object::Error(e)
+            },
+            obj => obj,
+        };
+        self.stack.clear();
+        self.frames.clear();
+        result
+    }
+
+    // the calls in progress, innermost first.
+    fn trace(&self) -> Vec<TraceFrame> {
+        return self.frames.iter().rev()
+            .map(|f| TraceFrame { name: f.callee.name.clone(), span: f.site })
+            .collect();
+    }
+
+    fn execute(&mut self, bc: &Bytecode<'_>) -> Object {
+        // the running function is kept in locals rather than in frames.
+        let mut func = Rc::clone(&bc.main);
+        let mut scope: Option<Rc<Scope>> = None;
+        let mut ip = 0;
+        let mut base = 0;
+        let mut last_popped = Object::Null(Null {});
+
+        loop {
+            let ins = &func.instructions;
+            if ip >= ins.len() {
+                // only the program itself runs off its end.
+                return last_popped;
+            }
+            let at = ip;
+            let op = Op::from_byte(ins[ip]).expect("invalid opcode");
+            ip += 1;
+            if let Err(e) = self.budget.step() {
+                return error_at(e, &func, at);
+            }
+
+            match op {
+                Op::Constant => {
+                    let i = read_u16(ins, ip);
+                    ip += 2;
+                    if let Err(e) = self.budget.check_len(&bc.constants[i]) {
+                        return error_at(e, &func, at);
+                    }
+                    self.stack.push(bc.constants[i].clone());
+                },
+                Op::Pop => last_popped = self.pop(),
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod | Op::Pow |
+                Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl | Op::Shr |
+                Op::Equ | Op::Neq | Op::Lt | Op::Gt | Op::Le | Op::Ge => {
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let res = eval::eval_infix(op.infix_operator().unwrap(), &lhs, &rhs, self.typing);
+                    if res.is_error() {
+                        return error_at(res, &func, at);
+                    }
+                    if let Err(e) = self.budget.check_len(&res) {
+                        return error_at(e, &func, at);
+                    }
+                    self.stack.push(res);
+                },
+                Op::Minus | Op::Bang | Op::BitNot => {
+                    let rhs = self.pop();
+                    let res = eval::eval_prefix(op.prefix_operator().unwrap(), &rhs, self.typing);
+                    if res.is_error() {
+                        return error_at(res, &func, at);
+                    }
+                    self.stack.push(res);
+                },
+                Op::True => self.stack.push(Object::Bool(Bool { val: true })),
+                Op::False => self.stack.push(Object::Bool(Bool { val: false })),
+                Op::Null => self.stack.push(Object::Null(Null {})),
+                Op::Jump => {
+                    let target = read_u16(ins, ip);
+                    // a jump backwards closes a loop, which could run forever.
+                    if target <= at && self.interrupt.load(Ordering::Relaxed) {
+                        let span = func.span_at(at).unwrap_or_default();
+                        return eval::interrupted(span);
+                    }
+                    ip = target;
+                },
+                Op::JumpNotTruthy => {
+                    let target = read_u16(ins, ip);
+                    ip += 2;
+                    if !eval::is_truthy(&self.pop()) {
+                        ip = target;
+                    }
+                },
+                Op::JumpFalsy | Op::JumpTruthy => {
+                    let target = read_u16(ins, ip);
+                    ip += 2;
+                    let truthy = eval::is_truthy(self.stack.last().unwrap());
+                    if truthy == (op == Op::JumpTruthy) {
+                        ip = target;
+                    } else {
+                        self.pop();
+                    }
+                },
+                Op::GetGlobal => {
+                    let i = read_u16(ins, ip);
+                    ip += 2;
+                    let val = match &self.globals[i] {
+                        Some(val) => val.clone(),
+                        None => match self.builtins.get(&bc.globals[i]) {
+                            Some(b) => b,
+                            None => return error_at(
+                                new_error(ErrorKind::UnknownIdent, bc.globals[i].clone(), None),
+                                &func,
+                                at,
+                            ),
+                        },
+                    };
+                    self.stack.push(val);
+                },
+                Op::SetGlobal | Op::ConstGlobal => {
+                    let i = read_u16(ins, ip);
+                    ip += 2;
+                    if self.consts.contains(&i) {
+                        return error_at(
+                            new_error(ErrorKind::ConstAssign, bc.globals[i].clone(), None),
+                            &func,
+                            at,
+                        );
+                    }
+                    self.globals[i] = self.stack.last().cloned();
+                    if op == Op::ConstGlobal {
+                        self.consts.insert(i);
+                    }
+                },
+                Op::AssignGlobal => {
+                    let i = read_u16(ins, ip);
+                    ip += 2;
+                    let kind = if self.globals[i].is_none() {
+                        Some(ErrorKind::UnknownIdent)
+                    } else if self.consts.contains(&i) {
+                        Some(ErrorKind::ConstAssign)
+                    } else {
+                        None
+                    };
+                    if let Some(kind) = kind {
+                        return error_at(new_error(kind, bc.globals[i].clone(), None), &func, at);
+                    }
+                    self.globals[i] = self.stack.last().cloned();
+                },
+                Op::GetLocal => {
+                    let depth = ins[ip] as usize;
+                    let i = read_u16(ins, ip + 1);
+                    ip += 3;
+                    let s = scope_at(&scope, depth);
+                    let val = match s.get(i) {
+                        Some(val) => Some(val),
+                        None => self.lookup_outer(s, &s.func.locals[i], bc),
+                    };
+                    match val {
+                        Some(val) => self.stack.push(val),
+                        None => return error_at(
+                            new_error(ErrorKind::UnknownIdent, s.func.locals[i].clone(), None),
+                            &func,
+                            at,
+                        ),
+                    }
+                },
+                Op::JumpPassed => {
+                    let target = read_u16(ins, ip);
+                    let i = read_u16(ins, ip + 2);
+                    ip += 4;
+                    let passed = match &scope {
+                        Some(s) => s.slots.borrow()[i].is_some(),
+                        None => false,
+                    };
+                    if passed {
+                        ip = target;
+                    }
+                },
+                Op::SetLocal | Op::ConstLocal => {
+                    let depth = ins[ip] as usize;
+                    let i = read_u16(ins, ip + 1);
+                    ip += 3;
+                    let s = scope_at(&scope, depth);
+                    if s.consts.borrow().contains(&i) {
+                        return error_at(
+                            new_error(ErrorKind::ConstAssign, s.func.locals[i].clone(), None),
+                            &func,
+                            at,
+                        );
+                    }
+                    s.set(i, self.stack.last().cloned());
+                    if op == Op::ConstLocal {
+                        s.consts.borrow_mut().insert(i);
+                    }
+                },
+                Op::AssignLocal => {
+                    let depth = ins[ip] as usize;
+                    let i = read_u16(ins, ip + 1);
+                    ip += 3;
+                    let s = scope_at(&scope, depth);
+                    let val = self.stack.last().cloned().unwrap();
+                    if let Err(kind) = self.assign_local(s, i, val, bc) {
+                        return error_at(
+                            new_error(kind, s.func.locals[i].clone(), None),
+                            &func,
+                            at,
+                        );
+                    }
+                },
+                Op::Array => {
+                    let n = read_u16(ins, ip);
+                    ip += 2;
+                    let elems = self.stack.split_off(self.stack.len() - n);
+                    let array = Object::Array(Array { elems: Rc::new(elems) });
+                    if let Err(e) = self.budget.check_len(&array) {
+                        return error_at(e, &func, at);
+                    }
+                    self.stack.push(array);
+                },
+                Op::Hash => {
+                    let n = read_u16(ins, ip);
+                    ip += 2;
+                    let items = self.stack.split_off(self.stack.len() - 2 * n);
+                    let mut pairs = BTreeMap::new();
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(val)) = (items.next(), items.next()) {
+                        let hash_key = key.hash_key().expect("key not checked by HashKey");
+                        pairs.insert(hash_key, HashPair { key: key, val: val });
+                    }
+                    let hash = Object::Hash(Hash { pairs: Rc::new(pairs) });
+                    if let Err(e) = self.budget.check_len(&hash) {
+                        return error_at(e, &func, at);
+                    }
+                    self.stack.push(hash);
+                },
+                // as in the evaluator, a key is checked before its value is
+                // evaluated.
+                Op::HashKey => {
+                    let key = self.stack.last().unwrap();
+                    if key.hash_key().is_none() {
+                        return error_at(
+                            new_error(ErrorKind::Unhashable, key.type_name().to_string(), None),
+                            &func,
+                            at,
+                        );
+                    }
+                },
+                Op::Range => {
+                    let end = self.pop();
+                    let start = self.pop();
+                    let res = eval::eval_range(&start, &end);
+                    if res.is_error() {
+                        return error_at(res, &func, at);
+                    }
+                    self.stack.push(res);
+                },
+                Op::Iter => {
+                    let iter = match eval::iterable(self.pop()) {
+                        Ok(iter) => iter,
+                        Err(e) => return error_at(e, &func, at),
+                    };
+                    self.stack.push(iter);
+                    self.stack.push(Object::Int(Int { val: 0 }));
+                },
+                Op::Next => {
+                    let target = read_u16(ins, ip);
+                    ip += 2;
+                    let n = self.stack.len();
+                    let cursor = match &self.stack[n - 1] {
+                        Object::Int(c) => c.val as usize,
+                        _ => unreachable!("for loop cursor is not an integer"),
+                    };
+                    match eval::next_item(&self.stack[n - 2], cursor) {
+                        Some((item, next)) => {
+                            self.stack[n - 1] = Object::Int(Int { val: next as isize });
+                            self.stack.push(item);
+                        },
+                        None => ip = target,
+                    }
+                },
+                Op::Index => {
+                    let index = self.pop();
+                    let lhs = self.pop();
+                    let res = eval::eval_index(&lhs, &index);
+                    if res.is_error() {
+                        return error_at(res, &func, at);
+                    }
+                    self.stack.push(res);
+                },
+                Op::Call | Op::TailCall => {
+                    let n = read_u16(ins, ip);
+                    ip += 2;
+                    if self.interrupt.load(Ordering::Relaxed) {
+                        let span = func.span_at(at).unwrap_or_default();
+                        return eval::interrupted(span);
+                    }
+                    let args = self.stack.split_off(self.stack.len() - n);
+                    let callee = self.pop();
+                    match callee {
+                        Object::Closure(c) => {
+                            let f = &c.def.func;
+                            let max = if f.rest { None } else { Some(f.num_params) };
+                            if n < f.num_required || max.is_some_and(|max| n > max) {
+                                let e = eval::wrong_arity(f.name.as_deref(), f.num_required, max, n);
+                                return error_at(e, &func, at);
+                            }
+                            let site = func.span_at(at).unwrap_or_default();
+                            // a tail call replaces the running function, so
+                            // only a call from the program itself needs a
+                            // frame of its own.
+                            let tail = op == Op::TailCall && !self.frames.is_empty();
+                            if !tail && self.frames.len() >= self.max_depth {
+                                return eval::stack_overflow(self.max_depth, site);
+                            }
+                            // parameters that were not passed are left empty
+                            // for their defaults.
+                            let mut args = args;
+                            let rest_args = args.split_off(n.min(f.num_params));
+                            let mut slots: Vec<Option<Object>> = args.into_iter().map(Some).collect();
+                            slots.resize(f.num_params, None);
+                            if f.rest {
+                                slots.push(Some(Object::Array(Array { elems: Rc::new(rest_args) })));
+                            }
+                            slots.resize(f.locals.len(), None);
+                            let callee_scope = Rc::new(Scope {
+                                func: Rc::clone(f),
+                                slots: RefCell::new(slots),
+                                consts: RefCell::new(HashSet::new()),
+                                outer: c.captured(),
+                            });
+
+                            if tail {
+                                let frame = self.frames.last_mut().unwrap();
+                                frame.callee = Rc::clone(&c.def.func);
+                                frame.site = site;
+                                self.stack.truncate(base);
+                            } else {
+                                self.frames.push(Frame {
+                                    func: func,
+                                    scope: scope,
+                                    ip: ip,
+                                    base: base,
+                                    callee: Rc::clone(&c.def.func),
+                                    site: site,
+                                });
+                                base = self.stack.len();
+                            }
+                            func = Rc::clone(&c.def.func);
+                            scope = Some(callee_scope);
+                            ip = 0;
+                        },
+                        Object::Builtin(b) => {
+                            let res = builtins::call(&b, args);
+                            if res.is_error() {
+                                return error_at(res, &func, at);
+                            }
+                            if let Err(e) = self.budget.check_len(&res) {
+                                return error_at(e, &func, at);
+                            }
+                            self.stack.push(res);
+                        },
+                        obj => return error_at(
+                            new_error(ErrorKind::NotCallable, obj.type_name().to_string(), None),
+                            &func,
+                            at,
+                        ),
+                    }
+                },
+                Op::ReturnValue | Op::Return => {
+                    let val = if op == Op::ReturnValue {
+                        self.pop()
+                    } else {
+                        Object::Null(Null {})
+                    };
+                    let caller = match self.frames.pop() {
+                        Some(frame) => frame,
+                        // `return` at the top level ends the program.
+                        None => return val,
+                    };
+                    self.stack.truncate(base);
+                    self.stack.push(val);
+                    func = caller.func;
+                    scope = caller.scope;
+                    ip = caller.ip;
+                    base = caller.base;
+                },
+                Op::Closure => {
+                    let i = read_u16(ins, ip);
+                    ip += 2;
+                    let def = ClosureDef {
+                        func: Rc::clone(&bc.funcs[i]),
+                        scope: scope.as_ref().map(Rc::downgrade),
+                    };
+                    self.stack.push(Object::Closure(Closure { def: Rc::new(def), scope: scope.clone() }));
+                },
+                Op::Unwind => {
+                    let height = read_u16(ins, ip);
+                    ip += 2;
+                    self.stack.truncate(base + height);
+                },
+            }
+        }
+    }
+
+    // a local read before its `let` has run refers to whatever the name
+    // means outside the function, as it does in the evaluator.
+    fn lookup_outer(
+        &self, s: &Rc<Scope>, name: &str, bc: &Bytecode<'_>
+    ) -> Option<Object> {
+        let mut outer = s.outer.as_ref();
+        while let Some(s) = outer {
+            if let Some(i) = s.func.locals.iter().position(|l| l == name) {
+                if let Some(val) = s.get(i) {
+                    return Some(val);
+                }
+            }
+            outer = s.outer.as_ref();
+        }
+        if let Some(i) = bc.globals.iter().position(|g| g == name) {
+            if let Some(val) = &self.globals[i] {
+                return Some(val.clone());
+            }
+        }
+        return self.builtins.get(name);
+    }
+
+    // assign to slot i of s. As with lookup_outer, a slot whose `let` has
+    // not run yet stands for whatever the name means outside the function.
+    fn assign_local(
+        &mut self, s: &Rc<Scope>, i: usize, val: Object, bc: &Bytecode<'_>
+    ) -> Result<(), ErrorKind> {
+        if s.slots.borrow()[i].is_some() {
+            return set_slot(s, i, val);
+        }
+
+        let name = &s.func.locals[i];
+        let mut outer = s.outer.as_ref();
+        while let Some(s) = outer {
+            if let Some(i) = s.func.locals.iter().position(|l| l == name) {
+                if s.slots.borrow()[i].is_some() {
+                    return set_slot(s, i, val);
+                }
+            }
+            outer = s.outer.as_ref();
+        }
+        return match bc.globals.iter().position(|g| g == name) {
+            Some(i) if self.globals[i].is_some() => {
+                if self.consts.contains(&i) {
+                    return Err(ErrorKind::ConstAssign);
+                }
+                self.globals[i] = Some(val);
+                Ok(())
+            },
+            _ => Err(ErrorKind::UnknownIdent),
+        };
+    }
+
+    fn pop(&mut self) -> Object {
+        return self.stack.pop().expect("stack underflow");
+    }
+}
+
+// the scope of the function depth levels out from the running one.
+fn scope_at(scope: &Option<Rc<Scope>>, depth: usize) -> &Rc<Scope> {
+    let mut s = scope.as_ref().expect("local outside of a function");
+    for _ in 0..depth {
+        s = s.outer.as_ref().expect("local outside of a function");
+    }
+    s
+}
+
+fn set_slot(s: &Scope, i: usize, val: Object) -> Result<(), ErrorKind> {
+    if s.consts.borrow().contains(&i) {
+        return Err(ErrorKind::ConstAssign);
+    }
+    s.set(i, Some(val));
+    Ok(())
+}
+
+// attach the location of the instruction at ip to an error.
+fn error_at(err: Object, func: &CompiledFunc, ip: usize) -> Object {
+    return match func.span_at(ip) {
+        Some(span) => eval::with_span(err, span),
+        None => err,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler;
+    use crate::env;
+    use crate::lexer;
+    use crate::parser;
+    use crate::ast;
+
+    #[test]
+    fn vm_matches_eval() {
+        let inputs = vec![
+            "5", "-10", "1 + 2 * 3 - 4 / 2", "(5 + 10 * 2 + 15 / 3) * 2 + -10",
+            "true", "!true", "!!5", "1 < 2 == true", "true + 1", "\"a\" + \"b\"",
+            "\"a\" == \"a\"", "\"a\" - \"b\"", "-true", "-\"a\"", "5 + true; 5;",
+            "if (1 > 2) { 10 }", "if (1 < 2) { 10 } else { 20 }", "if (false) { 1 } else { }",
+            "if (1 > 2) { 10 } else if (2 > 1) { 20 } else { 30 }", "if (false) { 1 } else if (false) { 2 }",
+            "let f = fn(n) { if (n == 0) { 1 } else if (n == 1) { f(0) } else { f(n - 1) } }; f(5)",
+            "if (true) { let a = 3; } ", "let a = 5; let b = a * 2; a + b",
+            "let a = 5;", "return 10; 11;", "9; return 2 * 5; 11;",
+            "if (10 > 1) { if (10 > 1) { return 10; } return 1; }",
+            "foobar", "let f = fn() { x }; f()",
+            "[1, 2 * 2, \"three\"]", "[1, 2, 3][1]", "[1, 2, 3][-1]", "[1, 2, 3][3]",
+            "{\"one\": 1, true: 2, 3: [3]}", "{\"a\": 1}[\"a\"]", "{\"a\": 1}[\"b\"]",
+            "{\"a\": 1}[fn(x) { x }]", "1[0]", "let h = {fn(){}: 1}", "{1: 2, [3]: puts(4)}",
+            "len(\"hello\")", "len(1)", "len(\"a\", \"b\")", "first([1, 2])", "rest([1, 2, 3])",
+            "push([], 1)", "let len = fn(x) { 0 }; len(\"abc\")",
+            "let identity = fn(x) { x; }; identity(5);",
+            "let add = fn(a, b) { a + b }; add(5, add(5, 5));",
+            "fn(x) { x * 2 }(5)", "let f = fn(x, y) { x + y }; f", "fn() { }()",
+            "let f = fn(x) { x }; f(1, 2)", "let x = 5; x(1)", "5()", "fn(x) { x }()",
+            "let f = fn(a, b = a * 10) { a + b }; [f(1), f(1, 2), f()]",
+            "let f = fn(a, b = 1) { a }; f(1, 2, 3)", "let f = fn(a = 1) { }; f()",
+            "let f = fn(a = if (true) { return 7; }) { 0 }; [f(), f(1)]",
+            "let y = 100; let f = fn(x = y) { let y = 1; x + y }; f()",
+            "let f = fn(a = b) { a }; f()", "let f = fn(a = 1) { a }; f",
+            "let f = fn(a, ...rest) { [a, rest] }; [f(1), f(1, 2, 3)]",
+            "const g = fn(a, b, ...c) { c }; g(1)", "fn(...xs) { len(xs) }(1, 2)",
+            "let f = fn(a, b = 2, ...c) { let d = a; [d, b, c] }; f(1, 3, 5, 7)",
+            "let g = fn(x) { x / 0 }; let f = fn(n) { if (n == 0) { g(n) } else { 1 + f(n - 1) } }; f(3)",
+            "let f = fn(g) { g(1) }; f(fn(x) { len(x) })", "let f = fn() { fn(a) { a }() }; f()",
+            "let f = fn(n) { if (n > 0) { f(n - 1) } else { return undefined; } }; 1 + f(2)",
+            "let loop = fn(n, acc) { if (n == 0) { acc } else { loop(n - 1, acc + n) } }; loop(5000, 0)",
+            "let even = fn(n) { if (n == 0) { return true; } odd(n - 1) };
+             let odd = fn(n) { if (n == 0) { return false; } return even(n - 1); };
+             even(3001)",
+            "let f = fn(n) { let m = n - 1; if (m < 0) { len(\"done\") } else { if (m % 2 == 0) { f(m) } else { return f(m); } } }; f(2000)",
+            "let f = fn(n) { if (n == 0) { g(1, 2) } else { f(n - 1) } }; let g = fn(x) { x }; f(5)",
+            "let f = fn(n) { if (n == 0) { [][0] + 1 } else { let r = f(n - 1); r } }; let g = fn() { f(2) }; g()",
+            "let f = fn(n) { while (true) { return f(n - 1); } }; f(101)",
+            "let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; [f(99), f(100)]",
+            "let newAdder = fn(x) { fn(y) { x + y } }; let addTwo = newAdder(2); addTwo(3);",
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+            "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+             let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+             even(10)",
+            "let f = fn() {
+                 let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+                 let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+                 odd(7)
+             };
+             f()",
+            "let counter = fn() { let c = 0; let get = fn() { c }; let c = 5; get() }; counter()",
+            "let f = fn(a) { let g = fn(b) { let h = fn(c) { a + b + c }; h }; g }; f(1)(2)(3)",
+            "let f = fn(x) { if (x > 0) { return x; } 0 - x }; f(-3) + f(4)",
+            "let f = fn() { let a = 1; }; f()",
+            "let f = fn() { let y = x; let x = 2; y }; let x = 1; f()",
+            "let f = fn() { return 1; }; f() + 1",
+            "let f = fn(n) { if (n == 0) { return 0; } f(n - 1) }; f(200)",
+            "let map = fn(arr, f) { let iter = fn(arr, acc) { if (len(arr) == 0) { acc } else { iter(rest(arr), push(acc, f(first(arr)))) } }; iter(arr, []) }; map([1, 2, 3], fn(x) { x * x })",
+            "let f = fn() { undefined_thing }; 1 + 1; f()",
+            "let f = fn(x) { x + true + \"a\" }; f(1)",
+            "while (false) { 1 }",
+            "let f = fn(n) { let i = 0; let sum = 0; while (i < n) { let i = i + 1; let sum = sum + i; } sum }; f(10)",
+            "let f = fn() { let i = 0; while (true) { let i = i + 1; if (i == 5) { break; } } i }; f()",
+            "let f = fn() { let i = 0; let odd = 0; while (i < 10) { let i = i + 1; if (i / 2 * 2 == i) { continue; } let odd = odd + 1; } odd }; f()",
+            "let f = fn() { while (true) { while (true) { break; } return 7; } }; f()",
+            "let f = fn() { while (true) { let g = fn() { return 3; }; return g() + 1; } }; f()",
+            "let i = 0; while (i < 3) { let i = i + 1; } i",
+            "let f = fn() { while (true) { x } }; f()",
+            "let f = fn() { while (y) { } }; f()",
+            "let f = fn(x) { 1 + if (x) { return 10; } }; f(true)",
+            "let i = 0; let out = []; while (i < 3) { i += 1; out = push(out, 1 + if (i == 2) { continue; } else { i }); } out",
+            "let i = 0; let out = []; while (i < 3) { i += 1; out = push(out, [i, if (i == 2) { break; } else { i }]); } [i, out]",
+            "let f = fn(xs) { let i = 0; let s = 0; while (i < len(xs)) { i += 1; s += xs[i - 1] * if (xs[i - 1] < 0) { continue; } else { 1 }; } s }; f([1, -2, 3])",
+            "let i = 0; while (i < 2) { i += 1; let j = 0; while (true) { j += 1; [i, j, if (j > i) { break; } else { j }]; } } i",
+            "let x = 1; x = 2; x", "let x = 10; x += 5; x -= 3; x *= 2; x /= 4; x",
+            "let a = 1; let b = 2; a = b = 3; a + b", "y = 1", "z += 1",
+            "let counter = fn() { let n = 0; fn() { n += 1 } }; let c = counter(); c(); c(); c()",
+            "let x = 1; let f = fn() { x = 5; let x = 7; x = 8; }; f(); x",
+            "let f = fn() { let x = 1; let g = fn() { x = 2; let x = 3; x }; g() + x }; f()",
+            "let f = fn(n) { let total = 0; while (n > 0) { total += n; n -= 1; } total }; f(4)",
+            "const x = 1; let x = 2; x = 3; x", "const x = 1; x = 2",
+            "const x = 1; const x = 2;", "const x = 1; for (x in [2]) { }",
+            "let f = fn() { const k = 1; let k = 2; }; f()",
+            "const x = 1; let f = fn() { let x = 2; x = 3; x }; f()",
+            "const x = 1; let f = fn() { x += 1 }; f()",
+            "let f = fn() { const k = 1; let g = fn() { k = 2 }; g() }; f()",
+            "let f = fn() { const k = 1; k *= 2 }; f()",
+            "let s = \"a\"; s -= 1", "let f = fn() { len = 1 }; f()",
+            "let sum = 0; for (x in [1, 2, 3]) { sum += x; } sum",
+            "let s = \"\"; for (c in \"héllo\") { s = c + s; } s",
+            "let ks = []; for (k in {\"b\": 1, \"a\": 2}) { ks = push(ks, k); } ks",
+            "let sum = 0; for (i in 0..10) { if (i == 5) { break; } sum += i; } sum",
+            "let odd = []; for (i in 0..6) { if (i / 2 * 2 == i) { continue; } odd = push(odd, i); } odd",
+            "let f = fn(xs) { for (x in xs) { if (x > 2) { return x; } } -1 }; [f([1, 5, 3]), f([])]",
+            "let f = fn() { let n = 0; for (i in 0..3) { for (j in 0..3) { if (j > i) { break; } n += 1; } } [n, i, j] }; f()",
+            "let f = fn() { let fs = []; for (i in 0..3) { fs = push(fs, fn() { i }); } fs[0]() }; f()",
+            "let out = []; for (a in [1, 2]) { for (b in [1, 2]) { out = push(out, if (true) { break; } else { 0 }); } out = push(out, a); } out",
+            "let out = []; for (x in [1, 2, 3]) { out = push(out, 1 + if (x == 2) { continue; } else { x }); } out",
+            "for (a in [1,2]) { for (b in [1,2]) { puts(if (true) { break; } else { 0 }); } }",
+            "let f = fn(xs) { let n = 0; for (x in xs) { n += [x, if (x > 1) { continue; } else { x }][1]; } n }; f([1, 2, 1])",
+            "for (i in 3..1) { i }", "for (i in 0..3) { } i", "let n = 2; 1..n * 3",
+            "for (x in 5) { }", "1..\"a\"", "let f = fn() { for (x in y) { } }; f()",
+            "true && false", "true || false", "1 && \"a\"", "if (false) { 1 } && 2", "0 || 2",
+            "false || [1]", "false && undefined", "true || undefined()", "true && undefined",
+            "let n = 0; let f = fn() { n += 1; true }; false && f(); true || f(); true && f(); n",
+            "let f = fn(x) { x > 0 && x < 10 || x == 100 }; [f(5), f(50), f(100), f(-1)]",
+            "let f = fn(xs) { let n = 0; for (x in xs) { n += x > 1 && x || 0 } n }; f([1, 2, 3])",
+            "17 % 5 + -7 % 2", "2 ** 3 ** 2", "-2 ** 2", "6 & 3 | 8 ^ 1", "1 << 4 + 1",
+            "-16 >> 2", "~5", "[1 <= 1, 2 <= 1, 1 >= 2, 2 >= 2]", "2 ** -1", "~true",
+            "\"a\" <= \"b\"", "let f = fn(x) { x % 2 == 0 && x ** 2 >= 16 }; [f(2), f(4), f(5)]",
+            "let f = fn(x) { 10 / x }; f(0)", "5 % 0", "let max = 9223372036854775807; 1 + (max + 1)",
+            "let min = -9223372036854775807 - 1;\n-min", "let min = -9223372036854775807 - 1; min / -1",
+            "true + 5", "true * true", "-true", "true == 1", "1 == \"1\"", "[1, [2]] == [1, [2]]",
+            "{\"a\": [1], 2: true} == {2: true, \"a\": [1]}", "let f = fn(x) { x }; [f == f, f != len]",
+            "fn(x) { x } == fn(x) { x }", "let f = fn() { let g = fn(n) { if (n > 0) { g(n - 1) } else { g } }; g }; let h = f(); [h(2) == h, h(1)(0) == f()]",
+            "len == len", "0..3 == 0..3", "if (false) { 1 } == [1][1]",
+            "3 ** 40", "1 << 64", "1 >> -1", "let x = 4611686018427387904; x *= 2", "2 ** 62 - 1 + 2 ** 62",
+        ];
+
+        for input in inputs.iter() {
+            let env = env::new();
+            let program = parse(input);
+            let mut e = eval::new();
+            e.set_max_depth(100);
+            let evaled = e.eval(ast::Node::Program(program.clone()), &env);
+
+            let mut c = compiler::new();
+            let mut vm = new();
+            vm.set_max_depth(100);
+            let ran = vm.run(c.compile(&program).unwrap());
+            assert_eq!(with_trace(&ran), with_trace(&evaled), "input: {}", input);
+        }
+    }
+
+    // an object as printed, followed by the stack trace of an error.
+    fn with_trace(obj: &Object) -> String {
+        let mut s = format!("{}", obj);
+        if let Object::Error(e) = obj {
+            for frame in e.trace.iter() {
+                s.push_str(&format!("\n{}", frame));
+            }
+        }
+        s
+    }
+
+    #[test]
+    fn vm_keeps_globals() {
+        let mut c = compiler::new();
+        let mut vm = new();
+
+        let ran = vm.run(c.compile(&parse("let f = fn() { g() + x };")).unwrap());
+        assert_eq!(format!("{}", ran), "fn()(g() + x)");
+
+        let ran = vm.run(c.compile(&parse("f()")).unwrap());
+        assert_eq!(format!("{}", ran), "ERROR: 1:16: identifier not found: g");
+
+        let ran = vm.run(c.compile(&parse("let g = fn() { 1 }; let x = 2;")).unwrap());
+        assert_eq!(format!("{}", ran), "2");
+
+        let ran = vm.run(c.compile(&parse("f()")).unwrap());
+        assert_eq!(format!("{}", ran), "3");
+    }
+
+    #[test]
+    fn vm_frees_scopes() {
+        let input = "fn() { let g = fn(n) { if (n > 0) { g(n - 1) } else { n } }; g }()";
+        let mut c = compiler::new();
+        let mut vm = new();
+        let g = vm.run(c.compile(&parse(input)).unwrap());
+        let scope = match &g {
+            Object::Closure(g) => Rc::downgrade(g.scope.as_ref().unwrap()),
+            _ => panic!("We ran other than a closure."),
+        };
+
+        // the closure stored in the scope it was made in does not keep that
+        // scope alive once the last outside copy is gone.
+        drop(g);
+        drop(vm);
+        assert!(scope.upgrade().is_none());
+    }
+
+    #[test]
+    fn vm_deep_recursion() {
+        let input = "let count = fn(n) { if (n == 0) { 0 } else { 1 + count(n - 1) } }; count(100000)";
+        let mut c = compiler::new();
+        let mut vm = new();
+        let ran = vm.run(c.compile(&parse(input)).unwrap());
+        assert_eq!(
+            format!("{}", ran),
+            "ERROR: 1:50: stack overflow: more than 1000 nested calls"
+        );
+
+        // calls live on the heap, so the limit can be raised far past what
+        // the evaluator could manage.
+        vm.set_max_depth(200000);
+        let ran = vm.run(c.compile(&parse(input)).unwrap());
+        assert_eq!(format!("{}", ran), "100000");
+    }
+
+    fn parse(input: &str) -> ast::Program {
+        let mut l = lexer::new(input);
+        let mut p = parser::new(&mut l);
+        return p.parse_program().ok().unwrap();
+    }
+}